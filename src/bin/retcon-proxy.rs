@@ -8,9 +8,11 @@
 //! This proxy also provides a `/retcon:rewrite-git-history` slash command
 //! that guides the user through creating a history specification.
 
-use std::path::PathBuf;
-use std::sync::RwLock;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 
+use clap::Parser;
 use determinishtic::Determinishtic;
 use sacp::mcp_server::{McpConnectionTo, McpServer};
 use sacp::schema::{
@@ -22,6 +24,18 @@ use sacp::{Agent, Client, Conductor, ConnectionTo, Proxy, RunWithConnectionTo};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+/// Command-line options. Hosts normally start the proxy without any.
+#[derive(Parser)]
+struct Args {
+    /// Write the JSON Schemas of the proxy tools' params and
+    /// results to PATH (`-` for stdout) and exit
+    #[arg(long, value_name = "PATH")]
+    json_schema_out: Option<PathBuf>,
+}
+
+/// Appended to plan entries whose commit completed but needs a human look.
+const NEEDS_REVIEW_SUFFIX: &str = " (needs review)";
+
 /// The slash command name for rewriting git history.
 const REWRITE_SLASH_COMMAND: &str = "retcon:rewrite-git-history";
 
@@ -30,9 +44,9 @@ const REWRITE_SLASH_COMMAND: &str = "retcon:rewrite-git-history";
 struct ExecuteParams {
     /// The TOML specification content describing the commits to create
     toml_spec: String,
-    /// Optional build command (default: "cargo check --all --workspace")
+    /// Optional build command (default: detected from the repository's Cargo.toml)
     build_command: Option<String>,
-    /// Optional test command (default: "cargo test --all --workspace")
+    /// Optional test command (default: detected from the repository's Cargo.toml)
     test_command: Option<String>,
     /// Skip build verification
     skip_build: Option<bool>,
@@ -40,6 +54,24 @@ struct ExecuteParams {
     skip_test: Option<bool>,
 }
 
+/// Parameters for the verify-git-rewrite tool.
+#[derive(Debug, Deserialize, JsonSchema)]
+struct VerifyParams {
+    /// The TOML specification naming the cleaned branch and its target
+    toml_spec: String,
+}
+
+/// Result of the verify-git-rewrite tool.
+#[derive(Debug, Serialize, JsonSchema)]
+struct VerifyResult {
+    /// Whether the cleaned branch has the same content as the target
+    matches_target: bool,
+    /// `git diff --stat` from the cleaned branch to the target; empty when they match
+    diff_stat: String,
+    /// Commits on the cleaned branch from its base to its tip, oldest first
+    cleaned_commits: Vec<CleanedCommit>,
+}
+
 /// Status of the rewrite execution.
 #[derive(Debug, Serialize, JsonSchema)]
 #[serde(tag = "status")]
@@ -54,6 +86,13 @@ enum ExecuteStatus {
         commit_message: String,
         /// Why the LLM got stuck
         reason: String,
+        /// The category of the reason, if known
+        kind: Option<retcon::StuckKind>,
+    },
+    /// The spec parsed but is not valid; nothing was executed
+    InvalidSpec {
+        /// Why the spec was rejected
+        message: String,
     },
     /// An error occurred during execution
     Error {
@@ -62,6 +101,15 @@ enum ExecuteStatus {
     },
 }
 
+/// A commit on the cleaned branch.
+#[derive(Debug, Serialize, JsonSchema)]
+struct CleanedCommit {
+    /// Short commit hash
+    hash: String,
+    /// First line of the commit message
+    subject: String,
+}
+
 /// Result of the execute-git-rewrite tool.
 #[derive(Debug, Serialize, JsonSchema)]
 struct ExecuteResult {
@@ -69,6 +117,73 @@ struct ExecuteResult {
     status: ExecuteStatus,
     /// The updated TOML spec with execution history
     updated_toml: String,
+    /// Commits on the cleaned branch from its base to HEAD, oldest first
+    cleaned_commits: Vec<CleanedCommit>,
+    /// Whether the cleaned branch has the same content as the target
+    matches_target: bool,
+    /// Number of commits on the cleaned branch, fixup commits included
+    commits_count: usize,
+    /// How much text this run exchanged with the agent
+    token_usage: TokenUsage,
+}
+
+/// Text exchanged with the agent during an execute-git-rewrite call.
+///
+/// The agent connection doesn't report token counts, so this measures what
+/// retcon sent and what the agent wrote back. Tool output the agent reads on
+/// its own isn't seen by retcon and isn't included.
+#[derive(Debug, Default, Serialize, JsonSchema)]
+struct TokenUsage {
+    /// Number of prompts sent (one per agent session)
+    prompts: usize,
+    /// Characters of prompt text sent to the agent
+    prompt_chars: usize,
+    /// Characters of message text the agent sent back
+    response_chars: usize,
+    /// Rough token estimate for the text above, at four characters per token
+    estimated_tokens: usize,
+}
+
+// =============================================================================
+// Usage Observer
+// =============================================================================
+
+/// Observer that tallies the text exchanged with the agent.
+#[derive(Default)]
+struct UsageObserver {
+    prompts: AtomicUsize,
+    prompt_chars: AtomicUsize,
+    response_chars: AtomicUsize,
+}
+
+impl UsageObserver {
+    fn usage(&self) -> TokenUsage {
+        let prompt_chars = self.prompt_chars.load(Ordering::Relaxed);
+        let response_chars = self.response_chars.load(Ordering::Relaxed);
+        TokenUsage {
+            prompts: self.prompts.load(Ordering::Relaxed),
+            prompt_chars,
+            response_chars,
+            estimated_tokens: (prompt_chars + response_chars).div_ceil(4),
+        }
+    }
+}
+
+impl determinishtic::ThinkObserver for UsageObserver {
+    fn on_prompt(&self, prompt: &str) {
+        self.prompts.fetch_add(1, Ordering::Relaxed);
+        self.prompt_chars
+            .fetch_add(prompt.chars().count(), Ordering::Relaxed);
+    }
+
+    fn on_notification(&self, notification: &SessionNotification) {
+        if let SessionUpdate::AgentMessageChunk(chunk) = &notification.update
+            && let ContentBlock::Text(text) = &chunk.content
+        {
+            self.response_chars
+                .fetch_add(text.text.chars().count(), Ordering::Relaxed);
+        }
+    }
 }
 
 // =============================================================================
@@ -171,15 +286,46 @@ impl retcon::ExecuteHooks for AcpHooks {
                     retcon::CommitStatus::InProgress => PlanEntryStatus::InProgress,
                     retcon::CommitStatus::Completed => PlanEntryStatus::Completed,
                     retcon::CommitStatus::Stuck => PlanEntryStatus::InProgress, // Best we can do
+                    retcon::CommitStatus::NeedsReview => PlanEntryStatus::Completed,
                 };
             }
         }
+        // Completed either way as far as ACP is concerned, so flag it in the entry text
+        if status == retcon::CommitStatus::NeedsReview {
+            let mut commits = self.commits.write().unwrap();
+            if let Some(message) = commits.get_mut(commit_idx)
+                && !message.ends_with(NEEDS_REVIEW_SUFFIX)
+            {
+                message.push_str(NEEDS_REVIEW_SUFFIX);
+            }
+        }
         self.send_plan();
     }
 }
 
+/// The JSON Schemas of the tools' inputs and outputs.
+fn tool_schemas() -> serde_json::Value {
+    serde_json::json!({
+        "ExecuteParams": schemars::schema_for!(ExecuteParams),
+        "ExecuteResult": schemars::schema_for!(ExecuteResult),
+        "VerifyParams": schemars::schema_for!(VerifyParams),
+        "VerifyResult": schemars::schema_for!(VerifyResult),
+    })
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    if let Some(path) = args.json_schema_out {
+        let json = serde_json::to_string_pretty(&tool_schemas())?;
+        if path.as_os_str() == "-" {
+            println!("{json}");
+        } else {
+            std::fs::write(&path, json + "\n")?;
+        }
+        return Ok(());
+    }
+
     tracing_subscriber::fmt()
         .with_writer(std::io::stderr)
         .init();
@@ -245,15 +391,29 @@ fn make_mcp_server(cwd: PathBuf) -> McpServer<Conductor, impl RunWithConnectionT
         .instructions(
             "Git history rewriting tools. Use execute-git-rewrite to run a rewrite \
              from a TOML specification. The tool will use the agent to extract and \
-             apply changes, creating clean commits.",
+             apply changes, creating clean commits. Use verify-git-rewrite to check \
+             that the cleaned branch still matches the target after manual edits.",
         )
         .tool_fn(
             "execute-git-rewrite",
             "Execute a git history rewrite from a TOML specification. \
              Returns the updated spec with execution history and a status \
              indicating completion or where it got stuck.",
-            async move |params: ExecuteParams, cx: McpConnectionTo<Conductor>| {
-                execute_tool(params, &cwd, cx).await
+            {
+                let cwd = cwd.clone();
+                async move |params: ExecuteParams, cx: McpConnectionTo<Conductor>| {
+                    execute_tool(params, &cwd, cx).await
+                }
+            },
+            sacp::tool_fn!(),
+        )
+        .tool_fn(
+            "verify-git-rewrite",
+            "Check whether the cleaned branch of a TOML specification has exactly \
+             the content of its target, without reconstructing anything. \
+             Returns a diff stat of whatever still differs.",
+            async move |params: VerifyParams, _cx: McpConnectionTo<Conductor>| {
+                verify_tool(params, &cwd)
             },
             sacp::tool_fn!(),
         )
@@ -286,9 +446,38 @@ fn replace_with_canned_prompt(mut request: PromptRequest) -> PromptRequest {
     request
 }
 
+fn verify_tool(params: VerifyParams, cwd: &Path) -> Result<VerifyResult, sacp::Error> {
+    let spec = retcon::HistorySpec::from_toml(&params.toml_spec)
+        .map_err(|e| sacp::Error::invalid_params().data(e.to_string()))?;
+    let git = retcon::Git::discover(cwd)
+        .map_err(|e| sacp::Error::internal_error().data(e.to_string()))?;
+
+    let diff_stat = git
+        .diff_stat(&spec.cleaned, spec.target())
+        .map_err(|e| sacp::Error::internal_error().data(e.to_string()))?;
+
+    Ok(VerifyResult {
+        matches_target: diff_stat.trim().is_empty(),
+        diff_stat,
+        cleaned_commits: cleaned_commits(&git, &spec),
+    })
+}
+
+/// The commits on the spec's cleaned branch, or none if they can't be listed.
+fn cleaned_commits(git: &retcon::Git, spec: &retcon::HistorySpec) -> Vec<CleanedCommit> {
+    retcon::cleaned_history(git, spec)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|entry| CleanedCommit {
+            hash: entry.hash,
+            subject: entry.subject,
+        })
+        .collect()
+}
+
 async fn execute_tool(
     params: ExecuteParams,
-    cwd: &PathBuf,
+    cwd: &Path,
     cx: McpConnectionTo<Conductor>,
 ) -> Result<ExecuteResult, sacp::Error> {
     // 1. Parse the TOML spec
@@ -300,29 +489,28 @@ async fn execute_tool(
         .map_err(|e| sacp::Error::internal_error().data(e.to_string()))?;
 
     // 3. Build config
+    let defaults = retcon::default_commands(git.root());
+    let build_command = params.build_command.or(defaults.build);
+    let skip_build = params.skip_build.unwrap_or(false);
     let config = retcon::ExecuteConfig {
-        build_command: if params.skip_build.unwrap_or(false) {
-            None
-        } else {
-            params
-                .build_command
-                .or_else(|| Some("cargo check --all --workspace".to_string()))
-        },
+        build_command: build_command.clone().filter(|_| !skip_build),
+        skipped_build_command: build_command.filter(|_| skip_build),
         test_command: if params.skip_test.unwrap_or(false) {
             None
         } else {
-            params
-                .test_command
-                .or_else(|| Some("cargo test --all --workspace".to_string()))
+            params.test_command.or(defaults.test)
         },
         agent: None,
+        ..Default::default()
     };
 
     // 4. Create Determinishtic and AcpHooks from the connection
     let connection = cx.connection_to();
     let session_id = AcpHooks::session_id_from_acp_url(&cx.acp_url());
     let hooks = AcpHooks::new(connection.clone(), session_id);
-    let d = Determinishtic::from_connection(connection);
+    let usage = Arc::new(UsageObserver::default());
+    let mut d = Determinishtic::from_connection(connection);
+    d.set_observer(usage.clone());
 
     // 5. Run execute with ACP hooks for progress feedback
     let result = retcon::execute_with_connection(&d, spec, &git, &config, &hooks).await;
@@ -338,32 +526,41 @@ async fn execute_tool(
         .map_err(|e| sacp::Error::internal_error().data(e.to_string()))?;
 
     let status = if let Some(e) = error {
-        ExecuteStatus::Error {
-            message: e.to_string(),
+        match e {
+            retcon::ExecuteError::InvalidSpec(e) => ExecuteStatus::InvalidSpec {
+                message: e.to_string(),
+            },
+            e => ExecuteStatus::Error {
+                message: e.to_string(),
+            },
         }
     } else if spec.commits.iter().all(|c| c.is_complete()) {
         ExecuteStatus::Complete
     } else if let Some((idx, commit)) = spec.commits.iter().enumerate().find(|(_, c)| c.is_stuck())
     {
         let reason = commit
-            .history
-            .last()
-            .and_then(|h| match h {
-                retcon::HistoryEntry::Stuck(r) => Some(r.clone()),
-                _ => None,
-            })
-            .unwrap_or_else(|| "Unknown reason".to_string());
+            .stuck_reason()
+            .unwrap_or("Unknown reason")
+            .to_string();
         ExecuteStatus::Stuck {
             commit_index: idx,
             commit_message: commit.message.clone(),
             reason,
+            kind: commit.stuck_kind(),
         }
     } else {
         ExecuteStatus::Complete
     };
 
+    let cleaned_commits = cleaned_commits(&git, &spec);
+    let matches_target = retcon::remaining_is_empty(&git, &spec).unwrap_or(false);
+
     Ok(ExecuteResult {
         status,
         updated_toml,
+        commits_count: cleaned_commits.len(),
+        cleaned_commits,
+        matches_target,
+        token_usage: usage.usage(),
     })
 }
@@ -0,0 +1,79 @@
+//! Detect default build and test commands from the repository's manifests.
+
+use std::path::Path;
+
+/// Build and test commands inferred from the repository layout.
+///
+/// A `None` command means no suitable default was found and the
+/// corresponding step should be skipped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DefaultCommands {
+    /// Command to check that each commit builds
+    pub build: Option<String>,
+    /// Command to run the test suite
+    pub test: Option<String>,
+}
+
+/// Infer build and test commands for the repository at `repo_root`.
+///
+/// Currently only Cargo projects are recognized. For a workspace the commands
+/// cover every member; the test step is dropped when the repository contains
+/// no Rust tests. Repositories without a `Cargo.toml` get no defaults rather
+/// than a cargo command that is bound to fail.
+#[must_use]
+pub fn default_commands(repo_root: &Path) -> DefaultCommands {
+    let Ok(manifest) = std::fs::read_to_string(repo_root.join("Cargo.toml")) else {
+        return DefaultCommands::default();
+    };
+    let is_workspace = manifest
+        .parse::<toml::Table>()
+        .map(|table| table.contains_key("workspace"))
+        .unwrap_or(false);
+
+    let (build, test) = if is_workspace {
+        (
+            "cargo check --all --workspace",
+            "cargo test --all --workspace",
+        )
+    } else {
+        ("cargo check", "cargo test")
+    };
+
+    DefaultCommands {
+        build: Some(build.to_string()),
+        test: has_rust_tests(repo_root).then(|| test.to_string()),
+    }
+}
+
+/// Check whether any Rust source under `dir` looks like it contains tests.
+fn has_rust_tests(dir: &Path) -> bool {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return false;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+
+        if path.is_dir() {
+            // Skip build output and hidden directories like .git
+            if name == "target" || name.starts_with('.') {
+                continue;
+            }
+            if has_rust_tests(&path) {
+                return true;
+            }
+        } else if name.ends_with(".rs") {
+            let contents = std::fs::read_to_string(&path).unwrap_or_default();
+            if contents.contains("#[test]")
+                || contents.contains("#[tokio::test")
+                || contents.contains("#[cfg(test)]")
+            {
+                return true;
+            }
+        }
+    }
+
+    false
+}
@@ -1,10 +1,18 @@
 //! Execute the history reconstruction loop.
-
-use std::path::Path;
+//!
+//! A single [`Determinishtic`] connection is reused for the whole run, but
+//! every `d.think()` call opens a fresh agent session. No conversation context
+//! carries over between the extract, fix, and catchall passes, or between
+//! commits; everything the agent needs is restated in each prompt. This keeps
+//! the context size bounded no matter how long the spec is.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::process::Command as StdCommand;
 use std::str::FromStr;
 
 use determinishtic::Determinishtic;
+use regex::Regex;
 use sacp::Agent;
 use sacp::role::{HasPeer, Role};
 use sacp_tokio::AcpAgent;
@@ -12,13 +20,32 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::git::Git;
-use crate::spec::{CommitSpec, HistoryEntry, HistorySpec};
+use crate::git::{Git, LogEntry, shell_quote};
+use crate::manifest::{MANIFEST_FILE, Manifest, ManifestCommit};
+use crate::record::{self, Recording};
+use crate::script::ScriptWorkspace;
+use crate::spec::{
+    Base, CommitSpec, HintFeedback, Hints, HistoryEntry, HistorySpec, StuckKind, StuckReason,
+    ValidationError,
+};
+use crate::throttle::RateLimit;
+use crate::tools;
+use crate::util::{sibling_temp_path, write_atomic};
 
 // =============================================================================
 // Hooks Trait
 // =============================================================================
 
+/// Order in which commits are reconstructed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Direction {
+    /// Build each commit up from the base, oldest first.
+    #[default]
+    Forward,
+    /// Start from the target and peel commits off, newest first.
+    Reverse,
+}
+
 /// Status of a commit in the execution plan.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CommitStatus {
@@ -30,6 +57,28 @@ pub enum CommitStatus {
     Completed,
     /// Stuck and needs human intervention
     Stuck,
+    /// Completed, but flagged for a human to look over
+    NeedsReview,
+}
+
+impl CommitStatus {
+    /// Whether the commit is done, with or without a review flag.
+    #[must_use]
+    pub fn is_finished(self) -> bool {
+        matches!(self, CommitStatus::Completed | CommitStatus::NeedsReview)
+    }
+}
+
+/// Decision returned by [`ExecuteHooks::approve_commit`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Approval {
+    /// Create the commit
+    Approve,
+    /// Don't commit; mark the commit stuck with the given reason
+    Reject(String),
+    /// Discard the extracted changes, leaving them for later commits, and
+    /// record this commit as skipped
+    Defer,
 }
 
 /// Hooks for observing execution progress.
@@ -66,6 +115,23 @@ pub trait ExecuteHooks {
         let _ = reason;
         None
     }
+
+    /// Called after changes are extracted, before the commit is created.
+    ///
+    /// The `staged_diff` contains exactly what would be committed.
+    /// The default approves every commit.
+    fn approve_commit(&self, commit_idx: usize, staged_diff: &str) -> Approval {
+        Approval::Approve
+    }
+
+    /// Called with the plan for leftover changes before any fixup commits
+    /// are created, when the run asks for confirmation.
+    ///
+    /// Return `false` to stop with the changes still uncommitted; the next
+    /// run picks up from there. The default approves the plan.
+    fn approve_finalize(&self, plan: &ExtractionPlan) -> bool {
+        true
+    }
 }
 
 /// Default hooks implementation that prints to stdout.
@@ -84,15 +150,156 @@ pub struct NoOpHooks;
 
 impl ExecuteHooks for NoOpHooks {}
 
+/// Message of the catchall commit when [`ExecuteConfig::catchall_message`] isn't set.
+pub const DEFAULT_CATCHALL_MESSAGE: &str = "Remaining changes (review manually)";
+
 /// Configuration for the execute command.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct ExecuteConfig {
     /// Build command to run after each commit. None means skip build.
     pub build_command: Option<String>,
+    /// Build command that was skipped for the run as a whole; it still runs
+    /// for commits that set `force_build`.
+    pub skipped_build_command: Option<String>,
     /// Test command to run after build passes. None means skip tests.
     pub test_command: Option<String>,
+    /// Run the build and test commands with `CARGO_TARGET_DIR` set to this
+    /// directory, so they don't share (and lock) the project's own `target/`.
+    pub build_target_dir: Option<PathBuf>,
+    /// Command (such as a formatter) to run after each commit; whatever it
+    /// changes is amended into the commit. None means skip it.
+    pub post_commit_command: Option<String>,
+    /// Command to run once each commit is complete or stuck, for notifications
+    /// and other automation. None means skip it.
+    ///
+    /// It gets `RETCON_COMMIT_INDEX` (1-based), `RETCON_COMMIT_SUBJECT`,
+    /// `RETCON_STATUS` (`complete`, `needs_review`, or `stuck`), and
+    /// `RETCON_HASH` (the commit's latest hash, empty if none) in its
+    /// environment.
+    pub on_commit_command: Option<String>,
     /// Agent command string. None means use default (zed_claude_code).
     pub agent: Option<String>,
+    /// Move on to the next commit when one gets stuck instead of stopping.
+    ///
+    /// The stuck commit's commits, edits and new files are undone, so later
+    /// commits are reconstructed without its changes. The final verification
+    /// is skipped while any commit is stuck.
+    pub continue_on_stuck: bool,
+    /// With `continue_on_stuck`, abort once this many commits in a row are stuck.
+    pub max_consecutive_stuck: Option<usize>,
+    /// With `continue_on_stuck`, write the stuck commits and their reasons
+    /// here at the end of the run, grouped by kind. A `.json` path gets
+    /// JSON, anything else Markdown.
+    pub stuck_report: Option<PathBuf>,
+    /// Mark a commit stuck as soon as its first extraction fails to build or
+    /// pass tests, instead of letting the LLM add fix commits.
+    pub strict_no_wip: bool,
+    /// Only reconstruct pending commits whose message matches this pattern.
+    ///
+    /// Other pending commits are left as they are, and the final verification
+    /// is skipped while any remain.
+    pub filter_message: Option<Regex>,
+    /// Only reconstruct the changes to this file (relative to the repository root).
+    ///
+    /// Everything else stays as it is in the base, and the final verification
+    /// only asks that this file match the target. Since the history this
+    /// records only covers that file, [`execute_with_hooks`] refuses it
+    /// without an `output_spec`.
+    pub file: Option<String>,
+    /// Fetch `remote` before computing the merge-base.
+    pub fetch: bool,
+    /// Show the LLM the source branch's own commits, with the files each
+    /// one changed, when extracting a commit.
+    pub show_original_commits: bool,
+    /// Before starting, run a trivial think block with retcon's tools registered
+    /// to check the agent accepts their schemas.
+    pub preflight: bool,
+    /// Only ask the LLM how it would split the changes among the commits,
+    /// print that plan, and stop without touching the repository.
+    pub dry_extract: bool,
+    /// Once every commit is reconstructed, only ask the LLM which commit each
+    /// leftover change belongs to, print that, and stop before creating
+    /// fixup commits.
+    pub dry_finalize: bool,
+    /// Show the LLM's plan for leftover changes and ask
+    /// [`ExecuteHooks::approve_finalize`] before creating fixup commits.
+    pub confirm_finalize: bool,
+    /// Whether to build commits up from the base or peel them off the target.
+    pub direction: Direction,
+    /// Discard all previous progress: clear every commit's history and
+    /// rebuild the cleaned branch from the current base.
+    pub fresh: bool,
+    /// How many extra times to ask the agent to sort leftover changes into
+    /// fixup commits before falling back to a single catchall commit.
+    pub catchall_retries: usize,
+    /// Message for the catchall commit that takes whatever the fixups missed.
+    /// `{last}` is replaced by the subject of the spec's last commit, so
+    /// `squash! {last}` gives a commit `git rebase --autosquash` folds into it.
+    /// None means [`DEFAULT_CATCHALL_MESSAGE`].
+    pub catchall_message: Option<String>,
+    /// Once the spec's commits are done, put everything left in one commit
+    /// with this message, instead of having the LLM sort it into fixups.
+    pub remainder_commit: Option<String>,
+    /// Git executable to run instead of the `git` on `PATH`.
+    ///
+    /// Only affects [`execute`] and [`execute_with_hooks`]; callers of
+    /// [`execute_with_connection`] configure the [`Git`] they pass in.
+    pub git_binary: Option<PathBuf>,
+    /// Skip pre-commit and commit-msg hooks for every commit retcon creates.
+    ///
+    /// By default hooks run, wherever `core.hooksPath` points them, just as
+    /// they would for a manual `git commit`.
+    pub no_verify: bool,
+    /// When retrying a resolved stuck commit, first undo the commits it
+    /// already created, keeping their changes in the working tree.
+    pub retry_clean: bool,
+    /// Make sure each created commit's timestamp is later than its parent's.
+    ///
+    /// Commits made in quick succession otherwise often share a timestamp,
+    /// which confuses tools that sort history by commit time.
+    pub distinct_commit_times: bool,
+    /// Create a lightweight `<cleaned>-base` tag pointing at the base commit.
+    pub tag_base: bool,
+    /// Once the branch matches the target, fold its `fixup!` commits into
+    /// the commits they fix.
+    pub autosquash: bool,
+    /// Record execution history in this file instead of the input spec,
+    /// which is left untouched. A run resumes from it if it already exists.
+    pub output_spec: Option<PathBuf>,
+    /// Also write each completed commit to a `.patch` file in this directory.
+    pub patch_dir: Option<PathBuf>,
+    /// Once finished, write `git range-diff` of the source against the cleaned
+    /// history to this file (`-` to print it instead).
+    pub range_diff: Option<PathBuf>,
+    /// Replacement for the prompt text that passes a stuck commit's resolution
+    /// note to the LLM. `{note}` is replaced by the note itself.
+    pub resolution_template: Option<String>,
+    /// Show only the last this many lines of build and test output (all of
+    /// it if unset). The LLM gets its own, separately truncated copy.
+    pub output_lines: Option<usize>,
+    /// How often requests may be sent to the agent.
+    pub rate_limit: RateLimit,
+    /// Save each agent request's outcome, or answer requests from one saved earlier.
+    pub recording: Recording,
+    /// Truncate files returned by the `read_file` tools to this many bytes.
+    pub max_read_file_bytes: Option<usize>,
+    /// Split extraction into several prompts, each covering a subset of the
+    /// remaining files, when the remaining diff would exceed this many tokens.
+    pub max_prompt_tokens: Option<usize>,
+    /// Instead of creating the cleaned branch, write a shell script that creates it.
+    ///
+    /// The reconstruction runs in a scratch worktree next to the script and
+    /// the resulting commits are exported as patches the script applies.
+    pub emit_script: Option<PathBuf>,
+    /// In script mode, clear out whatever is at the scratch worktree path
+    /// instead of refusing to use it.
+    pub worktree_force: bool,
+    /// Treat files the cleaned branch added that the target doesn't have as
+    /// a stuck commit, instead of only warning about them.
+    pub stuck_on_stray_files: bool,
+    /// When checking that the branch matches the target at the end, ignore
+    /// differences that are only whitespace (including a missing final newline).
+    pub ignore_whitespace: bool,
 }
 
 /// Execute the reconstruction loop for the given spec file.
@@ -104,7 +311,13 @@ pub struct ExecuteConfig {
 /// This variant creates its own connection to the default agent (Zed Claude Code).
 /// For use inside a proxy where you have an existing connection, use
 /// [`execute_with_connection`] instead.
-pub async fn execute(spec_path: &Path, config: &ExecuteConfig) -> Result<(), Error> {
+///
+/// Returns the final spec state. On error, the spec is returned alongside
+/// the error whenever it could be read and parsed.
+pub async fn execute(
+    spec_path: &Path,
+    config: &ExecuteConfig,
+) -> Result<HistorySpec, (Option<HistorySpec>, Error)> {
     execute_with_hooks(spec_path, config, &PrintHooks, None).await
 }
 
@@ -116,55 +329,140 @@ pub async fn execute(spec_path: &Path, config: &ExecuteConfig) -> Result<(), Err
 /// 3. If stuck, prompts the user for a response
 /// 4. If the user responds, appends `Resolved` to the TOML and loops
 /// 5. If the user cancels (or all complete), exits
+///
+/// Like [`execute_with_connection`], errors carry the spec state at the time
+/// of failure. It is `None` only if the spec file couldn't be read or parsed.
 pub async fn execute_with_hooks(
     spec_path: &Path,
     config: &ExecuteConfig,
     hooks: &(impl ExecuteHooks + Sync),
     observer: Option<std::sync::Arc<dyn determinishtic::ThinkObserver>>,
-) -> Result<(), Error> {
+) -> Result<HistorySpec, (Option<HistorySpec>, Error)> {
+    // Commits marked complete with one file's changes would be skipped by a full run
+    if config.file.is_some() && config.output_spec.is_none() {
+        return Err((None, Error::FileWithoutOutputSpec));
+    }
+
+    // With an output spec, all state lives there once it exists
+    let state_path = match &config.output_spec {
+        Some(output) if output.exists() => output.as_path(),
+        _ => spec_path,
+    };
+
+    // Parse up front so a broken spec is reported before connecting
+    let mut spec = read_spec(state_path).map_err(|e| (None, e))?;
+    let fail = |spec: &HistorySpec, e: Error| (Some(spec.clone()), e);
+
+    let state_path = match &config.output_spec {
+        Some(output) if !config.dry_extract => {
+            // Start from a copy so saving keeps the input's formatting and comments
+            if !output.exists() {
+                std::fs::read(spec_path)
+                    .and_then(|content| write_atomic(output, &content))
+                    .map_err(|e| {
+                        fail(
+                            &spec,
+                            Error::WriteSpec {
+                                path: output.display().to_string(),
+                                source: e,
+                            },
+                        )
+                    })?;
+            }
+            output.as_path()
+        }
+        _ => state_path,
+    };
+
+    // A read-only spec would otherwise only fail at the first save, after real work
+    if !config.dry_extract {
+        check_spec_writable(state_path).map_err(|e| fail(&spec, e))?;
+    }
+
     // Connect to the LLM agent once
     hooks.report("Connecting to LLM agent...");
     let agent = match &config.agent {
-        Some(cmd) => AcpAgent::from_str(cmd).map_err(|e| Error::Agent {
-            message: format!("invalid agent command: {e}"),
+        Some(cmd) => AcpAgent::from_str(cmd).map_err(|e| {
+            fail(
+                &spec,
+                Error::Agent {
+                    message: format!("invalid agent command: {e}"),
+                },
+            )
         })?,
         None => AcpAgent::zed_claude_code(),
     };
     let mut d = Determinishtic::new(agent)
         .await
-        .map_err(|e| Error::AgentConnect { source: e.into() })?;
+        .map_err(|e| fail(&spec, Error::AgentConnect { source: e.into() }))?;
     hooks.report("Connected.");
 
     if let Some(obs) = observer {
         d.set_observer(obs);
     }
 
-    let git = Git::discover(spec_path)?;
+    let git = match &config.git_binary {
+        Some(program) => Git::discover_with_program(spec_path, program),
+        None => Git::discover(spec_path),
+    }
+    .map_err(|e| fail(&spec, e.into()))?;
+
+    if config.preflight {
+        preflight_tools(&d, &git, config, hooks)
+            .await
+            .map_err(|e| fail(&spec, e))?;
+    }
+
+    if config.dry_extract {
+        let base = resolve_base(&git, &spec).map_err(|e| fail(&spec, e))?;
+        override_target(&git, &mut spec, &base, config, hooks).map_err(|e| fail(&spec, e))?;
+        let plan = plan_extraction(&d, &git, &spec, config)
+            .await
+            .map_err(|e| fail(&spec, e))?;
+        report_extraction_plan(&spec, &plan, hooks);
+        return Ok(spec);
+    }
+
+    if config.fresh {
+        start_fresh(&git, &mut spec, config, hooks).map_err(|e| fail(&spec, e))?;
+        save_spec(state_path, &spec).map_err(|e| fail(&spec, e))?;
+    }
 
     loop {
         // Read spec fresh from disk each iteration
-        let content = std::fs::read_to_string(spec_path).map_err(|e| Error::ReadSpec {
-            path: spec_path.display().to_string(),
-            source: e,
-        })?;
-        let spec = HistorySpec::from_toml(&content)?;
+        let spec = read_spec(state_path).map_err(|e| fail(&spec, e))?;
 
         // Run one pass — this saves to disk after each state change
-        let result = execute_inner(&d, spec, &git, Some(spec_path), config, hooks).await;
+        let result = execute_inner(&d, spec, &git, Some(state_path), config, hooks).await;
+
+        // Written even when giving up on too many stuck commits, when it's most useful
+        if config.continue_on_stuck
+            && let Some(path) = &config.stuck_report
+        {
+            // A report that can't be written mustn't hide how the run went
+            let (Ok(spec) | Err((spec, _))) = &result;
+            if let Err(e) = write_stuck_report(path, spec) {
+                let cause = std::error::Error::source(&e)
+                    .map(|source| format!(": {source}"))
+                    .unwrap_or_default();
+                hooks.report(&format!("  ⚠ {e}{cause}"));
+            }
+        }
 
         // On hard error, spec was already saved by execute_inner
-        let spec = match result {
-            Ok(spec) => spec,
-            Err((_spec, e)) => return Err(e),
-        };
+        let spec = result.map_err(|(spec, e)| (Some(spec), e))?;
+
+        // Unattended runs leave stuck commits for the user to resolve later
+        if config.continue_on_stuck {
+            return Ok(spec);
+        }
 
         // Check if we're stuck and need user input
         let stuck_commit = spec.commits.iter().position(|c| c.is_stuck());
         if let Some(idx) = stuck_commit {
-            let reason = match spec.commits[idx].history.last() {
-                Some(HistoryEntry::Stuck(r)) => r.as_str(),
-                _ => "Unknown reason",
-            };
+            let reason = spec.commits[idx]
+                .stuck_reason()
+                .unwrap_or("Unknown reason");
 
             if let Some(response) = hooks.on_stuck(reason) {
                 let mut spec = spec;
@@ -177,16 +475,16 @@ pub async fn execute_with_hooks(
                         .history
                         .push(HistoryEntry::Resolved(response));
                 }
-                save_spec(spec_path, &spec)?;
+                save_spec(state_path, &spec).map_err(|e| fail(&spec, e))?;
                 continue;
             }
 
             // User cancelled
-            return Ok(());
+            return Ok(spec);
         }
 
         // Not stuck — we're done
-        return Ok(());
+        return Ok(spec);
     }
 }
 
@@ -227,14 +525,325 @@ where
     R: Role + HasPeer<Agent>,
     H: ExecuteHooks,
 {
+    let mut spec = spec;
+    if config.preflight
+        && let Err(e) = preflight_tools(d, git, config, hooks).await
+    {
+        return Err((spec, e));
+    }
+    if config.fresh
+        && let Err(e) = start_fresh(git, &mut spec, config, hooks)
+    {
+        return Err((spec, e));
+    }
     execute_inner(d, spec, git, None, config, hooks).await
 }
 
+/// How the LLM would split the changes among the spec's commits.
+///
+/// Produced by [`plan_extraction`] without modifying any files.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ExtractionPlan {
+    /// One entry per commit in the spec, in order
+    pub commits: Vec<PlannedCommit>,
+    /// Changes the LLM could not place in any commit
+    pub unassigned: Vec<PlannedChange>,
+}
+
+/// The changes the LLM would put in one commit.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PlannedCommit {
+    /// Which commit this is (1-indexed, matching the spec order)
+    pub commit_number: usize,
+    /// The changes (hunks or groups of hunks) that belong to it
+    pub changes: Vec<PlannedChange>,
+}
+
+/// A hunk, or a group of related hunks, from the full diff.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PlannedChange {
+    /// File the change is in
+    pub file: String,
+    /// Short description of the change
+    pub description: String,
+}
+
+/// Ask the LLM how it would divide the full diff among the spec's commits.
+///
+/// This is a single think block over the whole `base..target` diff. It does
+/// not write files, create branches, or commit, so it is a cheap way to
+/// sanity-check the commit boundaries before running the full loop.
+pub async fn plan_extraction<R>(
+    d: &Determinishtic<R>,
+    git: &Git,
+    spec: &HistorySpec,
+    config: &ExecuteConfig,
+) -> Result<ExtractionPlan, Error>
+where
+    R: Role + HasPeer<Agent>,
+{
+    spec.validate()?;
+    let base = resolve_base(git, spec)?;
+    let diff_stat = git.diff_stat(&base, spec.target())?;
+    let commit_list: String = spec
+        .commits
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            format!(
+                "{}. {}\n   Hints: {}",
+                i + 1,
+                c.message,
+                hints_for_prompt(c)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let request = tools::with_file_tools(d.think(), git, config.max_read_file_bytes)
+        .textln("# Task: Plan how to split changes into commits")
+        .textln("")
+        .textln("You are planning a reconstruction of clean git history from a messy branch.")
+        .textln("Do NOT modify any files or run any commands that change the repository.")
+        .textln("Only describe which changes you would put in each commit.")
+        .textln(&format!(
+            "Work in the repository at: {}",
+            git.root().display()
+        ))
+        .textln("")
+        .textln("## Commits to create, in order:")
+        .textln(&commit_list)
+        .textln("")
+        .textln(&format!("## Files changed ({base}..{}):", spec.target()))
+        .textln("```")
+        .text(&diff_stat)
+        .textln("```")
+        .textln("")
+        .textln(&format!(
+            "To see the full diff, run: git diff {base} {}",
+            spec.target()
+        ))
+        .textln("")
+        .textln("## Instructions:")
+        .textln("1. Read the full diff")
+        .textln("2. Assign every hunk (or group of related hunks) to the commit it belongs to")
+        .textln(
+            "3. Return one entry per commit, plus any changes that fit no commit as unassigned",
+        );
+    ask_agent(git, config, request).await
+}
+
+/// Print an extraction plan through the hooks.
+fn report_extraction_plan<H: ExecuteHooks>(spec: &HistorySpec, plan: &ExtractionPlan, hooks: &H) {
+    let total = spec.commits.len();
+    for (idx, commit) in spec.commits.iter().enumerate() {
+        hooks.report(&format!(
+            "\nCommit {}/{}: {}",
+            idx + 1,
+            total,
+            commit.subject()
+        ));
+        let changes = plan
+            .commits
+            .iter()
+            .filter(|p| p.commit_number == idx + 1)
+            .flat_map(|p| &p.changes)
+            .collect::<Vec<_>>();
+        if changes.is_empty() {
+            hooks.report("  (no changes)");
+        }
+        for change in changes {
+            hooks.report(&format!("  {}: {}", change.file, change.description));
+        }
+    }
+    if !plan.unassigned.is_empty() {
+        hooks.report("\nUnassigned:");
+        for change in &plan.unassigned {
+            hooks.report(&format!("  {}: {}", change.file, change.description));
+        }
+    }
+}
+
+/// Ask the LLM which commit each change left over after reconstruction
+/// belongs to, without creating any fixup commits.
+///
+/// Returns `None` if nothing is left over.
+async fn plan_finalize<R>(
+    d: &Determinishtic<R>,
+    git: &Git,
+    spec: &HistorySpec,
+    config: &ExecuteConfig,
+) -> Result<Option<ExtractionPlan>, Error>
+where
+    R: Role + HasPeer<Agent>,
+{
+    let Some(diff_stat) = remaining_for_finalize(git, spec, config)? else {
+        return Ok(None);
+    };
+    let commit_summary: String = spec
+        .commits
+        .iter()
+        .enumerate()
+        .map(|(i, c)| format!("{}. {}", i + 1, c.message))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let request = tools::with_file_tools(d.think(), git, config.max_read_file_bytes)
+        .textln("# Task: Plan fixup commits for remaining changes")
+        .textln("")
+        .textln("The main reconstruction is complete, but some changes were missed.")
+        .textln("Do NOT modify any files or run any commands that change the repository.")
+        .textln("Only describe which commit each remaining change belongs to.")
+        .textln(&format!(
+            "Work in the repository at: {}",
+            git.root().display()
+        ))
+        .textln("")
+        .textln("## Commits that were created:")
+        .textln(&commit_summary)
+        .textln("")
+        .textln(&format!(
+            "## Remaining files changed (HEAD..{}):",
+            spec.target()
+        ))
+        .textln("```")
+        .text(&diff_stat)
+        .textln("```")
+        .textln("")
+        .textln(&format!(
+            "To see the full diff, run: git diff HEAD {}",
+            spec.target()
+        ))
+        .textln("")
+        .textln("## Instructions:")
+        .textln("1. Read the full diff")
+        .textln("2. Assign every hunk (or group of related hunks) to the commit it belongs to")
+        .textln(
+            "3. Return an entry for each commit that gets changes, \
+             plus any changes that fit no commit as unassigned",
+        );
+    let plan = ask_agent(git, config, request).await?;
+    Ok(Some(plan))
+}
+
+/// Print a plan for leftover changes through the hooks.
+fn report_finalize_plan<H: ExecuteHooks>(spec: &HistorySpec, plan: &ExtractionPlan, hooks: &H) {
+    for line in render_finalize_plan(spec, plan).lines() {
+        hooks.report(line);
+    }
+}
+
+/// A plan for leftover changes as text, listing only the commits that get changes.
+fn render_finalize_plan(spec: &HistorySpec, plan: &ExtractionPlan) -> String {
+    let mut text = String::new();
+    for planned in plan.commits.iter().filter(|p| !p.changes.is_empty()) {
+        let subject = spec
+            .commits
+            .get(planned.commit_number.wrapping_sub(1))
+            .map_or("(no such commit)", |c| c.subject());
+        text.push_str(&format!("Commit {}: {subject}\n", planned.commit_number));
+        for change in &planned.changes {
+            text.push_str(&format!("  {}: {}\n", change.file, change.description));
+        }
+    }
+    if !plan.unassigned.is_empty() {
+        text.push_str("Unassigned:\n");
+        for change in &plan.unassigned {
+            text.push_str(&format!("  {}: {}\n", change.file, change.description));
+        }
+    }
+    text
+}
+
+/// Check that the agent accepts retcon's tool schemas before doing any work.
+///
+/// Registers the tools on a trivial think block that doesn't use them, so
+/// an agent that rejects the schemas fails here with [`Error::ToolCheck`]
+/// instead of partway through a commit.
+async fn preflight_tools<R, H>(
+    d: &Determinishtic<R>,
+    git: &Git,
+    config: &ExecuteConfig,
+    hooks: &H,
+) -> Result<(), Error>
+where
+    R: Role + HasPeer<Agent>,
+    H: ExecuteHooks,
+{
+    hooks.report("Checking agent tool compatibility...");
+    config.rate_limit.wait().await;
+    let result: PreflightResult =
+        tools::with_file_tools(d.think(), git, config.max_read_file_bytes)
+            .textln("# Task: Tool check")
+            .textln("")
+            .textln("This is a startup check. Do not call any tools; just return ok = true.")
+            .await
+            .map_err(|e| Error::ToolCheck {
+                message: e.to_string(),
+            })?;
+    if !result.ok {
+        return Err(Error::ToolCheck {
+            message: "agent did not confirm the tool check".to_string(),
+        });
+    }
+    hooks.report("Agent accepted tools.");
+    Ok(())
+}
+
 /// Internal implementation shared by both execute variants.
 ///
 /// Advances as far as it can in a single pass, saving the spec to disk
 /// after each state change. Returns the final spec state.
+///
+/// Retcon never writes to `source`. As a last line of defense against a bug
+/// that does, this checks that `source` still points where it did before
+/// the pass, and fails loudly if not.
 async fn execute_inner<R, H>(
+    d: &Determinishtic<R>,
+    spec: HistorySpec,
+    git: &Git,
+    spec_path: Option<&Path>,
+    config: &ExecuteConfig,
+    hooks: &H,
+) -> Result<HistorySpec, (HistorySpec, Error)>
+where
+    R: Role + HasPeer<Agent>,
+    H: ExecuteHooks,
+{
+    let validated = spec.validate().and_then(|()| match config.direction {
+        Direction::Reverse => spec.check_reversible(),
+        Direction::Forward => Ok(()),
+    });
+    if let Err(e) = validated {
+        return Err((spec, e.into()));
+    }
+    let source_ref = spec.source.clone();
+    let before = match git.rev_parse(&source_ref) {
+        Ok(hash) => hash,
+        Err(e) => return Err((spec, e.into())),
+    };
+
+    let result = execute_pass(d, spec, git, spec_path, config, hooks).await;
+
+    let after = git.rev_parse(&source_ref).ok();
+    if after.as_deref() == Some(before.as_str()) {
+        return result;
+    }
+    let spec = match result {
+        Ok(spec) | Err((spec, _)) => spec,
+    };
+    Err((
+        spec,
+        Error::SourceModified {
+            source_ref,
+            before,
+            after: after.unwrap_or_else(|| "nothing".to_string()),
+        },
+    ))
+}
+
+/// A single pass of the reconstruction loop; see [`execute_inner`].
+async fn execute_pass<R, H>(
     d: &Determinishtic<R>,
     mut spec: HistorySpec,
     git: &Git,
@@ -246,48 +855,126 @@ where
     R: Role + HasPeer<Agent>,
     H: ExecuteHooks,
 {
+    // Every commit retcon makes goes through this handle, so hook settings apply uniformly
+    let git = &git.clone().with_no_verify(config.no_verify);
+
     let total = spec.commits.len();
     let verify_idx = total; // index of the "verify" entry in the plan
 
     // Initialize the plan: all commits + a final "verify" step
-    let mut plan_messages: Vec<&str> = spec.commits.iter().map(|c| c.message.lines().next().unwrap_or("")).collect();
+    let mut plan_messages: Vec<&str> = spec.commits.iter().map(|c| c.subject()).collect();
     plan_messages.push("Verify branch matches source");
     hooks.plan_init(&plan_messages);
 
     // Mark already-completed/stuck commits so the UI shows their status immediately
     for (i, commit) in spec.commits.iter().enumerate() {
         if commit.is_complete() {
-            hooks.plan_update(i, CommitStatus::Completed);
+            hooks.plan_update(i, completed_status(commit, false));
         } else if commit.is_stuck() {
             hooks.plan_update(i, CommitStatus::Stuck);
         }
     }
 
-    // Set up git state: create cleaned branch from merge-base if it doesn't exist
-    setup_cleaned_branch(git, &spec, hooks).map_err(|e| (spec.clone(), e))?;
+    // In script mode all work happens in a scratch worktree, leaving the
+    // repository's branches untouched
+    let repo_git = git;
+    if config.fetch {
+        fetch_remote(git, &spec, hooks).map_err(|e| (spec.clone(), e))?;
+    }
+    let base = resolve_base(git, &spec).map_err(|e| (spec.clone(), e))?;
+    warn_multiple_merge_bases(git, &spec, &base, hooks).map_err(|e| (spec.clone(), e))?;
+    override_target(git, &mut spec, &base, config, hooks).map_err(|e| (spec.clone(), e))?;
+    let workspace = match &config.emit_script {
+        Some(script_path) => {
+            let (workspace, created) =
+                ScriptWorkspace::open_or_create(git, script_path, &base, config.worktree_force)
+                    .map_err(|e| (spec.clone(), e.into()))?;
+            hooks.report(&format!(
+                "{} scratch worktree: {}",
+                if created { "Created" } else { "Reusing" },
+                workspace.git().root().display()
+            ));
+            Some(workspace)
+        }
+        None => {
+            // Set up git state: create cleaned branch from merge-base if it doesn't exist
+            setup_cleaned_branch(git, &spec, &base, hooks).map_err(|e| (spec.clone(), e))?;
+            if config.tag_base {
+                let tag = format!("{}-base", spec.cleaned);
+                git.tag(&tag, &base).map_err(|e| (spec.clone(), e.into()))?;
+                hooks.report(&format!("Tagged base as {tag}"));
+            }
+            None
+        }
+    };
+    let git = workspace.as_ref().map_or(repo_git, |w| w.git());
+    if spec.base_resolved.as_deref() != Some(base.as_str()) {
+        spec.base_resolved = Some(base.clone());
+        if let Some(p) = spec_path {
+            save_spec(p, &spec).map_err(|e| (spec.clone(), e))?;
+        }
+    }
+
+    if config.build_command.is_none() && config.test_command.is_none() {
+        hooks.report("No build or test command configured; commits will not be verified.");
+    }
 
     // Find where to resume (may be None if all commits are already done)
-    if let Some(start_idx) = spec.next_pending_commit() {
+    let next_pending = spec.commits.iter().position(|c| {
+        !c.is_complete()
+            && config
+                .filter_message
+                .as_ref()
+                .is_none_or(|filter| filter.is_match(&c.message))
+    });
+    if let Some(start_idx) = next_pending {
         hooks.report(&format!(
             "Resuming from commit {}/{}: {}",
             start_idx + 1,
             total,
-            spec.commits[start_idx].message.lines().next().unwrap_or("")
+            spec.commits[start_idx].subject()
         ));
+    } else if spec.next_pending_commit().is_some() {
+        hooks.report("No pending commits match --filter-message.");
     } else {
         hooks.report("All commits complete, verifying final state...");
     }
 
+    if config.direction == Direction::Reverse {
+        match reconstruct_reverse(d, git, &mut spec, spec_path, config, hooks).await {
+            Ok(true) => return Ok(spec),
+            Ok(false) => {}
+            Err(e) => return Err((spec, e)),
+        }
+    }
+
     // Process each commit
+    let mut consecutive_stuck = 0;
     for commit_idx in 0..spec.commits.len() {
         // Extract state from commit before any mutation
         if spec.commits[commit_idx].is_complete() {
-            hooks.plan_update(commit_idx, CommitStatus::Completed);
+            hooks.plan_update(
+                commit_idx,
+                completed_status(&spec.commits[commit_idx], false),
+            );
+            consecutive_stuck = 0;
+            continue;
+        }
+
+        if let Some(filter) = &config.filter_message
+            && !filter.is_match(&spec.commits[commit_idx].message)
+        {
             continue;
         }
 
         if spec.commits[commit_idx].is_stuck() {
             hooks.plan_update(commit_idx, CommitStatus::Stuck);
+            if config.continue_on_stuck {
+                consecutive_stuck += 1;
+                check_consecutive_stuck(consecutive_stuck, config)
+                    .map_err(|e| (spec.clone(), e))?;
+                continue;
+            }
             return Ok(spec);
         }
 
@@ -299,7 +986,7 @@ where
             "\nCommit {}/{}: {}",
             commit_idx + 1,
             total,
-            spec.commits[commit_idx].message.lines().next().unwrap_or("")
+            spec.commits[commit_idx].subject()
         ));
 
         if was_interrupted {
@@ -310,6 +997,26 @@ where
             hooks.report(&format!("  Resolved: {note}"));
         }
 
+        // Undo the stuck attempt's commits, keeping their changes (and the
+        // user's edits) in the working tree for the retry to build on
+        if config.retry_clean
+            && resolution_note.is_some()
+            && let Some(first) = spec.commits[commit_idx].first_commit()
+        {
+            let parent = git
+                .rev_parse(&format!("{first}^"))
+                .map_err(|e| (spec.clone(), e.into()))?;
+            git.reset_mixed(&parent)
+                .map_err(|e| (spec.clone(), e.into()))?;
+            hooks.report(&format!(
+                "  Rolled back to {} to retry as one commit; the working tree is kept",
+                &parent[..8.min(parent.len())]
+            ));
+            spec.commits[commit_idx]
+                .history
+                .push(HistoryEntry::RolledBack(parent));
+        }
+
         // Record Started and save before doing any work
         if !was_interrupted {
             spec.commits[commit_idx].history.push(HistoryEntry::Started);
@@ -318,6 +1025,21 @@ where
             }
         }
 
+        // Save entries as they are recorded, so a crash mid-commit keeps
+        // them, and remember them so an error below doesn't drop them
+        let recorded = std::sync::Mutex::new(Vec::new());
+        let save_progress = |entries: &[HistoryEntry]| -> Result<(), Error> {
+            *recorded.lock().unwrap() = entries.to_vec();
+            let Some(p) = spec_path else {
+                return Ok(());
+            };
+            let mut snapshot = spec.clone();
+            snapshot.commits[commit_idx]
+                .history
+                .extend(entries.iter().cloned());
+            save_spec(p, &snapshot)
+        };
+
         // Run the reconstruction for this commit
         let result = reconstruct_commit(
             d,
@@ -326,6 +1048,7 @@ where
             commit_idx,
             was_interrupted,
             resolution_note.as_deref(),
+            &save_progress,
             config,
             hooks,
         )
@@ -339,51 +1062,596 @@ where
                 }
 
                 if spec.commits[commit_idx].is_complete() {
-                    hooks.plan_update(commit_idx, CommitStatus::Completed);
-                    hooks.report("  ✓ Commit complete");
+                    // The stray-file warning was given before the commit
+                    // completed; look again so the plan flags it too
+                    let warned = check_expected_files(git, &spec.commits[commit_idx], hooks)
+                        .and_then(|warned| Ok(warned || !stray_files(git, &spec)?.is_empty()))
+                        .map_err(|e| (spec.clone(), e))?;
+                    if let Some(dir) = &config.patch_dir {
+                        write_commit_patch(git, &spec, commit_idx, dir)
+                            .map_err(|e| (spec.clone(), e))?;
+                    }
+                    let status = completed_status(&spec.commits[commit_idx], warned);
+                    run_on_commit(git, &spec, commit_idx, status, config, hooks);
+                    hooks.plan_update(commit_idx, status);
+                    if status == CommitStatus::NeedsReview {
+                        hooks.report("  ✓ Commit complete (worth a review)");
+                    } else {
+                        hooks.report("  ✓ Commit complete");
+                    }
+                    consecutive_stuck = 0;
                 } else if spec.commits[commit_idx].is_stuck() {
+                    run_on_commit(git, &spec, commit_idx, CommitStatus::Stuck, config, hooks);
                     hooks.plan_update(commit_idx, CommitStatus::Stuck);
+                    if config.continue_on_stuck {
+                        hooks.report("  ✗ Stuck, continuing with the next commit");
+                        // Drop everything the attempt left behind so it doesn't leak into the next commit
+                        abandon_stuck_commit(git, &mut spec.commits[commit_idx])
+                            .map_err(|e| (spec.clone(), e))?;
+                        if let Some(p) = spec_path {
+                            save_spec(p, &spec).map_err(|e| (spec.clone(), e))?;
+                        }
+                        consecutive_stuck += 1;
+                        check_consecutive_stuck(consecutive_stuck, config)
+                            .map_err(|e| (spec.clone(), e))?;
+                        continue;
+                    }
                     return Ok(spec);
                 }
             }
             Err(e) => {
-                spec.commits[commit_idx]
-                    .history
-                    .push(HistoryEntry::Stuck(e.to_string()));
-                if let Some(p) = spec_path {
-                    let _ = save_spec(p, &spec);
+                let recorded = std::mem::take(&mut *recorded.lock().unwrap());
+                let history = &mut spec.commits[commit_idx].history;
+                history.extend(recorded);
+                history.push(HistoryEntry::Stuck(StuckReason::new(
+                    StuckKind::Interrupted,
+                    e.to_string(),
+                )));
+                if let Some(p) = spec_path
+                    && let Err(save_error) = save_spec(p, &spec)
+                {
+                    hooks.report(&format!("  ⚠ Couldn't save the spec: {save_error}"));
                 }
+                run_on_commit(git, &spec, commit_idx, CommitStatus::Stuck, config, hooks);
                 hooks.plan_update(commit_idx, CommitStatus::Stuck);
                 return Err((spec, e));
             }
         }
     }
 
+    let stuck_count = spec.commits.iter().filter(|c| c.is_stuck()).count();
+    if stuck_count > 0 {
+        hooks.report(&format!(
+            "\n{stuck_count} commit(s) stuck; skipping final verification until they are resolved."
+        ));
+        return Ok(spec);
+    }
+
+    let pending_count = spec.commits.iter().filter(|c| !c.is_complete()).count();
+    if pending_count > 0 {
+        hooks.report(&format!(
+            "\n{pending_count} commit(s) left pending by --filter-message; skipping final verification."
+        ));
+        return Ok(spec);
+    }
+
     hooks.report("\nAll specified commits reconstructed.");
 
     // Catchall phase: ensure cleaned branch matches source exactly
     hooks.plan_update(verify_idx, CommitStatus::InProgress);
-    finalize_remaining_changes(d, git, &spec, hooks)
+
+    // The remainder commit takes all the leftovers, so finalizing finds none
+    if let Some(message) = &config.remainder_commit {
+        commit_remainder(git, &spec, message, config, hooks)
+            .await
+            .map_err(|e| (spec.clone(), e))?;
+    }
+
+    // Preview where the leftovers would go before committing any of them
+    let finalize_plan = if config.dry_finalize || config.confirm_finalize {
+        plan_finalize(d, git, &spec, config)
+            .await
+            .map_err(|e| (spec.clone(), e))?
+    } else {
+        None
+    };
+    if let Some(plan) = &finalize_plan {
+        hooks.report("\nPlan for the remaining changes:");
+        report_finalize_plan(&spec, plan, hooks);
+        if config.dry_finalize {
+            hooks.plan_update(verify_idx, CommitStatus::Pending);
+            hooks.report("\nStopping before creating fixup commits (--dry-finalize).");
+            return Ok(spec);
+        }
+        if !hooks.approve_finalize(plan) {
+            hooks.plan_update(verify_idx, CommitStatus::Pending);
+            hooks.report("\nLeaving the remaining changes uncommitted; run again to finalize.");
+            return Ok(spec);
+        }
+    }
+
+    let catchall = finalize_remaining_changes(d, git, &spec, finalize_plan.as_ref(), config, hooks)
         .await
         .map_err(|e| {
             hooks.plan_update(verify_idx, CommitStatus::Stuck);
             (spec.clone(), e)
         })?;
 
-    hooks.plan_update(verify_idx, CommitStatus::Completed);
+    verify_matches_target(git, &spec, config).map_err(|e| {
+        hooks.plan_update(verify_idx, CommitStatus::Stuck);
+        (spec.clone(), e)
+    })?;
+
+    hooks.plan_update(
+        verify_idx,
+        if catchall {
+            CommitStatus::NeedsReview
+        } else {
+            CommitStatus::Completed
+        },
+    );
     hooks.report("\nComplete! Reconstructed branch matches source.");
-    Ok(spec)
-}
 
-/// Reconstruct a single commit, returning history entries to append.
-async fn reconstruct_commit<R, H>(
-    d: &Determinishtic<R>,
-    git: &Git,
-    spec: &HistorySpec,
-    commit_idx: usize,
-    was_interrupted: bool,
-    resolution_note: Option<&str>,
-    config: &ExecuteConfig,
+    // Before autosquash, which would rewrite the commits the patches were made from
+    if let Some(dir) = &config.patch_dir {
+        write_manifest(git, &spec, &base, dir).map_err(|e| (spec.clone(), e))?;
+    }
+
+    if config.autosquash {
+        autosquash_fixups(git, &mut spec, &base, hooks).map_err(|e| (spec.clone(), e))?;
+        if let Some(p) = spec_path {
+            save_spec(p, &spec).map_err(|e| (spec.clone(), e))?;
+        }
+    }
+
+    if let Ok(history) = git.log_between(&base, "HEAD") {
+        hooks.report(&format!("\nCommits on {}:", spec.cleaned));
+        for entry in &history {
+            hooks.report(&format!("  {} {}", entry.hash, entry.subject));
+        }
+    }
+
+    if let Some(path) = &config.range_diff {
+        let range_diff = git
+            .range_diff(&base, &spec.source, "HEAD")
+            .map_err(|e| (spec.clone(), e.into()))?;
+        if path.as_os_str() == "-" {
+            hooks.report(&format!(
+                "\nRange diff against {}:\n{range_diff}",
+                spec.source
+            ));
+        } else {
+            std::fs::write(path, range_diff).map_err(|e| {
+                (
+                    spec.clone(),
+                    Error::WriteRangeDiff {
+                        path: path.display().to_string(),
+                        source: e,
+                    },
+                )
+            })?;
+            hooks.report(&format!("\nWrote range diff to {}", path.display()));
+        }
+    }
+
+    if let Some(workspace) = workspace {
+        workspace
+            .finish(repo_git, &base, &spec.cleaned)
+            .map_err(|e| (spec.clone(), e.into()))?;
+        if let Some(script_path) = &config.emit_script {
+            hooks.report(&format!(
+                "\nWrote {}; run it to create branch {}.",
+                script_path.display(),
+                spec.cleaned
+            ));
+        }
+    }
+
+    Ok(spec)
+}
+
+/// Commit everything still differing from the target as one commit.
+///
+/// For runs where only the first few commits need to be reviewable; unlike
+/// the catchall commit, this one is meant to stay.
+async fn commit_remainder<H: ExecuteHooks>(
+    git: &Git,
+    spec: &HistorySpec,
+    message: &str,
+    config: &ExecuteConfig,
+    hooks: &H,
+) -> Result<(), Error> {
+    if remaining_diff_stat(git, spec)?.is_none() {
+        return Ok(());
+    }
+    let unmerged = git.unmerged_paths()?;
+    if !unmerged.is_empty() {
+        return Err(Error::UnmergedPaths {
+            paths: unmerged.join(", "),
+        });
+    }
+    git.restore_tree(spec.target())?;
+    if config.distinct_commit_times {
+        wait_past_head_time(git).await?;
+    }
+    // `read-tree` left the index at the target; committing it as is keeps
+    // untracked files in the working tree out of the commit
+    let hash = git.commit_index(message)?;
+    hooks.report(&format!(
+        "\nCreated {hash}: {}",
+        message.lines().next().unwrap_or("")
+    ));
+    Ok(())
+}
+
+/// Fold the branch's `fixup!` commits into their targets, and point the
+/// spec's history at the rewritten commits.
+///
+/// Like `git rebase -i --autosquash`, each fixup goes right after the latest
+/// earlier commit with the subject it names. `squash!` commits are left
+/// where they are, since their subject line would stay in the combined
+/// message with nobody there to edit it. A rebase that can't finish is
+/// undone, and the fix commits stay as they are for the user to sort out.
+fn autosquash_fixups<H: ExecuteHooks>(
+    git: &Git,
+    spec: &mut HistorySpec,
+    base: &str,
+    hooks: &H,
+) -> Result<(), Error> {
+    let log = git.log_between(base, "HEAD")?;
+
+    // Each group is a commit to keep, followed by the fixups folded into it
+    let mut groups: Vec<Vec<&LogEntry>> = Vec::new();
+    for entry in &log {
+        let mut subject = entry.subject.as_str();
+        while let Some(rest) = subject.strip_prefix("fixup! ") {
+            subject = rest;
+        }
+        let target = (subject != entry.subject)
+            .then(|| groups.iter().rposition(|group| group[0].subject == subject))
+            .flatten();
+        match target {
+            Some(idx) => groups[idx].push(entry),
+            None => groups.push(vec![entry]),
+        }
+    }
+    let fixups = log.len() - groups.len();
+    if fixups == 0 {
+        return Ok(());
+    }
+
+    let todo: String = groups
+        .iter()
+        .flat_map(|group| {
+            group.iter().enumerate().map(|(i, entry)| {
+                let action = if i == 0 { "pick" } else { "fixup" };
+                format!("{action} {}\n", entry.hash)
+            })
+        })
+        .collect();
+    if let Err(e) = git.rebase_with_todo(base, &todo) {
+        hooks.report(&format!(
+            "  ⚠ Couldn't autosquash the fixup commits, so they are left in place: {e}\n  \
+             Fold them in by hand with: git rebase -i --autosquash {base}"
+        ));
+        return Ok(());
+    }
+    hooks.report(&format!(
+        "Squashed {fixups} fixup commit(s) into their targets"
+    ));
+
+    // Every group became one commit, in order, so a fixup's history entry
+    // now names the commit it was folded into
+    let rewritten = git.log_between(base, "HEAD")?;
+    if rewritten.len() != groups.len() {
+        hooks.report("  ⚠ The rewritten commits don't line up with the old ones, so the spec's history still names the old ones");
+        return Ok(());
+    }
+    let renamed: HashMap<&str, &str> = groups
+        .iter()
+        .zip(&rewritten)
+        .flat_map(|(group, new)| {
+            group
+                .iter()
+                .map(|old| (old.hash.as_str(), new.hash.as_str()))
+        })
+        .collect();
+    for commit in &mut spec.commits {
+        for entry in &mut commit.history {
+            if let HistoryEntry::CommitCreated(hash) = entry
+                && let Some(new) = renamed.get(hash.as_str())
+            {
+                *hash = new.to_string();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// List the commits on the cleaned branch, from its base to its tip.
+///
+/// Useful for checking that the reconstructed history has the shape the
+/// spec intended, including any fixup or catchall commits that were added.
+pub fn cleaned_history(git: &Git, spec: &HistorySpec) -> Result<Vec<LogEntry>, Error> {
+    let base = resolve_base(git, spec)?;
+    Ok(git.log_between(&base, &spec.cleaned)?)
+}
+
+/// The changes still missing from the cleaned branch, as a unified diff.
+///
+/// This is `git diff-tree -p cleaned target`; once reconstruction is
+/// finished it is empty, and it is empty exactly when [`remaining_is_empty`]
+/// says so.
+pub fn remaining_diff(git: &Git, spec: &HistorySpec) -> Result<String, Error> {
+    Ok(git.diff_tree_patch(&spec.cleaned, spec.target())?)
+}
+
+/// Whether the cleaned branch already matches the target.
+///
+/// Like [`remaining_diff`], this compares the trees with `git diff-tree`, so
+/// user diff settings can't make the two disagree.
+pub fn remaining_is_empty(git: &Git, spec: &HistorySpec) -> Result<bool, Error> {
+    Ok(git.diff_tree(&spec.cleaned, spec.target())?.is_empty())
+}
+
+/// Check that HEAD's tree is exactly the target's, which every finished
+/// reconstruction must guarantee.
+///
+/// With `ignore_whitespace`, files whose only differences are whitespace
+/// are let through, but nothing else is.
+fn verify_matches_target(
+    git: &Git,
+    spec: &HistorySpec,
+    config: &ExecuteConfig,
+) -> Result<(), Error> {
+    let paths = remaining_paths(git, spec, config)?;
+    if paths.is_empty() {
+        return Ok(());
+    }
+    Err(Error::TargetMismatch {
+        target: spec.target().to_string(),
+        paths: paths.join(", "),
+    })
+}
+
+/// Diffstat of what is left between the working HEAD and the target, or
+/// `None` if nothing is.
+fn remaining_diff_stat(git: &Git, spec: &HistorySpec) -> Result<Option<String>, Error> {
+    let diff_stat = git.diff_stat("HEAD", spec.target())?;
+    Ok((!diff_stat.trim().is_empty()).then_some(diff_stat))
+}
+
+/// Files HEAD still differs from the target in, leaving out the
+/// whitespace-only ones with `ignore_whitespace`.
+fn remaining_paths(
+    git: &Git,
+    spec: &HistorySpec,
+    config: &ExecuteConfig,
+) -> Result<Vec<String>, Error> {
+    let mut paths = git.diff_tree("HEAD", spec.target())?;
+    if config.ignore_whitespace && !paths.is_empty() {
+        let ignored = git.whitespace_only_changes("HEAD", spec.target())?;
+        paths.retain(|path| !ignored.contains(path));
+    }
+    Ok(paths)
+}
+
+/// What is left for the final check, honoring `ignore_whitespace`.
+fn remaining_for_finalize(
+    git: &Git,
+    spec: &HistorySpec,
+    config: &ExecuteConfig,
+) -> Result<Option<String>, Error> {
+    if !config.ignore_whitespace {
+        return remaining_diff_stat(git, spec);
+    }
+    if remaining_paths(git, spec, config)?.is_empty() {
+        return Ok(None);
+    }
+    // `-w` drops mode changes and empty files from the stat, so fall back
+    // to the full one rather than showing nothing
+    let diff_stat = git.diff_stat_ignoring_whitespace("HEAD", spec.target())?;
+    if diff_stat.trim().is_empty() {
+        return remaining_diff_stat(git, spec);
+    }
+    Ok(Some(diff_stat))
+}
+
+/// Say which files still differ from the target in whitespace only, once
+/// everything else matches; `ignore_whitespace` let them through.
+fn report_ignored_whitespace<H: ExecuteHooks>(
+    git: &Git,
+    spec: &HistorySpec,
+    config: &ExecuteConfig,
+    hooks: &H,
+) -> Result<(), Error> {
+    if !config.ignore_whitespace {
+        return Ok(());
+    }
+    let files = git.whitespace_only_changes("HEAD", spec.target())?;
+    if !files.is_empty() {
+        hooks.report(&format!(
+            "\nIgnored whitespace-only differences from the target in: {}",
+            files.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Files the cleaned branch has added that the target doesn't have.
+///
+/// A file the target deletes from the base is expected to still be around
+/// until the commit that deletes it; one that is in neither the base nor the
+/// target can only have been created by mistake.
+fn stray_files(git: &Git, spec: &HistorySpec) -> Result<Vec<String>, Error> {
+    let Some(base) = spec.base_resolved.as_deref() else {
+        return Ok(Vec::new());
+    };
+    let missing_from_target = git.changed_paths("HEAD", spec.target(), "D")?;
+    if missing_from_target.is_empty() {
+        return Ok(missing_from_target);
+    }
+    let added = git.changed_paths(base, "HEAD", "A")?;
+    Ok(missing_from_target
+        .into_iter()
+        .filter(|path| added.contains(path))
+        .collect())
+}
+
+/// Whether any of the commit's `expected_files` still differ from the target.
+///
+/// A commit that doesn't list its files could be about anything, so it
+/// always counts as having changes.
+fn has_expected_changes(
+    git: &Git,
+    spec: &HistorySpec,
+    commit_spec: &CommitSpec,
+) -> Result<bool, Error> {
+    if commit_spec.expected_files.is_empty() {
+        return Ok(true);
+    }
+    let remaining = git.changed_paths("HEAD", spec.target(), "ACDMRT")?;
+    Ok(commit_spec
+        .expected_files
+        .iter()
+        .any(|path| remaining.contains(path)))
+}
+
+/// Group the files left to extract into sets small enough for one prompt each.
+///
+/// With no `max_prompt_tokens`, or a remaining diff that fits, this is a
+/// single part with every file. Tokens are estimated at four bytes each. A
+/// file whose diff alone is over the limit still gets a part of its own.
+fn extraction_parts(
+    git: &Git,
+    spec: &HistorySpec,
+    config: &ExecuteConfig,
+) -> Result<Vec<Vec<String>>, Error> {
+    let files = git.changed_paths("HEAD", spec.target(), "ACDMRT")?;
+    let Some(max_tokens) = config.max_prompt_tokens else {
+        return Ok(vec![files]);
+    };
+    let max_bytes = max_tokens.saturating_mul(4);
+    if git.diff("HEAD", spec.target())?.len() <= max_bytes {
+        return Ok(vec![files]);
+    }
+
+    let mut parts: Vec<Vec<String>> = Vec::new();
+    let mut part_bytes = 0;
+    for file in files {
+        let bytes = git.diff_path("HEAD", spec.target(), &file)?.len();
+        match parts.last_mut() {
+            Some(part) if part_bytes + bytes <= max_bytes => {
+                part.push(file);
+                part_bytes += bytes;
+            }
+            _ => {
+                parts.push(vec![file]);
+                part_bytes = bytes;
+            }
+        }
+    }
+    Ok(parts)
+}
+
+/// A prompt section listing files whose mode changes but content doesn't,
+/// or an empty string if there are none.
+fn mode_changes_context(git: &Git, spec: &HistorySpec) -> Result<String, Error> {
+    let changes = git.mode_only_changes("HEAD", spec.target())?;
+    if changes.is_empty() {
+        return Ok(String::new());
+    }
+    let mut context = String::from("\n## Mode-only changes:\n");
+    for (path, executable) in &changes {
+        let mode = if *executable {
+            "executable"
+        } else {
+            "not executable"
+        };
+        context.push_str(&format!("- {path}: becomes {mode}\n"));
+    }
+    context.push_str(
+        "Their contents don't change, so use the set_executable tool for these \
+         files instead of rewriting them.\n",
+    );
+    Ok(context)
+}
+
+/// A prompt section listing the source branch's original commits, or an
+/// empty string if there are none.
+///
+/// The messy history still shows how the author grouped their changes,
+/// which the combined diff doesn't.
+fn original_commits_context(git: &Git, spec: &HistorySpec) -> Result<String, Error> {
+    let Some(base) = spec.base_resolved.as_deref() else {
+        return Ok(String::new());
+    };
+    let log = git.log_stat(base, &spec.source)?;
+    if log.trim().is_empty() {
+        return Ok(String::new());
+    }
+    Ok(format!(
+        "\n## Original commits on {source} (oldest first):\n```\n{log}```\n\
+         These are the commits the changes originally came in. They are not \
+         organized the way the new history should be, but how they group changes \
+         can help decide what belongs together. To see one in full, run: git show <hash>\n",
+        source = spec.source,
+        log = if log.ends_with('\n') {
+            log
+        } else {
+            format!("{log}\n")
+        },
+    ))
+}
+
+/// The message to commit a logical commit with: its own, or the one of the
+/// commit it cherry-picks.
+fn commit_message(git: &Git, commit_spec: &CommitSpec) -> Result<String, Error> {
+    match &commit_spec.cherry_pick {
+        Some(pick) if pick.inherit_message => Ok(git.commit_message(&pick.commit)?),
+        _ => Ok(commit_spec.message.clone()),
+    }
+}
+
+/// The LLM's report on which hints it used, or `None` if it reported nothing.
+fn hint_feedback(applied: Vec<String>, ignored: Vec<String>) -> Option<HintFeedback> {
+    let clean = |hints: Vec<String>| -> Vec<String> {
+        hints
+            .into_iter()
+            .map(|hint| hint.trim().to_string())
+            .filter(|hint| !hint.is_empty())
+            .collect()
+    };
+    let feedback = HintFeedback {
+        applied: clean(applied),
+        ignored: clean(ignored),
+    };
+    (feedback != HintFeedback::default()).then_some(feedback)
+}
+
+/// A commit's hints as prompt text.
+fn hints_for_prompt(commit: &CommitSpec) -> String {
+    commit
+        .hints
+        .as_ref()
+        .map_or_else(|| "No specific hints".to_string(), Hints::render)
+}
+
+/// Reconstruct a single commit, returning history entries to append.
+///
+/// Every commit created along the way is passed to `save_progress`
+/// together with the entries before it, so the spec on disk never falls
+/// more than one step behind the branch.
+#[allow(clippy::too_many_arguments)]
+async fn reconstruct_commit<R, H>(
+    d: &Determinishtic<R>,
+    git: &Git,
+    spec: &HistorySpec,
+    commit_idx: usize,
+    was_interrupted: bool,
+    resolution_note: Option<&str>,
+    save_progress: &(dyn Fn(&[HistoryEntry]) -> Result<(), Error> + Sync),
+    config: &ExecuteConfig,
     hooks: &H,
 ) -> Result<Vec<HistoryEntry>, Error>
 where
@@ -393,26 +1661,80 @@ where
     let commit_spec = &spec.commits[commit_idx];
     let mut entries = Vec::new();
 
+    // An interrupted attempt may have committed already; carry on from there
+    // rather than extracting the changes a second time
+    if was_interrupted && let Some(created) = commit_spec.interrupted_commit() {
+        hooks.report(&format!(
+            "  Already created {}; verifying it",
+            &created[..8.min(created.len())]
+        ));
+        let hints = hints_for_prompt(commit_spec);
+        return verify_commit(
+            d,
+            git,
+            spec,
+            commit_spec,
+            &hints,
+            entries,
+            save_progress,
+            config,
+            hooks,
+        )
+        .await;
+    }
+
     // Check if there are remaining changes
-    let diff_stat = git.diff_stat(&spec.cleaned, &spec.source)?;
-    if diff_stat.trim().is_empty() {
+    let Some(diff_stat) = remaining_diff_stat(git, spec)? else {
         // No more changes to extract
         entries.push(HistoryEntry::Complete);
         return Ok(entries);
+    };
+
+    // Don't ask the LLM to extract changes an earlier commit already took
+    if commit_spec.cherry_pick.is_none() && !has_expected_changes(git, spec, commit_spec)? {
+        hooks.report("  Skipped: none of its expected_files have changes remaining");
+        entries.push(HistoryEntry::Skipped(
+            "no relevant changes remaining".to_string(),
+        ));
+        entries.push(HistoryEntry::Complete);
+        return Ok(entries);
     }
 
     // Build the prompt for extracting this commit
-    let hints = commit_spec.hints.as_deref().unwrap_or("No specific hints");
+    let hints = hints_for_prompt(commit_spec);
 
     // Build resolution context if this is a retry after human intervention
     let resolution_context = resolution_note
-        .map(|note| {
-            format!(
-                "\n## Previous attempt was stuck - human provided resolution:\n{note}\n\nUse this context to guide your approach.\n"
-            )
+        .map(|note| resolution_context(note, config))
+        .unwrap_or_default();
+
+    let review_context = review_context(commit_spec);
+
+    let context_files = context_files_context(git, commit_spec, config.max_read_file_bytes);
+
+    let original_commits = if config.show_original_commits {
+        original_commits_context(git, spec)?
+    } else {
+        String::new()
+    };
+
+    let mode_changes = mode_changes_context(git, spec)?;
+
+    let file_context = config
+        .file
+        .as_deref()
+        .map(|path| {
+            format!("\n## Scope:\nOnly {path} is being reconstructed. Leave every other file as it is.\n")
         })
         .unwrap_or_default();
 
+    // The commit's own framing comes first, ahead of the generic task
+    let style = commit_spec
+        .style
+        .as_deref()
+        .map(|style| format!("{}\n\n", style.trim()))
+        .unwrap_or_default();
+
     // Build interrupted context if resuming after Ctrl-C
     let interrupted_context = if was_interrupted {
         "\n## Note: A previous run was interrupted.\nThere may be partial changes already in the working directory. Check the current state of files before making changes.\n"
@@ -420,61 +1742,213 @@ where
         ""
     };
 
-    // First pass: extract and apply changes
-    let extract_result: ExtractResult = d
-        .think()
-        .textln("# Task: Extract changes for a git commit")
-        .textln("")
-        .textln("You are reconstructing clean git history from a messy branch.")
-        .textln("Your job is to extract ONLY the changes relevant to this commit.")
-        .text(&resolution_context)
-        .text(interrupted_context)
-        .textln("")
-        .textln("## Commit to create:")
-        .textln(&format!("Message: {}", commit_spec.message))
-        .textln(&format!("Hints: {hints}"))
-        .textln("")
-        .textln(&format!("## Files changed (HEAD..{}):", spec.source))
-        .textln("```")
-        .text(&diff_stat)
-        .textln("```")
-        .textln("")
-        .textln(&format!(
-            "To see the full diff, run: git diff HEAD {}",
-            spec.source
-        ))
-        .textln("")
-        .textln("## Instructions:")
-        .textln("1. Run the git diff command above to see the available changes")
-        .textln("2. Examine current file contents if needed")
-        .textln("3. Write the relevant changes to the appropriate files")
-        .textln("4. Only include changes that belong to THIS commit based on the message and hints")
-        .textln("5. Leave other changes for subsequent commits")
-        .textln("")
-        .textln("When done, return whether you successfully applied changes.")
-        .await
-        .map_err(|e| Error::Agent {
-            message: e.to_string(),
-        })?;
+    // First pass: extract and apply changes, unless they come from an existing commit
+    let extract_result: ExtractResult = if let Some(pick) = &commit_spec.cherry_pick {
+        if let Err(e) = git.cherry_pick_no_commit(&pick.commit) {
+            entries.push(HistoryEntry::Stuck(StuckReason::new(
+                StuckKind::Other,
+                format!("cherry-pick of {} failed: {e}", pick.commit),
+            )));
+            return Ok(entries);
+        }
+        hooks.report(&format!("  Cherry-picked {}", pick.commit));
+        ExtractResult {
+            applied_changes: true,
+            hints_applied: Vec::new(),
+            hints_ignored: Vec::new(),
+        }
+    } else {
+        let parts = extraction_parts(git, spec, config)?;
+        let total = parts.len();
+        let mut combined = ExtractResult {
+            applied_changes: false,
+            hints_applied: Vec::new(),
+            hints_ignored: Vec::new(),
+        };
+        for (part, files) in parts.iter().enumerate() {
+            let (part_context, paths) = if total > 1 {
+                hooks.report(&format!(
+                    "  Extraction part {}/{total}: {} file(s)",
+                    part + 1,
+                    files.len()
+                ));
+                let list: String = files.iter().map(|f| format!("- {f}\n")).collect();
+                (
+                    format!(
+                        "\n## Part {} of {total}:\nThe remaining diff is too large for one prompt, \
+                         so this commit is extracted in parts. In this part, only look at and \
+                         change these files; the other parts cover the rest:\n{list}",
+                        part + 1
+                    ),
+                    files
+                        .iter()
+                        .fold(" --".to_string(), |args, f| args + " " + &shell_quote(f)),
+                )
+            } else {
+                (String::new(), String::new())
+            };
+            let request = tools::with_file_tools(d.think(), git, config.max_read_file_bytes)
+                .text(&style)
+                .textln("# Task: Extract changes for a git commit")
+                .textln("")
+                .textln("You are reconstructing clean git history from a messy branch.")
+                .textln("Your job is to extract ONLY the changes relevant to this commit.")
+                .textln(&format!(
+                    "Work in the repository at: {}",
+                    git.root().display()
+                ))
+                .text(&resolution_context)
+                .text(&review_context)
+                .text(interrupted_context)
+                .text(&file_context)
+                .text(&part_context)
+                .textln("")
+                .textln("## Commit to create:")
+                .textln(&format!("Message: {}", commit_spec.message))
+                .textln(&format!("Hints: {hints}"))
+                .textln("")
+                .textln(&format!("## Files changed (HEAD..{}):", spec.target()))
+                .textln("```")
+                .text(&diff_stat)
+                .textln("```")
+                .textln("")
+                .textln(&format!("To see the full diff, run: git diff HEAD {}{paths}", spec.target()))
+                .text(&mode_changes)
+                .text(&context_files)
+                .text(&original_commits)
+                .textln("")
+                .textln("## Instructions:")
+                .textln("1. Run the git diff command above to see the available changes")
+                .textln("2. Examine current file contents if needed (read_file / read_file_range truncate large files; search finds code by content)")
+                .textln("3. Write the relevant changes to the appropriate files (for a few hunks in a large file, apply_patch with a unified diff is easier than rewriting it)")
+                .textln("4. Delete files that the diff removes, if their removal belongs to this commit")
+                .textln("5. Only include changes that belong to THIS commit based on the message and hints")
+                .textln("6. Leave other changes for subsequent commits")
+                .textln("7. Don't create files that aren't in the diff (scratch notes, backups, etc.)")
+                .textln("")
+                .textln("When done, return whether you successfully applied changes,")
+                .textln("and which of the hints you acted on and which didn't apply to this commit.");
+            let result: ExtractResult = ask_agent(git, config, request).await?;
+
+            combined.applied_changes |= result.applied_changes;
+            for hint in result.hints_applied {
+                if !combined.hints_applied.contains(&hint) {
+                    combined.hints_applied.push(hint);
+                }
+            }
+            for hint in result.hints_ignored {
+                if !combined.hints_ignored.contains(&hint) {
+                    combined.hints_ignored.push(hint);
+                }
+            }
+        }
+        combined
+    };
+
+    if commit_spec.hints.is_some()
+        && let Some(feedback) =
+            hint_feedback(extract_result.hints_applied, extract_result.hints_ignored)
+    {
+        for hint in &feedback.applied {
+            hooks.report(&format!("  Hint applied: {hint}"));
+        }
+        for hint in &feedback.ignored {
+            hooks.report(&format!("  Hint ignored: {hint}"));
+        }
+        entries.push(HistoryEntry::HintFeedback(feedback));
+    }
 
     if !extract_result.applied_changes {
-        entries.push(HistoryEntry::Stuck(
-            "LLM could not extract changes".to_string(),
-        ));
+        entries.push(HistoryEntry::Stuck(StuckReason::new(
+            StuckKind::MissingContext,
+            "LLM could not extract changes",
+        )));
+        return Ok(entries);
+    }
+
+    // Staging would mark conflicts resolved with their markers still in the files
+    if let Some(stuck) = conflicts_stuck(git)? {
+        entries.push(stuck);
         return Ok(entries);
     }
 
+    // Give the hooks a chance to veto the commit
+    git.add_all()?;
+    let staged_diff = git.staged_diff()?;
+    match hooks.approve_commit(commit_idx, &staged_diff) {
+        Approval::Approve => {}
+        Approval::Reject(reason) => {
+            entries.push(HistoryEntry::Stuck(StuckReason::new(
+                StuckKind::Other,
+                reason,
+            )));
+            return Ok(entries);
+        }
+        Approval::Defer => {
+            git.discard_changes()?;
+            hooks.report("  Deferred; changes left for later commits");
+            entries.push(HistoryEntry::Skipped(
+                "deferred by approve_commit".to_string(),
+            ));
+            entries.push(HistoryEntry::Complete);
+            return Ok(entries);
+        }
+    }
+
     // Create the commit
-    let hash = git.commit(&commit_spec.message)?;
+    if config.distinct_commit_times {
+        wait_past_head_time(git).await?;
+    }
+    let hash = git.commit(&commit_message(git, commit_spec)?)?;
+    let hash = run_post_commit(git, config, hooks)?.unwrap_or(hash);
     entries.push(HistoryEntry::CommitCreated(hash));
+    save_progress(&entries)?;
     hooks.report("  Created commit");
 
     // Enter the verify/fix loop
+    verify_commit(
+        d,
+        git,
+        spec,
+        commit_spec,
+        &hints,
+        entries,
+        save_progress,
+        config,
+        hooks,
+    )
+    .await
+}
+
+/// Build and test the commit just created, asking the LLM to fix failures.
+///
+/// Each fix is a fixup commit on top. Returns `entries` with the fixups
+/// and a final `Complete` appended, or a `Stuck` entry if the fixes ran out.
+#[allow(clippy::too_many_arguments)]
+async fn verify_commit<R, H>(
+    d: &Determinishtic<R>,
+    git: &Git,
+    spec: &HistorySpec,
+    commit_spec: &CommitSpec,
+    hints: &str,
+    mut entries: Vec<HistoryEntry>,
+    save_progress: &(dyn Fn(&[HistoryEntry]) -> Result<(), Error> + Sync),
+    config: &ExecuteConfig,
+    hooks: &H,
+) -> Result<Vec<HistoryEntry>, Error>
+where
+    R: Role + HasPeer<Agent>,
+    H: ExecuteHooks,
+{
     loop {
-        // Run build if configured
-        if let Some(build_cmd) = &config.build_command {
+        // Run build if configured, or if this commit insists
+        let build_command = config.build_command.as_ref().or(config
+            .skipped_build_command
+            .as_ref()
+            .filter(|_| commit_spec.force_build));
+        if let Some(build_cmd) = build_command {
             hooks.report("  Building...");
-            let build_result = run_command(git.root(), build_cmd, hooks)?;
+            let build_result = run_check(git, build_cmd, config, hooks)?;
 
             if !build_result.success {
                 hooks.report("  Build failed, consulting LLM...");
@@ -486,6 +1960,8 @@ where
                     hints,
                     &build_result,
                     &mut entries,
+                    save_progress,
+                    config,
                     hooks,
                 )
                 .await?
@@ -501,7 +1977,7 @@ where
         // Run tests if configured
         if let Some(test_cmd) = &config.test_command {
             hooks.report("  Testing...");
-            let test_result = run_command(git.root(), test_cmd, hooks)?;
+            let test_result = run_check(git, test_cmd, config, hooks)?;
 
             if !test_result.success {
                 hooks.report("  Tests failed, consulting LLM...");
@@ -513,6 +1989,8 @@ where
                     hints,
                     &test_result,
                     &mut entries,
+                    save_progress,
+                    config,
                     hooks,
                 )
                 .await?
@@ -526,13 +2004,96 @@ where
         }
 
         // Both build and test passed (or were skipped)
+        let stray = stray_files(git, spec)?;
+        if !stray.is_empty() {
+            let list = stray.join(", ");
+            if config.stuck_on_stray_files {
+                entries.push(HistoryEntry::Stuck(StuckReason::new(
+                    StuckKind::Other,
+                    format!(
+                        "commit added files that {} doesn't have: {list}",
+                        spec.target()
+                    ),
+                )));
+                return Ok(entries);
+            }
+            hooks.report(&format!(
+                "  ⚠ Files not in {} (likely created by mistake): {list}",
+                spec.target()
+            ));
+        }
         entries.push(HistoryEntry::Complete);
         return Ok(entries);
     }
 }
 
-/// Try to fix a build/test failure using the LLM.
+/// Run the post-commit command, amending whatever it changed into HEAD.
+///
+/// Returns the amended commit's hash, or `None` if HEAD is unchanged. If
+/// the command fails, its changes are discarded and the commit is kept as
+/// it was; the build and tests that follow will show what's wrong with it.
+fn run_post_commit<H: ExecuteHooks>(
+    git: &Git,
+    config: &ExecuteConfig,
+    hooks: &H,
+) -> Result<Option<String>, Error> {
+    let Some(command) = &config.post_commit_command else {
+        return Ok(None);
+    };
+    hooks.report(&format!("  Running {command}..."));
+    let result = run_command(git.root(), command, config.output_lines, hooks)?;
+    if !result.success {
+        hooks.report("  ⚠ Post-commit command failed; keeping the commit as it was");
+        git.discard_changes()?;
+        git.clean_untracked()?;
+        return Ok(None);
+    }
+    if !git.has_changes()? {
+        return Ok(None);
+    }
+    let hash = git.amend_all()?;
+    hooks.report("  Amended the post-commit command's changes into the commit");
+    Ok(Some(hash))
+}
+
+/// Run the on-commit command for a commit that just finished or got stuck.
+///
+/// The command is only told what happened, so if it fails that is reported
+/// and the run carries on.
+fn run_on_commit<H: ExecuteHooks>(
+    git: &Git,
+    spec: &HistorySpec,
+    commit_idx: usize,
+    status: CommitStatus,
+    config: &ExecuteConfig,
+    hooks: &H,
+) {
+    let Some(command) = &config.on_commit_command else {
+        return;
+    };
+    let commit = &spec.commits[commit_idx];
+    let index = (commit_idx + 1).to_string();
+    let status = match status {
+        CommitStatus::NeedsReview => "needs_review",
+        CommitStatus::Stuck => "stuck",
+        _ => "complete",
+    };
+    let env = [
+        ("RETCON_COMMIT_INDEX", index.as_str()),
+        ("RETCON_COMMIT_SUBJECT", commit.subject()),
+        ("RETCON_STATUS", status),
+        ("RETCON_HASH", commit.last_commit().unwrap_or("")),
+    ];
+    match run_command_with_env(git.root(), command, &env, config.output_lines, hooks) {
+        Ok(result) if result.success => {}
+        Ok(_) => hooks.report("  ⚠ On-commit command failed"),
+        Err(e) => hooks.report(&format!("  ⚠ On-commit command failed: {e}")),
+    }
+}
+
+/// Try to fix a build/test failure using the LLM.
 /// Returns true if progress was made, false if stuck.
+#[allow(clippy::too_many_arguments)]
 async fn try_fix<R, H>(
     d: &Determinishtic<R>,
     git: &Git,
@@ -541,33 +2102,46 @@ async fn try_fix<R, H>(
     hints: &str,
     failure: &CommandResult,
     entries: &mut Vec<HistoryEntry>,
+    save_progress: &(dyn Fn(&[HistoryEntry]) -> Result<(), Error> + Sync),
+    config: &ExecuteConfig,
     hooks: &H,
 ) -> Result<bool, Error>
 where
     R: Role + HasPeer<Agent>,
     H: ExecuteHooks,
 {
+    if config.strict_no_wip {
+        entries.push(HistoryEntry::Stuck(StuckReason::new(
+            StuckKind::BuildUnfixable,
+            format!(
+                "Build or tests failed on the initial extraction (fix commits are disabled by --strict-no-wip):\n{}",
+                output_for_prompt(&failure.output)
+            ),
+        )));
+        return Ok(false);
+    }
+
     // Get fresh diff stat - maybe we need to pull more from source
-    let fresh_diff_stat = git.diff_stat(&spec.cleaned, &spec.source)?;
+    let fresh_diff_stat = git.diff_stat("HEAD", spec.target())?;
 
     // Ask LLM if it can make progress
-    let assess_result: AssessResult = d
-        .think()
+    let request = tools::with_file_tools(d.think(), git, config.max_read_file_bytes)
         .textln("# Task: Fix build/test failure or report stuck")
         .textln("")
         .textln("The build or tests failed after applying changes. You need to either fix it or report that you're stuck.")
+        .textln(&format!("Work in the repository at: {}", git.root().display()))
         .textln("")
         .textln("## Command output:")
         .textln("```")
-        .text(&failure.output)
+        .text(&output_for_prompt(&failure.output))
         .textln("```")
         .textln("")
-        .textln(&format!("## Remaining files changed (HEAD..{}):", spec.source))
+        .textln(&format!("## Remaining files changed (HEAD..{}):", spec.target()))
         .textln("```")
         .text(&fresh_diff_stat)
         .textln("```")
         .textln("")
-        .textln(&format!("To see the full diff, run: git diff HEAD {}", spec.source))
+        .textln(&format!("To see the full diff, run: git diff HEAD {}", spec.target()))
         .textln("")
         .textln("## Original commit:")
         .textln(&format!("Message: {}", commit_spec.message))
@@ -580,53 +2154,99 @@ where
         .textln("4. If you're stuck (circular dependency, missing context, etc): report why")
         .textln("")
         .textln("Return can_progress=true if you applied fixes, false if stuck.")
-        .await
-        .map_err(|e| Error::Agent {
-            message: e.to_string(),
-        })?;
+        .textln("If stuck, also give stuck_kind, the category that best fits the reason.");
+    let assess_result: AssessResult = ask_agent(git, config, request).await?;
 
     if !assess_result.can_progress {
         // LLM is stuck
         let reason = assess_result
             .stuck_reason
             .unwrap_or_else(|| "Unknown reason".to_string());
-        entries.push(HistoryEntry::Stuck(reason));
+        let kind = assess_result.stuck_kind.unwrap_or(StuckKind::Other);
+        entries.push(HistoryEntry::Stuck(StuckReason::new(kind, reason)));
         return Ok(false);
     }
 
-    // LLM made fixes, create a fixup commit targeting the original
-    let target_hash = entries
-        .iter()
-        .find_map(|e| match e {
-            HistoryEntry::CommitCreated(h) => Some(h.as_str()),
-            _ => None,
+    // LLM made fixes, create a fixup commit targeting the original, so that
+    // its subject is `fixup! <original subject>` even when resuming a commit
+    // whose first attempt was made by an earlier run
+    let target_hash = commit_spec
+        .first_commit()
+        .or_else(|| {
+            entries.iter().find_map(|e| match e {
+                HistoryEntry::CommitCreated(h) => Some(h.as_str()),
+                _ => None,
+            })
         })
         .unwrap_or("HEAD");
+    if let Some(stuck) = conflicts_stuck(git)? {
+        entries.push(stuck);
+        return Ok(false);
+    }
+    if config.distinct_commit_times {
+        wait_past_head_time(git).await?;
+    }
     let hash = git.commit_fixup(target_hash)?;
+    let hash = run_post_commit(git, config, hooks)?.unwrap_or(hash);
     entries.push(HistoryEntry::CommitCreated(hash));
+    save_progress(entries)?;
     hooks.report("  Created fixup commit");
 
     Ok(true)
 }
 
+/// Paths left conflicted (say, by `apply_patch`) that still have conflict markers.
+///
+/// Editing the markers away resolves a path, even though the index lists it
+/// as unmerged until the next `add -A`.
+fn unresolved_conflicts(git: &Git) -> Result<Vec<String>, Error> {
+    let has_markers = |path: &String| {
+        std::fs::read_to_string(git.root().join(path)).is_ok_and(|content| {
+            content
+                .lines()
+                .any(|line| line.starts_with("<<<<<<<") || line.starts_with(">>>>>>>"))
+        })
+    };
+    Ok(git
+        .unmerged_paths()?
+        .into_iter()
+        .filter(has_markers)
+        .collect())
+}
+
+/// A stuck entry for unresolved conflicts, if there are any.
+fn conflicts_stuck(git: &Git) -> Result<Option<HistoryEntry>, Error> {
+    let paths = unresolved_conflicts(git)?;
+    if paths.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(HistoryEntry::Stuck(StuckReason::new(
+        StuckKind::Other,
+        format!("conflict markers left in {}", paths.join(", ")),
+    ))))
+}
+
 /// Finalize any remaining changes that weren't captured by the specified commits.
 ///
 /// This ensures the invariant: cleaned branch must match source branch exactly.
+/// Returns `true` if a catchall commit was needed, which calls for manual review.
 async fn finalize_remaining_changes<R, H>(
     d: &Determinishtic<R>,
     git: &Git,
     spec: &HistorySpec,
+    plan: Option<&ExtractionPlan>,
+    config: &ExecuteConfig,
     hooks: &H,
-) -> Result<(), Error>
+) -> Result<bool, Error>
 where
     R: Role + HasPeer<Agent>,
     H: ExecuteHooks,
 {
     // Check if there's any remaining diff
-    let diff_stat = git.diff_stat(&spec.cleaned, &spec.source)?;
-    if diff_stat.trim().is_empty() {
-        return Ok(());
-    }
+    let Some(diff_stat) = remaining_for_finalize(git, spec, config)? else {
+        report_ignored_whitespace(git, spec, config, hooks)?;
+        return Ok(false);
+    };
 
     hooks.report("\nRemaining changes detected - creating fixup commits...");
 
@@ -643,206 +2263,1066 @@ where
     let commit_hashes: Vec<Option<String>> = spec
         .commits
         .iter()
-        .map(|c| {
-            c.history.iter().find_map(|e| match e {
-                HistoryEntry::CommitCreated(h) => Some(h.clone()),
-                _ => None,
-            })
-        })
+        .map(|c| c.first_commit().map(String::from))
         .collect();
 
-    // We need the root path for the tool closure
-    let repo_root = git.root().to_path_buf();
-    let source = spec.source.clone();
+    let target = spec.target().to_string();
 
-    // Ask LLM to analyze and create fixup commits
-    let _result: CatchallResult = d
-        .think()
-        .textln("# Task: Create fixup commits for remaining changes")
-        .textln("")
-        .textln("The main reconstruction is complete, but some changes were missed.")
-        .textln("Your job is to apply ALL remaining changes, creating fixup commits that")
-        .textln("will be automatically squashed into the right commit during rebase --autosquash.")
-        .textln("")
-        .textln("## Commits that were created:")
-        .textln(&commit_summary)
+    // Keep the fixups in line with the plan that was shown for approval
+    let plan_context = plan
+        .map(|plan| {
+            format!(
+                "\n## Approved plan (follow it):\n{}",
+                render_finalize_plan(spec, plan)
+            )
+        })
+        .unwrap_or_default();
+
+    // Ask LLM to analyze and create fixup commits, retrying on leftovers
+    let mut diff_stat = diff_stat;
+    for attempt in 0..=config.catchall_retries {
+        if attempt > 0 {
+            hooks.report(&format!(
+                "  Changes still remain - asking again ({attempt}/{})",
+                config.catchall_retries
+            ));
+        }
+
+        let mode_changes = mode_changes_context(git, spec)?;
+        let request = tools::with_file_tools(d.think(), git, config.max_read_file_bytes)
+            .textln("# Task: Create fixup commits for remaining changes")
+            .textln("")
+            .textln("The main reconstruction is complete, but some changes were missed.")
+            .textln("Your job is to apply ALL remaining changes, creating fixup commits that")
+            .textln(
+                "will be automatically squashed into the right commit during rebase --autosquash.",
+            )
+            .textln(&format!(
+                "Work in the repository at: {}",
+                git.root().display()
+            ))
+            .textln("")
+            .textln("## Commits that were created:")
+            .textln(&commit_summary)
+            .textln("")
+            .textln(&format!(
+                "## Remaining files changed (HEAD..{}):",
+                spec.target()
+            ))
+            .textln("```")
+            .text(&diff_stat)
+            .textln("```")
+            .textln("")
+            .textln(&format!(
+                "To see the full diff, run: git diff HEAD {}",
+                spec.target()
+            ))
+            .text(&mode_changes)
+            .text(&plan_context)
+            .textln("")
+            .textln("## Instructions:")
+            .textln("1. Run the git diff command above to see all remaining changes")
+            .textln("2. Analyze which original commit each change logically belongs to")
+            .textln("3. Group changes by target commit")
+            .textln("4. For each group, write the changes to the appropriate files, deleting files the diff removes")
+            .textln("5. After each group, call create_fixup_commit with the target commit number")
+            .textln("6. Apply ALL changes from the diff - don't leave anything out")
+            .define_tool(
+                "create_fixup_commit",
+                "Create a fixup commit for changes that belong to a specific original commit",
+                {
+                    let git = git.clone();
+                    let commit_hashes = commit_hashes.clone();
+                    let distinct_commit_times = config.distinct_commit_times;
+                    async move |input: CreateWipCommitInput, _cx| {
+                        let target_idx = input.target_commit_number.saturating_sub(1);
+                        let target_hash = commit_hashes.get(target_idx).and_then(|h| h.as_deref());
+
+                        let Some(target_hash) = target_hash else {
+                            return Ok(CreateWipCommitOutput {
+                                wip_message: None,
+                                error: Some(format!(
+                                    "No commit hash found for commit {}",
+                                    input.target_commit_number
+                                )),
+                            });
+                        };
+
+                        // Stage and commit
+                        if distinct_commit_times {
+                            let _ = wait_past_head_time(&git).await;
+                        }
+                        if let Err(e) = git.commit_fixup(target_hash) {
+                            return Ok(CreateWipCommitOutput {
+                                wip_message: None,
+                                error: Some(format!("Failed to create commit: {e}")),
+                            });
+                        }
+
+                        Ok(CreateWipCommitOutput {
+                            wip_message: Some(format!("fixup! {target_hash}")),
+                            error: None,
+                        })
+                    }
+                },
+                sacp::tool_fn_mut!(),
+            );
+        let _result: CatchallResult = ask_agent(git, config, request).await?;
+
+        // Check if there's still a diff after LLM's attempt
+        match remaining_for_finalize(git, spec, config)? {
+            None => {
+                report_ignored_whitespace(git, spec, config, hooks)?;
+                return Ok(false);
+            }
+            Some(remaining) => diff_stat = remaining,
+        }
+    }
+
+    // Still have remaining changes - create a final catchall commit
+    // Never bury unresolved conflicts in a commit nobody looks at closely
+    let unmerged = git.unmerged_paths()?;
+    if !unmerged.is_empty() {
+        return Err(Error::UnmergedPaths {
+            paths: unmerged.join(", "),
+        });
+    }
+
+    // Make the tree match the target exactly, deletions included
+    let stray = stray_files(git, spec)?;
+    git.restore_tree(&target)?;
+    if config.distinct_commit_times {
+        wait_past_head_time(git).await?;
+    }
+    let last = spec.commits.last().map_or("", |c| c.subject());
+    let message = config
+        .catchall_message
+        .as_deref()
+        .unwrap_or(DEFAULT_CATCHALL_MESSAGE)
+        .replace("{last}", last);
+    let _hash = git.commit(&message)?;
+
+    hooks.report("  Created: remaining uncategorized changes (review manually)");
+    hooks.report("\n⚠ Warning: Some changes could not be automatically categorized.");
+    hooks.report("  Review the final commit and distribute its contents using");
+    hooks.report("  git rebase -i --autosquash");
+    if !stray.is_empty() {
+        hooks.report(&format!(
+            "  It deletes files no commit should have added: {}",
+            stray.join(", ")
+        ));
+    }
+
+    Ok(true)
+}
+
+// =============================================================================
+// Reverse Reconstruction
+// =============================================================================
+
+/// Reconstruct the pending commits newest first, by peeling them off the target.
+///
+/// Starting from the target's content on a detached HEAD, the LLM removes one
+/// commit's changes at a time and each intermediate state is snapshotted.
+/// The snapshots are then replayed oldest first onto the cleaned branch, so
+/// the resulting history is in the usual forward order.
+///
+/// Peeling always restarts from the target, so a stuck commit costs a full
+/// re-peel once resolved. Returns `true` if a commit got stuck.
+async fn reconstruct_reverse<R, H>(
+    d: &Determinishtic<R>,
+    git: &Git,
+    spec: &mut HistorySpec,
+    spec_path: Option<&Path>,
+    config: &ExecuteConfig,
+    hooks: &H,
+) -> Result<bool, Error>
+where
+    R: Role + HasPeer<Agent>,
+    H: ExecuteHooks,
+{
+    let Some(first) = spec.next_pending_commit() else {
+        return Ok(false);
+    };
+    // Where the reconstructed commits go: the cleaned branch, or the
+    // detached scratch worktree HEAD in script mode
+    let resume = if config.emit_script.is_some() {
+        git.head_short()?
+    } else {
+        spec.cleaned.clone()
+    };
+
+    git.checkout_detached(spec.target())?;
+    let peeled = peel_commits(d, git, spec, spec_path, first, &resume, config, hooks).await;
+
+    // However the peeling ended, leave the branch checked out with nothing
+    // of the peeling left in the working tree
+    let cleanup = git
+        .discard_changes()
+        .and_then(|()| git.clean_untracked())
+        .and_then(|()| git.checkout(&resume));
+    let Some(snapshots) = peeled? else {
+        cleanup?;
+        return Ok(true);
+    };
+    cleanup?;
+
+    // Replay the snapshots oldest first
+    hooks.report("\nReplaying peeled commits in order...");
+    for (idx, snapshot) in snapshots.iter().enumerate().skip(first) {
+        git.restore_tree(snapshot)?;
+        let message = commit_message(git, &spec.commits[idx])?;
+        if !git.staged_diff()?.trim().is_empty() {
+            if config.distinct_commit_times {
+                wait_past_head_time(git).await?;
+            }
+            let hash = git.commit(&message)?;
+            hooks.report(&format!(
+                "  {hash} {}",
+                message.lines().next().unwrap_or("")
+            ));
+            spec.commits[idx]
+                .history
+                .push(HistoryEntry::CommitCreated(hash));
+        }
+        spec.commits[idx].history.push(HistoryEntry::Complete);
+        if let Some(dir) = &config.patch_dir {
+            write_commit_patch(git, spec, idx, dir)?;
+        }
+        hooks.plan_update(idx, CommitStatus::Completed);
+    }
+    if let Some(p) = spec_path {
+        save_spec(p, spec)?;
+    }
+
+    Ok(false)
+}
+
+/// Peel the pending commits after `first` off the target, newest first,
+/// on the detached HEAD [`reconstruct_reverse`] set up.
+///
+/// Returns the snapshots, where `snapshots[i]` is the tree as it should be
+/// right after commit `i`, or `None` if a commit got stuck. Commits peeled
+/// in an earlier run reuse their snapshot, as long as every commit after
+/// them did too, since each peel builds on the one before it.
+#[allow(clippy::too_many_arguments)]
+async fn peel_commits<R, H>(
+    d: &Determinishtic<R>,
+    git: &Git,
+    spec: &mut HistorySpec,
+    spec_path: Option<&Path>,
+    first: usize,
+    resume: &str,
+    config: &ExecuteConfig,
+    hooks: &H,
+) -> Result<Option<Vec<String>>, Error>
+where
+    R: Role + HasPeer<Agent>,
+    H: ExecuteHooks,
+{
+    let total = spec.commits.len();
+    let mut snapshots = vec![String::new(); total];
+    snapshots[total - 1] = spec.target().to_string();
+
+    let mut reusing = true;
+    for idx in (first + 1..total).rev() {
+        let commit_spec = &spec.commits[idx];
+        let subject = commit_spec.subject();
+        hooks.plan_update(idx, CommitStatus::InProgress);
+        hooks.report(&format!(
+            "\nPeeling commit {}/{}: {}",
+            idx + 1,
+            total,
+            subject
+        ));
+
+        if reusing
+            && let Some(snapshot) = commit_spec.peeled_snapshot()
+            && git.rev_parse(snapshot).is_ok()
+        {
+            git.checkout_detached(snapshot)?;
+            snapshots[idx - 1] = snapshot.to_string();
+            hooks.report("  ✓ Already peeled; reusing its snapshot");
+            continue;
+        }
+        reusing = false;
+
+        let hints = hints_for_prompt(commit_spec);
+        let resolution_context = commit_spec
+            .resolution_note()
+            .map(|note| resolution_context(note, config))
+            .unwrap_or_default();
+        let review_context = review_context(commit_spec);
+        let context_files = context_files_context(git, commit_spec, config.max_read_file_bytes);
+        let earlier: String = spec.commits[first..idx]
+            .iter()
+            .enumerate()
+            .map(|(i, c)| format!("{}. {}", first + i + 1, c.message))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let request = tools::with_file_tools(
+            d.think(),
+            git,
+            config.max_read_file_bytes,
+        )
+        .textln("# Task: Remove one commit's changes")
         .textln("")
+        .textln(
+            "You are reconstructing clean git history by working backward from the final state.",
+        )
+        .textln("The working tree contains the combined result of the commits listed below.")
+        .textln("Your job is to remove ONLY the changes belonging to the commit being peeled,")
+        .textln("leaving the tree as it would look just before that commit was made.")
         .textln(&format!(
-            "## Remaining files changed (HEAD..{}):",
-            spec.source
+            "Work in the repository at: {}",
+            git.root().display()
         ))
-        .textln("```")
-        .text(&diff_stat)
-        .textln("```")
+        .text(&resolution_context)
+        .text(&review_context)
+        .textln("")
+        .textln("## Commit to peel:")
+        .textln(&format!("Message: {}", commit_spec.message))
+        .textln(&format!("Hints: {hints}"))
+        .textln("")
+        .textln("## Earlier commits (keep their changes):")
+        .textln(&earlier)
         .textln("")
         .textln(&format!(
-            "To see the full diff, run: git diff HEAD {}",
-            spec.source
+            "To see all changes still in the tree, run: git diff {resume} HEAD"
         ))
+        .text(&context_files)
         .textln("")
         .textln("## Instructions:")
-        .textln("1. Run the git diff command above to see all remaining changes")
-        .textln("2. Analyze which original commit each change logically belongs to")
-        .textln("3. Group changes by target commit")
-        .textln("4. For each group, write the changes to the appropriate files")
-        .textln("5. After each group, call create_fixup_commit with the target commit number")
-        .textln("6. Apply ALL changes from the diff - don't leave anything out")
-        .define_tool(
-            "create_fixup_commit",
-            "Create a fixup commit for changes that belong to a specific original commit",
+        .textln("1. Run the git diff command above to see the available changes")
+        .textln("2. Revert the changes that belong to the commit being peeled")
+        .textln("3. Keep everything the earlier commits need, so their build still passes")
+        .textln("4. Do not commit; retcon snapshots the result")
+        .textln("")
+        .textln("If you cannot separate this commit's changes, report why in stuck_reason,")
+        .textln("and give stuck_kind, the category that best fits the reason.");
+        let peel_result: PeelResult = ask_agent(git, config, request).await?;
+
+        let stuck_reason = match peel_result.stuck_reason {
+            Some(reason) => Some(StuckReason::new(
+                peel_result.stuck_kind.unwrap_or(StuckKind::Other),
+                reason,
+            )),
+            None => {
+                git.commit_snapshot(&format!("retcon: before {subject}"))
+                    .map(|snapshot| snapshots[idx - 1] = snapshot)?;
+                verify_state(git, config, hooks)?.map(|failure| {
+                    StuckReason::new(
+                        StuckKind::BuildUnfixable,
+                        format!(
+                            "Build or tests fail once this commit's changes are removed:\n{}",
+                            output_for_prompt(&failure.output)
+                        ),
+                    )
+                })
+            }
+        };
+
+        let history = &mut spec.commits[idx].history;
+        match stuck_reason {
+            Some(reason) => history.push(HistoryEntry::Stuck(reason)),
+            None => history.push(HistoryEntry::Peeled(snapshots[idx - 1].clone())),
+        }
+        if let Some(p) = spec_path {
+            save_spec(p, spec)?;
+        }
+        if spec.commits[idx].is_stuck() {
+            hooks.plan_update(idx, CommitStatus::Stuck);
+            return Ok(None);
+        }
+        hooks.report("  ✓ Peeled");
+    }
+    Ok(Some(snapshots))
+}
+
+/// Run the configured build and test commands, returning the first failure.
+fn verify_state<H: ExecuteHooks>(
+    git: &Git,
+    config: &ExecuteConfig,
+    hooks: &H,
+) -> Result<Option<CommandResult>, Error> {
+    for command in [&config.build_command, &config.test_command]
+        .into_iter()
+        .flatten()
+    {
+        let result = run_check(git, command, config, hooks)?;
+        if !result.success {
+            return Ok(Some(result));
+        }
+    }
+    Ok(None)
+}
+
+// =============================================================================
+// Tool Input/Output Types
+// =============================================================================
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct ExtractResult {
+    /// Whether changes were successfully applied
+    applied_changes: bool,
+    /// The hints you acted on, each briefly quoted or paraphrased
+    #[serde(default)]
+    hints_applied: Vec<String>,
+    /// The hints you found irrelevant to this commit, each briefly quoted or paraphrased
+    #[serde(default)]
+    hints_ignored: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct PreflightResult {
+    /// Always true; confirms the agent handled the request
+    ok: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct PeelResult {
+    /// If the commit's changes could not be removed, explanation of why
+    stuck_reason: Option<String>,
+    /// If the commit's changes could not be removed, the category of the reason
+    stuck_kind: Option<StuckKind>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct AssessResult {
+    /// Whether the LLM can make progress on fixing the build
+    can_progress: bool,
+    /// If stuck, explanation of why
+    stuck_reason: Option<String>,
+    /// If stuck, the category of the reason
+    stuck_kind: Option<StuckKind>,
+}
+
+#[derive(Debug)]
+struct CommandResult {
+    success: bool,
+    /// The complete output, stdout followed by stderr
+    output: String,
+}
+
+/// Lines of build or test output kept from the start and end for the LLM.
+const PROMPT_OUTPUT_HEAD_LINES: usize = 100;
+const PROMPT_OUTPUT_TAIL_LINES: usize = 100;
+
+/// Cut build or test output down for an LLM prompt.
+///
+/// Compilers report the first errors at the top and a summary at the end,
+/// so both are kept and the middle is dropped.
+fn output_for_prompt(output: &str) -> String {
+    let lines: Vec<&str> = output.lines().collect();
+    if lines.len() <= PROMPT_OUTPUT_HEAD_LINES + PROMPT_OUTPUT_TAIL_LINES {
+        return output.to_string();
+    }
+    let hidden = lines.len() - PROMPT_OUTPUT_HEAD_LINES - PROMPT_OUTPUT_TAIL_LINES;
+    let mut result = lines[..PROMPT_OUTPUT_HEAD_LINES].join("\n");
+    result.push_str(&format!("\n[... {hidden} lines omitted ...]\n"));
+    result.push_str(&lines[lines.len() - PROMPT_OUTPUT_TAIL_LINES..].join("\n"));
+    result
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct CreateWipCommitInput {
+    /// Which commit number (1-indexed) this change belongs to
+    target_commit_number: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct CreateWipCommitOutput {
+    /// The fixup commit message if successful
+    wip_message: Option<String>,
+    /// Error message if the commit failed
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct CatchallResult {
+    /// Number of fixup commits created
+    commits_created: usize,
+}
+
+// =============================================================================
+// Helper Functions
+// =============================================================================
+
+/// Compute the commit the cleaned branch starts from.
+///
+/// Errors if `source` doesn't descend from the base, since the diff
+/// `base..source` would then not describe the changes to reconstruct.
+/// (With `base = "remote"` the target is rebased instead; see
+/// [`rebase_target`].)
+fn resolve_base(git: &Git, spec: &HistorySpec) -> Result<String, Error> {
+    match spec.base {
+        Base::Orphan => return Ok(git.empty_root_commit()?),
+        // Keep building on the tip the cleaned branch was started from, even
+        // if `remote` has moved on since
+        Base::Remote => {
+            if let Some(resolved) = &spec.base_resolved
+                && git.ref_exists(&spec.cleaned)
             {
-                let repo = repo_root.clone();
-                let commit_hashes = commit_hashes.clone();
-                async move |input: CreateWipCommitInput, _cx| {
-                    let target_idx = input.target_commit_number.saturating_sub(1);
-                    let target_hash = commit_hashes
-                        .get(target_idx)
-                        .and_then(|h| h.as_deref());
-
-                    let Some(target_hash) = target_hash else {
-                        return Ok(CreateWipCommitOutput {
-                            wip_message: None,
-                            error: Some(format!(
-                                "No commit hash found for commit {}",
-                                input.target_commit_number
-                            )),
-                        });
-                    };
-
-                    // Stage and commit
-                    let status = StdCommand::new("git")
-                        .args(["add", "-A"])
-                        .current_dir(&repo)
-                        .status();
-
-                    if status.map(|s| !s.success()).unwrap_or(true) {
-                        return Ok(CreateWipCommitOutput {
-                            wip_message: None,
-                            error: Some("Failed to stage changes".to_string()),
-                        });
-                    }
+                return Ok(resolved.clone());
+            }
+            // Not an ancestor of `source` at all, unless it was already rebased
+            return Ok(git.rev_parse(&spec.remote)?);
+        }
+        _ => {}
+    }
+    let base = match spec.base {
+        Base::SmallestDiff => smallest_diff_merge_base(git, spec)?,
+        _ => git.merge_base(&spec.source, &spec.remote)?,
+    };
+    // A merge-base is always an ancestor of `source`; the misconfiguration
+    // to catch is `source` being an ancestor of `remote` (the two swapped,
+    // say), which leaves the branch with no changes of its own. Once the
+    // cleaned branch exists the run is under way, and `remote` may have
+    // merged the source since.
+    if !git.ref_exists(&spec.cleaned) && git.is_ancestor(&spec.source, &spec.remote)? {
+        return Err(Error::SourceInRemote {
+            source_ref: spec.source.clone(),
+            remote: spec.remote.clone(),
+        });
+    }
+    Ok(base)
+}
 
-                    let status = StdCommand::new("git")
-                        .args(["commit", "--fixup", target_hash])
-                        .current_dir(&repo)
-                        .status();
+/// The merge-base of `source` and `remote` with the smallest diff to the target.
+fn smallest_diff_merge_base(git: &Git, spec: &HistorySpec) -> Result<String, Error> {
+    let mut best: Option<(usize, String)> = None;
+    for base in git.merge_bases(&spec.source, &spec.remote)? {
+        let size = git.diff(&base, spec.target())?.len();
+        if best.as_ref().is_none_or(|(best_size, _)| size < *best_size) {
+            best = Some((size, base));
+        }
+    }
+    match best {
+        Some((_, base)) => Ok(base),
+        // No common history; let `merge_base` report it
+        None => Ok(git.merge_base(&spec.source, &spec.remote)?),
+    }
+}
 
-                    if status.map(|s| !s.success()).unwrap_or(true) {
-                        return Ok(CreateWipCommitOutput {
-                            wip_message: None,
-                            error: Some("Failed to create commit".to_string()),
-                        });
-                    }
+/// Point the spec at what the cleaned branch has to end up as, when that
+/// isn't just the target as the spec names it.
+fn override_target<H: ExecuteHooks>(
+    git: &Git,
+    spec: &mut HistorySpec,
+    base: &str,
+    config: &ExecuteConfig,
+    hooks: &H,
+) -> Result<(), Error> {
+    if spec.base == Base::Remote {
+        rebase_target(git, spec, base, hooks)?;
+    }
+    if let Some(path) = &config.file {
+        restrict_to_file(git, spec, base, path, hooks)?;
+    }
+    Ok(())
+}
 
-                    Ok(CreateWipCommitOutput {
-                        wip_message: Some(format!("fixup! {target_hash}")),
-                        error: None,
-                    })
-                }
-            },
-            sacp::tool_fn_mut!(),
-        )
-        .await
-        .map_err(|e| Error::Agent {
-            message: e.to_string(),
-        })?;
+/// Point the spec at the target's changes merged onto `remote`, so that
+/// reconstructing from the remote tip effectively rebases the branch.
+///
+/// Fails if the changes conflict with `remote`, since there is then no
+/// well-defined result to reconstruct.
+fn rebase_target<H: ExecuteHooks>(
+    git: &Git,
+    spec: &mut HistorySpec,
+    base: &str,
+    hooks: &H,
+) -> Result<(), Error> {
+    let (tree, conflicts) = git.merge_tree(base, spec.target())?;
+    if !conflicts.is_empty() {
+        return Err(Error::RemoteBaseConflicts {
+            remote: spec.remote.clone(),
+            paths: conflicts.join(", "),
+        });
+    }
+    let target = git.commit_tree(
+        &tree,
+        base,
+        &format!("{} rebased onto {}", spec.target(), spec.remote),
+    )?;
+    hooks.report(&format!(
+        "Reconstructing {} rebased onto {}; its target is {}",
+        spec.target(),
+        spec.remote,
+        &target[..8.min(target.len())]
+    ));
+    spec.target_override = Some(target);
+    Ok(())
+}
+
+/// Narrow the run to one file by pointing the spec at a target that only
+/// differs from `base` in that file.
+///
+/// The override lives only in memory, so the spec file keeps its real target.
+fn restrict_to_file<H: ExecuteHooks>(
+    git: &Git,
+    spec: &mut HistorySpec,
+    base: &str,
+    path: &str,
+    hooks: &H,
+) -> Result<(), Error> {
+    let target = git.commit_with_file(base, spec.target(), path)?;
+    hooks.report(&format!(
+        "Reconstructing only {path}; its target is {}",
+        &target[..8.min(target.len())]
+    ));
+    spec.target_override = Some(target);
+    Ok(())
+}
+
+/// Warn when `source` and `remote` have several merge-bases and the spec
+/// takes whichever one git picks.
+fn warn_multiple_merge_bases<H: ExecuteHooks>(
+    git: &Git,
+    spec: &HistorySpec,
+    base: &str,
+    hooks: &H,
+) -> Result<(), Error> {
+    if spec.base != Base::MergeBase {
+        return Ok(());
+    }
+    let bases = git.merge_bases(&spec.source, &spec.remote)?;
+    if bases.len() > 1 {
+        let others: Vec<&str> = bases
+            .iter()
+            .filter(|b| *b != base)
+            .map(|b| &b[..8.min(b.len())])
+            .collect();
+        hooks.report(&format!(
+            "Warning: {} and {} have {} merge-bases; using {} (not {}). \
+             The diff may include changes that aren't yours; \
+             set base = \"smallest_diff\" to pick the closest one.",
+            spec.source,
+            spec.remote,
+            bases.len(),
+            &base[..8.min(base.len())],
+            others.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Fetch the spec's `remote` so the merge-base is computed against current upstream.
+///
+/// `remote` is a remote-tracking ref like `origin/main`; anything that doesn't
+/// start with a configured remote's name is left alone.
+fn fetch_remote<H: ExecuteHooks>(git: &Git, spec: &HistorySpec, hooks: &H) -> Result<(), Error> {
+    let Some((remote, branch)) = spec.remote.split_once('/') else {
+        hooks.report(&format!(
+            "{} is not a remote-tracking ref; not fetching",
+            spec.remote
+        ));
+        return Ok(());
+    };
+    if !git.remotes()?.iter().any(|r| r == remote) {
+        hooks.report(&format!("No remote named {remote}; not fetching"));
+        return Ok(());
+    }
+    hooks.report(&format!("Fetching {branch} from {remote}..."));
+    git.fetch(remote, Some(branch))?;
+    Ok(())
+}
+
+/// Set up the cleaned branch from merge-base if it doesn't exist.
+fn setup_cleaned_branch<H: ExecuteHooks>(
+    git: &Git,
+    spec: &HistorySpec,
+    base: &str,
+    hooks: &H,
+) -> Result<(), Error> {
+    if git.ref_exists(&spec.cleaned) {
+        reject_cleaned_at_source(git, spec)?;
+        // A branch built on an outdated base can never converge on the source
+        let found = match spec.base {
+            Base::MergeBase => git.merge_base(&spec.cleaned, &spec.source)?,
+            // The cleaned branch may share several merge-bases with the
+            // source too, so just check it was built on this one
+            Base::SmallestDiff if git.is_ancestor(base, &spec.cleaned)? => base.to_string(),
+            Base::SmallestDiff => git.merge_base(&spec.cleaned, &spec.source)?,
+            Base::Orphan => git.root_commit(&spec.cleaned)?,
+            Base::Remote if git.is_ancestor(base, &spec.cleaned)? => base.to_string(),
+            Base::Remote => git.merge_base(&spec.cleaned, &spec.remote)?,
+        };
+        if found != base {
+            return Err(Error::StaleCleanedBranch {
+                branch: spec.cleaned.clone(),
+                found: found[..8.min(found.len())].to_string(),
+                expected: base[..8.min(base.len())].to_string(),
+            });
+        }
+        git.checkout(&spec.cleaned)?;
+        hooks.report(&format!("Checked out existing branch: {}", spec.cleaned));
+    } else if spec.base == Base::Orphan {
+        git.checkout_orphan(&spec.cleaned)?;
+        hooks.report(&format!("Created branch {} with no history", spec.cleaned));
+    } else {
+        git.checkout_new_branch(&spec.cleaned, base)?;
+        let base_short = &base[..8.min(base.len())];
+        hooks.report(&format!(
+            "Created branch {} from merge-base {}",
+            spec.cleaned, base_short
+        ));
+    }
+    Ok(())
+}
+
+/// Refuse to touch an existing cleaned branch that points at the source commit.
+///
+/// `validate` already rejects a spec naming the same branch twice, but two
+/// different spellings (`feature` and `refs/heads/feature`) get past it, and
+/// committing on or resetting such a branch would destroy the source.
+fn reject_cleaned_at_source(git: &Git, spec: &HistorySpec) -> Result<(), Error> {
+    if git.rev_parse(&spec.cleaned)? == git.rev_parse(&spec.source)? {
+        return Err(Error::CleanedIsSource {
+            cleaned: spec.cleaned.clone(),
+            source_ref: spec.source.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// Throw away previous progress so the reconstruction starts over from the base.
+///
+/// Clears every commit's history and resets the cleaned branch (or, in
+/// script mode, removes the scratch worktree).
+fn start_fresh<H: ExecuteHooks>(
+    git: &Git,
+    spec: &mut HistorySpec,
+    config: &ExecuteConfig,
+    hooks: &H,
+) -> Result<(), Error> {
+    for commit in &mut spec.commits {
+        commit.history.clear();
+    }
+
+    if let Some(script_path) = &config.emit_script {
+        ScriptWorkspace::discard(git, script_path)?;
+    } else if git.ref_exists(&spec.cleaned) {
+        reject_cleaned_at_source(git, spec)?;
+        let base = resolve_base(git, spec)?;
+        git.reset_branch(&spec.cleaned, &base)?;
+    }
+
+    hooks.report("Starting fresh: cleared execution history.");
+    Ok(())
+}
+
+/// Wait until the clock has moved past HEAD's commit time, so the next
+/// commit's timestamp is strictly later.
+///
+/// Git timestamps have one-second resolution, so this waits at most about a
+/// second. If HEAD is further in the future than that (clock skew), it
+/// doesn't wait at all.
+async fn wait_past_head_time(git: &Git) -> Result<(), Error> {
+    let head = git.head_commit_time()?;
+    let now = || {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+    };
+    if head.saturating_sub(now().as_secs()) > 1 {
+        return Ok(());
+    }
+    while now().as_secs() <= head {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    Ok(())
+}
 
-    // Check if there's still a diff after LLM's attempt
-    let remaining_diff = git.diff_stat(&spec.cleaned, &spec.source)?;
-    if remaining_diff.trim().is_empty() {
-        return Ok(());
+/// The plan status for a completed commit.
+///
+/// A commit that only built after fixup commits is flagged for review, since
+/// its changes weren't cleanly separated on the first try. So is one that
+/// drew a warning when it completed (`warned`), such as changing files its
+/// `expected_files` doesn't list or adding files the target doesn't have.
+/// Those warnings aren't recorded in the history, so a commit completed by
+/// an earlier run is judged by its fixups alone.
+fn completed_status(commit: &CommitSpec, warned: bool) -> CommitStatus {
+    let created = commit
+        .entries_since_rollback()
+        .iter()
+        .filter(|e| matches!(e, HistoryEntry::CommitCreated(_)))
+        .count();
+    if created > 1 || warned {
+        CommitStatus::NeedsReview
+    } else {
+        CommitStatus::Completed
     }
+}
 
-    // Still have remaining changes - create a final catchall commit
-    // Apply all remaining changes by checking out files from source
-    git.checkout_files(&source, ".")?;
-    let _hash = git.commit("WIP--remaining changes (review manually)")?;
+/// Stuck kinds in triage order: the ones a hint or spec edit usually fixes come first.
+const STUCK_REPORT_ORDER: [Option<StuckKind>; 7] = [
+    Some(StuckKind::MissingContext),
+    Some(StuckKind::CircularDependency),
+    Some(StuckKind::BuildUnfixable),
+    Some(StuckKind::MaxIterations),
+    Some(StuckKind::Interrupted),
+    Some(StuckKind::Other),
+    None,
+];
+
+/// Write every stuck commit in `spec` to `path`, grouped by kind.
+fn write_stuck_report(path: &Path, spec: &HistorySpec) -> Result<(), Error> {
+    let total = spec.commits.len();
+    let stuck: Vec<(usize, &CommitSpec)> = STUCK_REPORT_ORDER
+        .iter()
+        .flat_map(|&kind| {
+            spec.commits
+                .iter()
+                .enumerate()
+                .filter(move |(_, c)| c.is_stuck() && c.stuck_kind() == kind)
+        })
+        .collect();
 
-    hooks.report("  Created: remaining uncategorized changes (review manually)");
-    hooks.report("\n⚠ Warning: Some changes could not be automatically categorized.");
-    hooks.report("  Review the final commit and distribute its contents using");
-    hooks.report("  git rebase -i --autosquash");
+    let report = if path.extension().is_some_and(|ext| ext == "json") {
+        let entries: Vec<serde_json::Value> = stuck
+            .iter()
+            .map(|(idx, commit)| {
+                serde_json::json!({
+                    "commit": idx + 1,
+                    "subject": commit.subject(),
+                    "kind": commit.stuck_kind().map(StuckKind::as_str),
+                    "reason": commit.stuck_reason().unwrap_or_default(),
+                })
+            })
+            .collect();
+        format!(
+            "{:#}\n",
+            serde_json::json!({ "total_commits": total, "stuck": entries })
+        )
+    } else {
+        let mut report = format!(
+            "# Stuck commits in {}\n\n{} of {total} commit(s) are stuck.\n",
+            spec.cleaned,
+            stuck.len()
+        );
+        let mut current = None;
+        for (idx, commit) in &stuck {
+            let kind = commit.stuck_kind();
+            if current != Some(kind) {
+                current = Some(kind);
+                let count = stuck.iter().filter(|(_, c)| c.stuck_kind() == kind).count();
+                let name = kind.map_or("uncategorized", StuckKind::as_str);
+                report.push_str(&format!("\n## {name} ({count})\n"));
+            }
+            report.push_str(&format!(
+                "\n### Commit {}/{total}: {}\n\n{}\n",
+                idx + 1,
+                commit.subject(),
+                commit.stuck_reason().unwrap_or_default().trim()
+            ));
+        }
+        report
+    };
 
-    Ok(())
+    std::fs::write(path, report).map_err(|e| Error::WriteStuckReport {
+        path: path.display().to_string(),
+        source: e,
+    })
 }
 
-// =============================================================================
-// Tool Input/Output Types
-// =============================================================================
-
-#[derive(Debug, Serialize, Deserialize, JsonSchema)]
-struct ExtractResult {
-    /// Whether changes were successfully applied
-    applied_changes: bool,
+/// Undo a stuck commit's attempt before moving on with `continue_on_stuck`:
+/// its commits, its edits and any files it created.
+///
+/// The `RolledBack` entry goes before the `Stuck` entry so the commit still
+/// counts as stuck.
+fn abandon_stuck_commit(git: &Git, commit: &mut CommitSpec) -> Result<(), Error> {
+    if let Some(first) = commit.first_commit() {
+        let parent = git.rev_parse(&format!("{first}^"))?;
+        git.reset_mixed(&parent)?;
+        let at = commit.history.len() - 1;
+        commit.history.insert(at, HistoryEntry::RolledBack(parent));
+    }
+    git.discard_changes()?;
+    git.clean_untracked()?;
+    Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize, JsonSchema)]
-struct AssessResult {
-    /// Whether the LLM can make progress on fixing the build
-    can_progress: bool,
-    /// If stuck, explanation of why
-    stuck_reason: Option<String>,
+/// Abort a `continue_on_stuck` run once too many commits in a row are stuck.
+fn check_consecutive_stuck(count: usize, config: &ExecuteConfig) -> Result<(), Error> {
+    match config.max_consecutive_stuck {
+        Some(max) if count >= max => Err(Error::TooManyStuck { count }),
+        _ => Ok(()),
+    }
 }
 
-#[derive(Debug)]
-struct CommandResult {
-    success: bool,
-    output: String,
-}
+/// Send a think block to the agent, waiting on the rate limit first.
+///
+/// When recording, the agent's result, the changes it made to the working
+/// tree, and any commits its tools made are saved. When replaying, the next
+/// saved request's commits are made again and its changes applied instead,
+/// and the agent isn't asked at all.
+async fn ask_agent<T>(
+    git: &Git,
+    config: &ExecuteConfig,
+    think: impl IntoFuture<Output = Result<T, determinishtic::Error>>,
+) -> Result<T, Error>
+where
+    T: Serialize + serde::de::DeserializeOwned,
+{
+    let recording = &config.recording;
+    let recording_error = |message: String| Error::Recording {
+        path: recording
+            .path()
+            .map(|p| p.display().to_string())
+            .unwrap_or_default(),
+        message,
+    };
+    let kind = short_type_name::<T>();
+
+    if recording.is_replaying() {
+        let entry = recording
+            .next()
+            .ok_or_else(|| recording_error(format!("no recorded result left for {kind}")))?;
+        if entry.kind != kind {
+            return Err(recording_error(format!(
+                "expected a recorded {kind}, found {}; the run has diverged from the recording",
+                entry.kind
+            )));
+        }
+        if !entry.commits.is_empty() {
+            // Build the commits on HEAD without touching the working tree,
+            // which the patch below brings to where the agent left it
+            let mut head = git.rev_parse("HEAD")?;
+            for commit in &entry.commits {
+                head = git.commit_patch(&head, &commit.patch, &commit.message)?;
+            }
+            git.reset_mixed(&head)?;
+        }
+        if !entry.patch.is_empty() {
+            git.apply_binary(&entry.patch)?;
+        }
+        return serde_json::from_value(entry.result).map_err(|e| Error::AgentSchema {
+            expected: kind,
+            raw: e.to_string(),
+        });
+    }
 
-#[derive(Debug, Serialize, Deserialize, JsonSchema)]
-struct CreateWipCommitInput {
-    /// Which commit number (1-indexed) this change belongs to
-    target_commit_number: usize,
+    config.rate_limit.wait().await;
+    let before = if recording.is_recording() {
+        Some((git.rev_parse("HEAD")?, git.snapshot_tree()?))
+    } else {
+        None
+    };
+    let result = think.await.map_err(agent_error::<T>)?;
+    if let Some((head, tree)) = before {
+        let patch = git.tree_patch(&tree, &git.snapshot_tree()?)?;
+        let commits = git
+            .log_between(&head, "HEAD")?
+            .into_iter()
+            .map(|entry| {
+                Ok(record::Commit {
+                    message: git.commit_message(&entry.hash)?.trim_end().to_string(),
+                    patch: git.tree_patch(
+                        &git.tree(&format!("{}^", entry.hash))?,
+                        &git.tree(&entry.hash)?,
+                    )?,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let result = serde_json::to_value(&result).map_err(|e| recording_error(e.to_string()))?;
+        recording
+            .save(&record::Entry {
+                kind,
+                result,
+                patch,
+                commits,
+            })
+            .map_err(|e| recording_error(e.to_string()))?;
+    }
+    Ok(result)
 }
 
-#[derive(Debug, Serialize, Deserialize, JsonSchema)]
-struct CreateWipCommitOutput {
-    /// The WIP commit message if successful
-    wip_message: Option<String>,
-    /// Error message if the commit failed
-    error: Option<String>,
+/// The last segment of `T`'s name, for error messages.
+fn short_type_name<T>() -> String {
+    std::any::type_name::<T>()
+        .rsplit("::")
+        .next()
+        .unwrap_or_default()
+        .to_string()
 }
 
-#[derive(Debug, Serialize, Deserialize, JsonSchema)]
-struct CatchallResult {
-    /// Number of WIP commits created
-    commits_created: usize,
+/// Convert a failed think block whose result should have been a `T`.
+///
+/// An answer that doesn't fit `T`'s schema becomes [`Error::AgentSchema`],
+/// so a model's malformed output can be told apart from a broken connection.
+fn agent_error<T>(e: determinishtic::Error) -> Error {
+    let expected = short_type_name::<T>();
+    match e {
+        determinishtic::Error::Deserialization(e) => Error::AgentSchema {
+            expected,
+            raw: e.to_string(),
+        },
+        determinishtic::Error::NoResult => Error::AgentSchema {
+            expected,
+            raw: "no result was returned".to_string(),
+        },
+        e => Error::Agent {
+            message: e.to_string(),
+        },
+    }
 }
 
-// =============================================================================
-// Helper Functions
-// =============================================================================
-
-/// Set up the cleaned branch from merge-base if it doesn't exist.
-fn setup_cleaned_branch<H: ExecuteHooks>(
+/// Run a build or test command, in `build_target_dir` if one is set.
+fn run_check<H: ExecuteHooks>(
     git: &Git,
-    spec: &HistorySpec,
+    command: &str,
+    config: &ExecuteConfig,
     hooks: &H,
-) -> Result<(), Error> {
-    if git.ref_exists(&spec.cleaned) {
-        git.checkout(&spec.cleaned)?;
-        hooks.report(&format!("Checked out existing branch: {}", spec.cleaned));
-    } else {
-        let base = git.merge_base(&spec.source, &spec.remote)?;
-        git.checkout_new_branch(&spec.cleaned, &base)?;
-        let base_short = &base[..8.min(base.len())];
-        hooks.report(&format!(
-            "Created branch {} from merge-base {}",
-            spec.cleaned, base_short
-        ));
-    }
-    Ok(())
+) -> Result<CommandResult, Error> {
+    let target_dir = config
+        .build_target_dir
+        .as_deref()
+        .map(Path::to_string_lossy);
+    let env: Vec<(&str, &str)> = target_dir
+        .as_deref()
+        .map(|dir| ("CARGO_TARGET_DIR", dir))
+        .into_iter()
+        .collect();
+    run_command_with_env(git.root(), command, &env, config.output_lines, hooks)
 }
 
 /// Run a shell command, streaming output through hooks and capturing it.
 fn run_command<H: ExecuteHooks>(
     repo_root: &Path,
     command: &str,
+    shown_lines: Option<usize>,
     hooks: &H,
 ) -> Result<CommandResult, Error> {
+    run_command_with_env(repo_root, command, &[], shown_lines, hooks)
+}
+
+/// Read `reader` to the end as lines, without their line endings.
+///
+/// Build output isn't always UTF-8 (a compiler quoting a Latin-1 source
+/// file, say), so invalid bytes are replaced rather than ending the read.
+fn read_lines_lossy(reader: impl std::io::Read) -> Vec<String> {
     use std::io::BufRead;
 
+    std::io::BufReader::new(reader)
+        .split(b'\n')
+        .map_while(Result::ok)
+        .map(|line| {
+            let line = line.strip_suffix(b"\r").unwrap_or(&line);
+            String::from_utf8_lossy(line).into_owned()
+        })
+        .collect()
+}
+
+/// Like [`run_command`], with extra environment variables for the command.
+fn run_command_with_env<H: ExecuteHooks>(
+    repo_root: &Path,
+    command: &str,
+    env: &[(&str, &str)],
+    shown_lines: Option<usize>,
+    hooks: &H,
+) -> Result<CommandResult, Error> {
     // Parse command into program and args (simple shell-style splitting)
     let parts: Vec<&str> = command.split_whitespace().collect();
     let (program, args) = parts
@@ -851,6 +3331,7 @@ fn run_command<H: ExecuteHooks>(
 
     let mut child = StdCommand::new(program)
         .args(args)
+        .envs(env.iter().copied())
         .current_dir(repo_root)
         .stdout(std::process::Stdio::piped())
         .stderr(std::process::Stdio::piped())
@@ -861,36 +3342,19 @@ fn run_command<H: ExecuteHooks>(
     let stdout_reader = child.stdout.take().unwrap();
     let stderr_reader = child.stderr.take().unwrap();
 
-    let stdout_handle = std::thread::spawn(move || {
-        let reader = std::io::BufReader::new(stdout_reader);
-        let mut lines = Vec::new();
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                lines.push(line);
-            }
-        }
-        lines
-    });
-
-    let stderr_handle = std::thread::spawn(move || {
-        let reader = std::io::BufReader::new(stderr_reader);
-        let mut lines = Vec::new();
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                lines.push(line);
-            }
-        }
-        lines
-    });
+    let stdout_handle = std::thread::spawn(move || read_lines_lossy(stdout_reader));
+    let stderr_handle = std::thread::spawn(move || read_lines_lossy(stderr_reader));
 
     let stdout_lines = stdout_handle.join().unwrap_or_default();
     let stderr_lines = stderr_handle.join().unwrap_or_default();
 
-    // Print all captured output through hooks
-    for line in &stdout_lines {
-        hooks.report(line);
+    // Print the captured output through hooks, or just its tail if limited
+    let total = stdout_lines.len() + stderr_lines.len();
+    let skip = shown_lines.map_or(0, |n| total.saturating_sub(n));
+    if skip > 0 {
+        hooks.report(&format!("  ... ({skip} lines of output hidden)"));
     }
-    for line in &stderr_lines {
+    for line in stdout_lines.iter().chain(&stderr_lines).skip(skip) {
         hooks.report(line);
     }
 
@@ -910,16 +3374,290 @@ fn run_command<H: ExecuteHooks>(
     })
 }
 
-/// Save the spec back to the TOML file.
-fn save_spec(spec_path: &Path, spec: &HistorySpec) -> Result<(), Error> {
-    let content = spec.to_toml()?;
-    std::fs::write(spec_path, &content).map_err(|e| Error::WriteSpec {
+/// Write a just-completed logical commit to `dir/commit-NN-<slug>.patch`.
+///
+/// The patch covers the commit and any fixup commits made on top of it, so
+/// applying it with `git am` reproduces the whole logical commit. Commits
+/// that turned out empty get no patch.
+fn write_commit_patch(
+    git: &Git,
+    spec: &HistorySpec,
+    commit_idx: usize,
+    dir: &Path,
+) -> Result<(), Error> {
+    let commit_spec = &spec.commits[commit_idx];
+    let Some(first) = commit_spec.first_commit() else {
+        return Ok(());
+    };
+
+    let patch = git.format_patch_stdout(&format!("{first}^"), "HEAD")?;
+    let path = dir.join(commit_patch_name(commit_idx, commit_spec));
+    std::fs::create_dir_all(dir)
+        .and_then(|()| std::fs::write(&path, patch))
+        .map_err(|e| Error::WritePatch {
+            path: path.display().to_string(),
+            source: e,
+        })
+}
+
+/// File name of the patch written for a logical commit in the patch directory.
+fn commit_patch_name(commit_idx: usize, commit_spec: &CommitSpec) -> String {
+    format!(
+        "commit-{:02}-{}.patch",
+        commit_idx + 1,
+        slugify(commit_spec.subject())
+    )
+}
+
+/// Write the manifest for the patches in `dir`, once the branch matches the target.
+///
+/// The patches are rewritten from the branch itself: each commit in
+/// `base..HEAD` goes to the logical commit whose history records it, and
+/// the unrecorded ones after the last of those (fixups for the leftovers,
+/// or the catchall commit) go in one more patch, so replaying the manifest
+/// gives the same tree. Any other commit would be left out of every patch,
+/// so it is an error.
+fn write_manifest(git: &Git, spec: &HistorySpec, base: &str, dir: &Path) -> Result<(), Error> {
+    let owner = |hash: &str| {
+        spec.commits.iter().position(|c| {
+            c.entries_since_rollback()
+                .iter()
+                .any(|e| matches!(e, HistoryEntry::CommitCreated(h) if h == hash))
+        })
+    };
+    let log = git.log_between(base, "HEAD")?;
+    let last_recorded = log.iter().rposition(|entry| owner(&entry.hash).is_some());
+
+    let mut groups = vec![Vec::new(); spec.commits.len()];
+    let mut remaining = Vec::new();
+    let mut uncovered = Vec::new();
+    for (i, entry) in log.iter().enumerate() {
+        match owner(&entry.hash) {
+            Some(idx) => groups[idx].push(entry.hash.as_str()),
+            None if last_recorded.is_none_or(|last| i > last) => {
+                remaining.push(entry.hash.as_str());
+            }
+            None => uncovered.push(entry.hash.as_str()),
+        }
+    }
+    if !uncovered.is_empty() {
+        return Err(Error::UncoveredCommits {
+            commits: uncovered.join(", "),
+        });
+    }
+
+    let write_patch = |name: String, commits: &[&str]| -> Result<Option<String>, Error> {
+        if commits.is_empty() {
+            return Ok(None);
+        }
+        let path = dir.join(&name);
+        let patch = git.format_commits_stdout(commits)?;
+        std::fs::create_dir_all(dir)
+            .and_then(|()| std::fs::write(&path, patch))
+            .map_err(|e| Error::WritePatch {
+                path: path.display().to_string(),
+                source: e,
+            })?;
+        Ok(Some(name))
+    };
+    let commits = spec
+        .commits
+        .iter()
+        .zip(&groups)
+        .enumerate()
+        .map(|(idx, (commit, group))| {
+            Ok(ManifestCommit {
+                message: commit.message.clone(),
+                patch: write_patch(commit_patch_name(idx, commit), group)?,
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let remaining = write_patch("remaining.patch".to_string(), &remaining)?;
+
+    let manifest = Manifest {
+        branch: spec.cleaned.clone(),
+        base: (spec.base != Base::Orphan).then(|| base.to_string()),
+        source: spec.source.clone(),
+        tree: git.tree("HEAD")?,
+        commits,
+        remaining,
+    };
+    let path = dir.join(MANIFEST_FILE);
+    let json = serde_json::to_string_pretty(&manifest).map_err(std::io::Error::from);
+    json.and_then(|json| std::fs::write(&path, json + "\n"))
+        .map_err(|e| Error::WritePatch {
+            path: path.display().to_string(),
+            source: e,
+        })
+}
+
+/// Warn if a completed commit changed different files than its
+/// `expected_files` list, returning whether it did.
+fn check_expected_files<H: ExecuteHooks>(
+    git: &Git,
+    commit_spec: &CommitSpec,
+    hooks: &H,
+) -> Result<bool, Error> {
+    if commit_spec.expected_files.is_empty() {
+        return Ok(false);
+    }
+    let Some(first) = commit_spec.first_commit() else {
+        return Ok(false);
+    };
+
+    let actual = git.changed_paths(&format!("{first}^"), "HEAD", "ACDMRT")?;
+    let missing: Vec<&str> = commit_spec
+        .expected_files
+        .iter()
+        .filter(|path| !actual.contains(path))
+        .map(String::as_str)
+        .collect();
+    let unexpected: Vec<&str> = actual
+        .iter()
+        .filter(|path| !commit_spec.expected_files.contains(path))
+        .map(String::as_str)
+        .collect();
+
+    if !missing.is_empty() {
+        hooks.report(&format!(
+            "  Warning: expected changes to {} that the commit doesn't make",
+            missing.join(", ")
+        ));
+    }
+    if !unexpected.is_empty() {
+        hooks.report(&format!(
+            "  Warning: the commit also changes {}, which expected_files doesn't list",
+            unexpected.join(", ")
+        ));
+    }
+    Ok(!missing.is_empty() || !unexpected.is_empty())
+}
+
+/// Turn a commit subject into a short, filename-safe slug.
+fn slugify(subject: &str) -> String {
+    let mut slug = String::new();
+    for c in subject.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+        if slug.len() >= 50 {
+            break;
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// The prompt section telling the LLM how a human resolved a stuck commit.
+///
+/// Uses `config.resolution_template` if set, with `{note}` replaced by the
+/// resolution note.
+fn resolution_context(note: &str, config: &ExecuteConfig) -> String {
+    match &config.resolution_template {
+        Some(template) => format!("\n{}\n", template.trim().replace("{note}", note)),
+        None => format!(
+            "\n## Previous attempt was stuck - human provided resolution:\n{note}\n\nUse this context to guide your approach.\n"
+        ),
+    }
+}
+
+/// The prompt section passing on review feedback about an earlier
+/// reconstruction of the commit, or nothing if there is none.
+fn review_context(commit_spec: &CommitSpec) -> String {
+    if commit_spec.review_notes.is_empty() {
+        return String::new();
+    }
+    let notes: String = commit_spec
+        .review_notes
+        .iter()
+        .map(|note| format!("- {}\n", note.trim()))
+        .collect();
+    format!(
+        "\n## Review feedback on a previous version of this commit:\n{notes}\nThese corrections take precedence over the hints where they disagree.\n"
+    )
+}
+
+/// A prompt section with the current contents of the commit's `context_files`.
+///
+/// A file that can't be read is listed with the reason, rather than failing
+/// the commit; it may simply not exist yet at this point in the history.
+fn context_files_context(git: &Git, commit_spec: &CommitSpec, max_bytes: Option<usize>) -> String {
+    if commit_spec.context_files.is_empty() {
+        return String::new();
+    }
+    let mut context = String::from("\n## Context files (current contents, for reference):\n");
+    for path in &commit_spec.context_files {
+        match tools::read_for_prompt(git.root(), path, max_bytes) {
+            Ok(content) => {
+                context.push_str(&format!("\n### {path}\n```\n{content}"));
+                if !content.ends_with('\n') {
+                    context.push('\n');
+                }
+                context.push_str("```\n");
+            }
+            Err(e) => context.push_str(&format!("\n### {path}\n({e})\n")),
+        }
+    }
+    context
+}
+
+/// Read and parse the spec file.
+fn read_spec(spec_path: &Path) -> Result<HistorySpec, Error> {
+    let content = std::fs::read_to_string(spec_path).map_err(|e| Error::ReadSpec {
         path: spec_path.display().to_string(),
         source: e,
     })?;
+    Ok(HistorySpec::from_toml(&content)?)
+}
+
+/// Check that progress can be saved to the spec file, without changing it.
+///
+/// Saving replaces the file with a new one written next to it, so this
+/// probes the directory the same way. A spec the user made read-only is
+/// refused too, even though replacing it would work.
+fn check_spec_writable(spec_path: &Path) -> Result<(), Error> {
+    let not_writable = |source| Error::SpecNotWritable {
+        path: spec_path.display().to_string(),
+        source,
+    };
+    let path = spec_path
+        .canonicalize()
+        .unwrap_or_else(|_| spec_path.to_path_buf());
+    if std::fs::metadata(&path).is_ok_and(|meta| meta.permissions().readonly()) {
+        return Err(not_writable(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "the file is read-only",
+        )));
+    }
+    let probe = sibling_temp_path(&path, "probe");
+    std::fs::File::create(&probe).map_err(not_writable)?;
+    let _ = std::fs::remove_file(&probe);
     Ok(())
 }
 
+/// Save the spec back to the TOML file.
+fn save_spec(spec_path: &Path, spec: &HistorySpec) -> Result<(), Error> {
+    // Edit the existing file rather than regenerating it, to keep the user's formatting
+    let content = match std::fs::read_to_string(spec_path) {
+        Ok(original) => spec.to_toml_preserving(&original)?,
+        Err(_) => spec.to_toml()?,
+    };
+    write_spec(spec_path, &content)
+}
+
+/// Replace the spec file at `spec_path` with `content`, which should
+/// already be a valid spec.
+///
+/// The file is swapped in whole, as retcon saves its own progress, so an
+/// interrupted write never leaves a truncated spec behind.
+pub fn write_spec(spec_path: &Path, content: &str) -> Result<(), Error> {
+    write_atomic(spec_path, content.as_bytes()).map_err(|e| Error::WriteSpec {
+        path: spec_path.display().to_string(),
+        source: e,
+    })
+}
+
 // =============================================================================
 // Error Types
 // =============================================================================
@@ -937,6 +3675,9 @@ pub enum Error {
     #[error("failed to parse spec file")]
     ParseSpec(#[from] toml::de::Error),
 
+    #[error("invalid spec: {0}")]
+    InvalidSpec(#[from] ValidationError),
+
     #[error("failed to serialize spec")]
     SerializeSpec(#[from] toml::ser::Error),
 
@@ -947,6 +3688,37 @@ pub enum Error {
         source: std::io::Error,
     },
 
+    #[error("spec file '{path}' is not writable, so progress couldn't be saved")]
+    SpecNotWritable {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("failed to write patch to '{path}'")]
+    WritePatch {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("failed to write stuck report to '{path}'")]
+    WriteStuckReport {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("failed to write range diff to '{path}'")]
+    WriteRangeDiff {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("refusing to create the catchall commit with unresolved conflicts in: {paths}")]
+    UnmergedPaths { paths: String },
+
     #[error("git: {0}")]
     Git(#[from] crate::git::Error),
 
@@ -961,4 +3733,148 @@ pub enum Error {
 
     #[error("LLM agent error: {message}")]
     Agent { message: String },
+
+    #[error("LLM agent returned a result that isn't a valid {expected}: {raw}")]
+    AgentSchema { expected: String, raw: String },
+
+    #[error("recording '{path}': {message}")]
+    Recording { path: String, message: String },
+
+    #[error(
+        "agent rejected retcon's tools ({message}); check that the agent supports ACP tool definitions"
+    )]
+    ToolCheck { message: String },
+
+    #[error(
+        "source '{source_ref}' is already part of remote '{remote}', so it has no changes to reconstruct; check the spec's branches"
+    )]
+    SourceInRemote { source_ref: String, remote: String },
+
+    #[error(
+        "branch '{branch}' was built on {found}, but the base is now {expected}; rerun with --fresh to rebuild it"
+    )]
+    StaleCleanedBranch {
+        branch: String,
+        found: String,
+        expected: String,
+    },
+
+    #[error(
+        "branch '{cleaned}' points at the same commit as source '{source_ref}'; \
+         refusing to commit on it"
+    )]
+    CleanedIsSource { cleaned: String, source_ref: String },
+
+    #[error(
+        "with base = \"remote\", the changes conflict with '{remote}' in: {paths}; \
+         rebase the source onto it first, or use another base"
+    )]
+    RemoteBaseConflicts { remote: String, paths: String },
+
+    #[error(
+        "source '{source_ref}' moved from {before} to {after} during the run; \
+         retcon never changes it, so unless you did, please report this"
+    )]
+    SourceModified {
+        source_ref: String,
+        before: String,
+        after: String,
+    },
+
+    #[error("cleaned branch still differs from '{target}' after finalizing, in: {paths}")]
+    TargetMismatch { target: String, paths: String },
+
+    #[error("giving up after {count} consecutive stuck commits")]
+    TooManyStuck { count: usize },
+
+    #[error("commits {commits} on the branch belong to no logical commit, so no patch covers them")]
+    UncoveredCommits { commits: String },
+
+    #[error(
+        "--file only reconstructs part of each commit, so it needs --output-spec \
+         to keep that progress out of the spec"
+    )]
+    FileWithoutOutputSpec,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_spec_writable_refuses_a_read_only_spec() {
+        let dir = std::env::temp_dir().join(format!("retcon-writable-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let spec = dir.join("spec.toml");
+        std::fs::write(&spec, "source = \"feature\"\n").unwrap();
+
+        check_spec_writable(&spec).unwrap();
+        let mut perms = std::fs::metadata(&spec).unwrap().permissions();
+        perms.set_readonly(true);
+        std::fs::set_permissions(&spec, perms).unwrap();
+        assert!(matches!(
+            check_spec_writable(&spec),
+            Err(Error::SpecNotWritable { .. })
+        ));
+
+        // The probe leaves nothing behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn read_lines_lossy_keeps_reading_past_invalid_utf8() {
+        let output: &[u8] = b"ok\r\nbad \xff byte\nlast";
+        assert_eq!(
+            read_lines_lossy(output),
+            ["ok", "bad \u{fffd} byte", "last"]
+        );
+    }
+
+    #[test]
+    fn completed_status_flags_fixups_and_warnings() {
+        let spec = HistorySpec::from_toml(
+            r#"source = "feature"
+remote = "origin/main"
+cleaned = "feature-clean"
+
+[[commit]]
+message = "Add a"
+history = [{ commit_created = "1111111" }, "complete"]
+
+[[commit]]
+message = "Add b"
+history = [
+    { commit_created = "2222222" },
+    { rolled_back = "1111111" },
+    { commit_created = "3333333" },
+    "complete",
+]
+
+[[commit]]
+message = "Add c"
+history = [{ commit_created = "4444444" }, { commit_created = "5555555" }, "complete"]
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            completed_status(&spec.commits[0], false),
+            CommitStatus::Completed
+        );
+        assert_eq!(
+            completed_status(&spec.commits[0], true),
+            CommitStatus::NeedsReview
+        );
+        // Commits that were rolled back don't count as fixups
+        assert_eq!(
+            completed_status(&spec.commits[1], false),
+            CommitStatus::Completed
+        );
+        assert_eq!(
+            completed_status(&spec.commits[2], false),
+            CommitStatus::NeedsReview
+        );
+    }
 }
@@ -1,23 +1,39 @@
 //! Git repository operations.
 
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 /// A git repository handle that provides common operations.
+#[derive(Debug, Clone)]
 pub struct Git {
     root: PathBuf,
+    no_verify: bool,
+    /// The git executable to run
+    program: PathBuf,
+    /// Extra environment variables for every git command
+    env: Vec<(OsString, OsString)>,
 }
 
 impl Git {
     /// Find the git repository root starting from the given path.
     pub fn discover(start: &Path) -> Result<Self, Error> {
+        Self::discover_with_program(start, "git")
+    }
+
+    /// Like [`Git::discover`], but run `program` instead of the `git` on `PATH`.
+    ///
+    /// The handle keeps using `program` for everything it does.
+    pub fn discover_with_program(start: &Path, program: impl Into<PathBuf>) -> Result<Self, Error> {
+        let program = program.into();
+
         // Start from the given directory, or current dir if it's just a filename
         let start_dir = start
             .parent()
             .filter(|p| !p.as_os_str().is_empty())
             .unwrap_or(Path::new("."));
 
-        let output = Command::new("git")
+        let output = Command::new(&program)
             .args(["rev-parse", "--show-toplevel"])
             .current_dir(start_dir)
             .output()
@@ -28,9 +44,47 @@ impl Git {
         }
 
         let root = String::from_utf8_lossy(&output.stdout).trim().to_string();
-        Ok(Self {
-            root: PathBuf::from(root),
-        })
+        Ok(Self::at(root).with_program(program))
+    }
+
+    /// Open the repository whose top-level directory is `root`.
+    pub fn at(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            no_verify: false,
+            program: PathBuf::from("git"),
+            env: Vec::new(),
+        }
+    }
+
+    /// Run `program` instead of the `git` on `PATH`.
+    #[must_use]
+    pub fn with_program(mut self, program: impl Into<PathBuf>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// Set an environment variable (such as `GIT_DIR`) for every git command
+    /// run through this handle.
+    #[must_use]
+    pub fn with_env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Skip commit hooks (`git commit --no-verify`) for commits made through this handle.
+    #[must_use]
+    pub fn with_no_verify(mut self, no_verify: bool) -> Self {
+        self.no_verify = no_verify;
+        self
+    }
+
+    /// A handle for another worktree of this repository, with the same settings.
+    pub(crate) fn for_worktree(&self, root: PathBuf) -> Self {
+        Self {
+            root,
+            ..self.clone()
+        }
     }
 
     /// Get the repository root path.
@@ -40,66 +94,734 @@ impl Git {
 
     /// Check if a branch or ref exists.
     pub fn ref_exists(&self, refname: &str) -> bool {
-        Command::new("git")
+        self.command()
             .args(["rev-parse", "--verify", refname])
-            .current_dir(&self.root)
             .output()
             .map(|o| o.status.success())
             .unwrap_or(false)
     }
 
+    /// Resolve a ref to its full commit hash.
+    pub fn rev_parse(&self, refname: &str) -> Result<String, Error> {
+        let output = self.run_output(&["rev-parse", "--verify", &peeled(refname)])?;
+        Ok(output.trim().to_string())
+    }
+
     /// Get the merge-base between two refs.
     pub fn merge_base(&self, ref1: &str, ref2: &str) -> Result<String, Error> {
-        let output = self.run_output(&["merge-base", ref1, ref2])?;
+        let output = self.run_output(&["merge-base", &peeled(ref1), &peeled(ref2)])?;
         Ok(output.trim().to_string())
     }
 
+    /// Get every merge-base between two refs; there is more than one after
+    /// criss-cross merges.
+    pub fn merge_bases(&self, ref1: &str, ref2: &str) -> Result<Vec<String>, Error> {
+        let output = self.run_output(&["merge-base", "--all", &peeled(ref1), &peeled(ref2)])?;
+        Ok(output.lines().map(str::to_string).collect())
+    }
+
+    /// Check whether `ancestor` is an ancestor of (or the same commit as) `descendant`.
+    pub fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool, Error> {
+        let (ancestor, descendant) = (&peeled(ancestor), &peeled(descendant));
+        let output = self
+            .command()
+            .args(["merge-base", "--is-ancestor", ancestor, descendant])
+            .output()
+            .map_err(|e| Error::Exec(format!("git merge-base: {e}")))?;
+
+        match output.status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => Err(failure(
+                &["merge-base", "--is-ancestor", ancestor, descendant],
+                &output,
+            )),
+        }
+    }
+
     /// Checkout a branch.
     pub fn checkout(&self, branch: &str) -> Result<(), Error> {
         self.run(&["checkout", branch])
     }
 
+    /// Check out `commit` on a detached HEAD.
+    pub fn checkout_detached(&self, commit: &str) -> Result<(), Error> {
+        self.run(&["checkout", "--detach", commit])
+    }
+
     /// Create and checkout a new branch from a starting point.
     pub fn checkout_new_branch(&self, branch: &str, start: &str) -> Result<(), Error> {
-        self.run(&["checkout", "-b", branch, start])
+        self.run(&["checkout", "-b", branch, &peeled(start)])
+    }
+
+    /// Create `branch` with no history in common with anything else, and check it out.
+    ///
+    /// The branch starts at [`empty_root_commit`](Self::empty_root_commit),
+    /// so its working tree is empty.
+    pub fn checkout_orphan(&self, branch: &str) -> Result<(), Error> {
+        let root = self.empty_root_commit()?;
+        self.checkout_new_branch(branch, &root)
+    }
+
+    /// A commit with an empty tree and no parents.
+    ///
+    /// Its author, committer, and dates are fixed, so every call (in any run)
+    /// produces the same commit.
+    pub fn empty_root_commit(&self) -> Result<String, Error> {
+        let tree = self.run_output(&["mktree"])?;
+        let commit = self.run_output_with_env(
+            &[
+                "commit-tree",
+                "--no-gpg-sign",
+                "-m",
+                "Empty root",
+                tree.trim(),
+            ],
+            &FIXED_IDENT,
+        )?;
+        Ok(commit.trim().to_string())
+    }
+
+    /// A commit on top of `base` that changes only `path`, to how it is in `from`.
+    ///
+    /// If `path` doesn't exist in `from`, the commit deletes it. As with
+    /// [`empty_root_commit`](Self::empty_root_commit), the author, committer,
+    /// and dates are fixed, so the same inputs always give the same commit.
+    pub fn commit_with_file(&self, base: &str, from: &str, path: &str) -> Result<String, Error> {
+        // Build the tree in a scratch index so the real one is left alone
+        let index = self.git_path("retcon-file-index")?;
+        let index_str = index.to_string_lossy();
+        let mut env = FIXED_IDENT.to_vec();
+        env.push(("GIT_INDEX_FILE", &index_str));
+
+        let result = (|| {
+            self.run_output_with_env(&["read-tree", base], &env)?;
+            let entry = self.run_output(&["ls-tree", from, "--", path])?;
+            match entry.split_once('\t') {
+                Some((info, _)) => {
+                    let fields: Vec<&str> = info.split_whitespace().collect();
+                    let [mode, "blob", hash] = fields[..] else {
+                        return Err(Error::Failed(format!("'{path}' is not a file in {from}")));
+                    };
+                    let cacheinfo = format!("{mode},{hash},{path}");
+                    self.run_output_with_env(
+                        &["update-index", "--add", "--cacheinfo", &cacheinfo],
+                        &env,
+                    )?;
+                }
+                None => {
+                    self.run_output_with_env(
+                        &["update-index", "--force-remove", "--", path],
+                        &env,
+                    )?;
+                }
+            }
+            let tree = self.run_output_with_env(&["write-tree"], &env)?;
+            self.commit_tree(tree.trim(), base, &format!("Only {path} from {from}"))
+        })();
+        let _ = std::fs::remove_file(&index);
+        result
+    }
+
+    /// A commit of `tree` with `parent` as its only parent.
+    ///
+    /// Like [`empty_root_commit`](Self::empty_root_commit), the author,
+    /// committer, and dates are fixed.
+    pub fn commit_tree(&self, tree: &str, parent: &str, message: &str) -> Result<String, Error> {
+        let commit = self.run_output_with_env(
+            &[
+                "commit-tree",
+                "--no-gpg-sign",
+                "-p",
+                parent,
+                "-m",
+                message,
+                tree,
+            ],
+            &FIXED_IDENT,
+        )?;
+        Ok(commit.trim().to_string())
+    }
+
+    /// Merge `theirs` into `ours` without touching the index or working tree.
+    ///
+    /// Returns the merged tree and the paths that conflict, if any (in which
+    /// case the tree has conflict markers in them). Needs git 2.38 or later.
+    pub fn merge_tree(&self, ours: &str, theirs: &str) -> Result<(String, Vec<String>), Error> {
+        let args = [
+            "merge-tree",
+            "--write-tree",
+            "--name-only",
+            &peeled(ours),
+            &peeled(theirs),
+        ];
+        let output = self
+            .command()
+            .args(args)
+            .output()
+            .map_err(|e| Error::Exec(format!("git merge-tree: {e}")))?;
+
+        match output.status.code() {
+            Some(0 | 1) => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let mut lines = stdout.lines();
+                let tree = lines.next().unwrap_or_default().to_string();
+                let conflicts = lines
+                    .take_while(|line| !line.is_empty())
+                    .map(String::from)
+                    .collect();
+                Ok((tree, conflicts))
+            }
+            _ => Err(failure(&args, &output)),
+        }
+    }
+
+    /// The first parentless commit reachable from `refname`.
+    pub fn root_commit(&self, refname: &str) -> Result<String, Error> {
+        let output = self.run_output(&["rev-list", "--max-parents=0", &peeled(refname)])?;
+        Ok(output.lines().next().unwrap_or_default().to_string())
+    }
+
+    /// Point `branch` at `start` (creating it if needed) and check it out.
+    pub fn reset_branch(&self, branch: &str, start: &str) -> Result<(), Error> {
+        self.run(&["checkout", "-B", branch, &peeled(start)])
+    }
+
+    /// Create (or move) the lightweight tag `name` to point at `commit`.
+    pub fn tag(&self, name: &str, commit: &str) -> Result<(), Error> {
+        self.run(&["tag", "-f", name, commit])
     }
 
     /// Get the diff between two refs.
     pub fn diff(&self, from: &str, to: &str) -> Result<String, Error> {
-        let range = format!("{from}..{to}");
+        let range = format!("{}..{}", peeled(from), peeled(to));
         self.run_output(&["diff", &range])
     }
 
+    /// Where `name` lives inside the repository's git directory, e.g. for scratch files.
+    pub fn git_path(&self, name: &str) -> Result<PathBuf, Error> {
+        let path = self.run_output(&["rev-parse", "--git-path", name])?;
+        Ok(self.root.join(path.trim()))
+    }
+
+    /// Get the diff between two refs, limited to one path.
+    pub fn diff_path(&self, from: &str, to: &str, path: &str) -> Result<String, Error> {
+        let range = format!("{}..{}", peeled(from), peeled(to));
+        self.run_output(&["diff", &range, "--", path])
+    }
+
+    /// Paths whose content or mode differs between the trees of two commits.
+    ///
+    /// This uses `git diff-tree`, which compares the trees directly. Being
+    /// plumbing, it ignores settings such as `diff.external` that can change
+    /// what porcelain `git diff` prints.
+    pub fn diff_tree(&self, from: &str, to: &str) -> Result<Vec<String>, Error> {
+        let output = self.run_output(&[
+            "diff-tree",
+            "-r",
+            "--name-only",
+            "--no-renames",
+            &peeled(from),
+            &peeled(to),
+        ])?;
+        Ok(output.lines().map(String::from).collect())
+    }
+
+    /// The patch between the trees of two commits, from `git diff-tree -p`.
+    ///
+    /// Empty exactly when [`diff_tree`](Self::diff_tree) is, since both come
+    /// from the same plumbing command.
+    pub fn diff_tree_patch(&self, from: &str, to: &str) -> Result<String, Error> {
+        self.run_output(&[
+            "diff-tree",
+            "-r",
+            "-p",
+            "--no-renames",
+            &peeled(from),
+            &peeled(to),
+        ])
+    }
+
     /// Get the diff --stat between two refs.
     pub fn diff_stat(&self, from: &str, to: &str) -> Result<String, Error> {
-        let range = format!("{from}..{to}");
+        let range = format!("{}..{}", peeled(from), peeled(to));
         self.run_output(&["diff", "--stat", &range])
     }
 
+    /// Like [`diff_stat`](Self::diff_stat), leaving out changes that only
+    /// add or remove whitespace (`git diff -w`).
+    pub fn diff_stat_ignoring_whitespace(&self, from: &str, to: &str) -> Result<String, Error> {
+        let range = format!("{}..{}", peeled(from), peeled(to));
+        self.run_output(&["diff", "--stat", "-w", &range])
+    }
+
+    /// Files modified between `from` and `to` in whitespace only.
+    ///
+    /// A file only counts if it keeps its mode and `git diff -w` finds
+    /// nothing left in it, so added, deleted, and binary files never do.
+    pub fn whitespace_only_changes(&self, from: &str, to: &str) -> Result<Vec<String>, Error> {
+        let (from, to) = (peeled(from), peeled(to));
+        let raw = self.run_output(&["diff-tree", "-r", "--no-renames", &from, &to])?;
+        // Binary files show up here too, as `-\t-\t<path>`
+        let numstat = self.run_output(&["diff", "-w", "--numstat", "--no-renames", &from, &to])?;
+        let changed: Vec<&str> = numstat
+            .lines()
+            .filter_map(|line| line.splitn(3, '\t').nth(2))
+            .collect();
+        Ok(raw
+            .lines()
+            .filter_map(|line| {
+                // :<old mode> <new mode> <old hash> <new hash> <status>\t<path>
+                let (meta, path) = line.strip_prefix(':')?.split_once('\t')?;
+                let fields: Vec<&str> = meta.split(' ').collect();
+                let [old_mode, new_mode, _, _, "M"] = fields[..] else {
+                    return None;
+                };
+                (old_mode == new_mode && !changed.contains(&path)).then(|| path.to_string())
+            })
+            .collect())
+    }
+
+    /// `git range-diff base..old base..new`: how each commit of `new` compares
+    /// to its counterpart in `old`.
+    pub fn range_diff(&self, base: &str, old: &str, new: &str) -> Result<String, Error> {
+        let base = peeled(base);
+        let old_range = format!("{base}..{}", peeled(old));
+        let new_range = format!("{base}..{}", peeled(new));
+        self.run_output(&["range-diff", "--no-color", &old_range, &new_range])
+    }
+
+    /// Paths changed between `from` and `to` whose status matches `filter`
+    /// (as in `--diff-filter`, e.g. `"D"` for deleted).
+    pub fn changed_paths(&self, from: &str, to: &str, filter: &str) -> Result<Vec<String>, Error> {
+        let filter = format!("--diff-filter={filter}");
+        let (from, to) = (peeled(from), peeled(to));
+        let output =
+            self.run_output(&["diff", "--name-only", "--no-renames", &filter, &from, &to])?;
+        Ok(output.lines().map(String::from).collect())
+    }
+
+    /// Files whose mode changes between `from` and `to` while their content
+    /// stays the same, with whether each ends up executable.
+    pub fn mode_only_changes(&self, from: &str, to: &str) -> Result<Vec<(String, bool)>, Error> {
+        let (from, to) = (peeled(from), peeled(to));
+        let output =
+            self.run_output(&["diff", "--raw", "--no-abbrev", "--no-renames", &from, &to])?;
+        Ok(output
+            .lines()
+            .filter_map(|line| {
+                // :<old mode> <new mode> <old hash> <new hash> <status>\t<path>
+                let (meta, path) = line.strip_prefix(':')?.split_once('\t')?;
+                let fields: Vec<&str> = meta.split(' ').collect();
+                let [old_mode, new_mode, old_hash, new_hash, "M"] = fields[..] else {
+                    return None;
+                };
+                (old_mode != new_mode && old_hash == new_hash)
+                    .then(|| (path.to_string(), new_mode == "100755"))
+            })
+            .collect())
+    }
+
+    /// Set or clear the executable bit of `path`, in the index and on disk.
+    pub fn set_executable(&self, path: &str, executable: bool) -> Result<(), Error> {
+        set_file_executable(&self.root.join(path), executable)?;
+        let chmod = if executable {
+            "--chmod=+x"
+        } else {
+            "--chmod=-x"
+        };
+        self.run(&["update-index", chmod, "--", path])
+    }
+
+    /// Apply a unified diff to the working tree with `git apply --3way`.
+    ///
+    /// This stages every change in the working tree first, since the merge
+    /// works through the index, and leaves conflicted paths unmerged there.
+    ///
+    /// Hunks whose context has drifted are merged against the blobs the
+    /// patch was made from, so a patch cut from the source branch still
+    /// applies on top of earlier edits. Returns the paths left with
+    /// conflict markers, which is empty if the patch applied cleanly.
+    pub fn apply_3way(&self, patch: &str) -> Result<Vec<String>, Error> {
+        self.add_all()?;
+        match self.run_apply(&["--3way"], patch) {
+            Ok(()) => Ok(Vec::new()),
+            Err(e) => {
+                let conflicts = self.unmerged_paths()?;
+                if conflicts.is_empty() {
+                    Err(e)
+                } else {
+                    Ok(conflicts)
+                }
+            }
+        }
+    }
+
+    /// Apply a diff from [`tree_patch`](Self::tree_patch) to the working tree.
+    pub fn apply_binary(&self, patch: &str) -> Result<(), Error> {
+        self.run_apply(&["--binary"], patch)
+    }
+
+    /// The tree the working tree would make if everything were staged.
+    ///
+    /// Staging happens in a scratch copy of the index, so the real one
+    /// (and whatever the caller has or hasn't staged) is left alone.
+    pub fn snapshot_tree(&self) -> Result<String, Error> {
+        let index = self.git_path("retcon-snapshot-index")?;
+        let real_index = self.git_path("index")?;
+        if real_index.exists() {
+            std::fs::copy(&real_index, &index)
+                .map_err(|e| Error::Exec(format!("copy {}: {e}", real_index.display())))?;
+        }
+        let index_str = index.to_string_lossy();
+        let env = [("GIT_INDEX_FILE", &*index_str)];
+
+        let result = self
+            .run_output_with_env(&["add", "-A"], &env)
+            .and_then(|_| self.run_output_with_env(&["write-tree"], &env));
+        let _ = std::fs::remove_file(&index);
+        Ok(result?.trim().to_string())
+    }
+
+    /// A commit on top of `parent` whose tree is `parent`'s with `patch` applied.
+    ///
+    /// `patch` is a binary diff as from [`tree_patch`](Self::tree_patch). The
+    /// tree is built in a scratch index, so the real index, the working tree,
+    /// and HEAD are left alone. As with [`commit_tree`](Self::commit_tree),
+    /// the author, committer, and dates are fixed.
+    pub fn commit_patch(&self, parent: &str, patch: &str, message: &str) -> Result<String, Error> {
+        let index = self.git_path("retcon-patch-index")?;
+        let index_str = index.to_string_lossy();
+        let env = [("GIT_INDEX_FILE", &*index_str)];
+
+        let result = (|| {
+            self.run_output_with_env(&["read-tree", parent], &env)?;
+            if !patch.is_empty() {
+                self.run_apply_with_env(&["--cached", "--binary"], patch, &env)?;
+            }
+            let tree = self.run_output_with_env(&["write-tree"], &env)?;
+            self.commit_tree(tree.trim(), parent, message)
+        })();
+        let _ = std::fs::remove_file(&index);
+        result
+    }
+
+    /// The diff between two trees, in a form [`apply_binary`](Self::apply_binary) can replay.
+    ///
+    /// `from` and `to` are tree ids, as from [`snapshot_tree`](Self::snapshot_tree),
+    /// so unlike the commit ranges elsewhere they aren't peeled.
+    pub fn tree_patch(&self, from: &str, to: &str) -> Result<String, Error> {
+        self.run_output(&["diff", "--binary", "--no-renames", from, to])
+    }
+
+    /// Search for lines matching the extended regex `pattern` with `git grep`.
+    ///
+    /// Searches the working tree, untracked files included, or the tree of
+    /// `rev` if given, optionally only under `path`. Each match is
+    /// `path:line:text`, prefixed with `rev:` when searching a revision.
+    /// Finding nothing is an empty list rather than an error. A `rev` that
+    /// starts with `-` is refused rather than passed to git as an option.
+    pub fn grep(
+        &self,
+        pattern: &str,
+        rev: Option<&str>,
+        path: Option<&str>,
+    ) -> Result<Vec<String>, Error> {
+        // The agent picks `rev`; git would read a leading `-` as an option
+        if let Some(rev) = rev
+            && rev.starts_with('-')
+        {
+            return Err(Error::Failed(format!("'{rev}' is not a revision")));
+        }
+        let mut args = vec!["grep", "-n", "-I", "-E"];
+        if rev.is_none() {
+            args.push("--untracked");
+        }
+        args.extend(["-e", pattern]);
+        args.extend(rev);
+        args.push("--");
+        args.extend(path);
+
+        let output = self
+            .command()
+            .args(&args)
+            .stdin(std::process::Stdio::null())
+            .output()
+            .map_err(|e| Error::Exec(format!("git grep: {e}")))?;
+        match output.status.code() {
+            Some(0) => Ok(String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(String::from)
+                .collect()),
+            // git grep exits with 1 when nothing matched
+            Some(1) if output.stderr.is_empty() => Ok(Vec::new()),
+            _ => Err(failure(&args, &output)),
+        }
+    }
+
+    /// Paths with unresolved merge conflicts in the index.
+    pub fn unmerged_paths(&self) -> Result<Vec<String>, Error> {
+        let output = self.run_output(&["diff", "--name-only", "--diff-filter=U"])?;
+        Ok(output.lines().map(String::from).collect())
+    }
+
+    /// List the commits in `from..to`, oldest first.
+    pub fn log_between(&self, from: &str, to: &str) -> Result<Vec<LogEntry>, Error> {
+        let range = format!("{}..{}", peeled(from), peeled(to));
+        let output = self.run_output(&["log", "--reverse", "--format=%H%x00%s", &range])?;
+        Ok(output
+            .lines()
+            .filter_map(|line| {
+                let (hash, subject) = line.split_once('\0')?;
+                Some(LogEntry {
+                    hash: hash[..8.min(hash.len())].to_string(),
+                    subject: subject.to_string(),
+                })
+            })
+            .collect())
+    }
+
+    /// The names of the configured remotes.
+    pub fn remotes(&self) -> Result<Vec<String>, Error> {
+        let output = self.run_output(&["remote"])?;
+        Ok(output.lines().map(String::from).collect())
+    }
+
+    /// Fetch from `remote`, limited to `branch` if given.
+    pub fn fetch(&self, remote: &str, branch: Option<&str>) -> Result<(), Error> {
+        let mut args = vec!["fetch", remote];
+        args.extend(branch);
+        self.run(&args)
+    }
+
+    /// Create a new worktree at `path` with a detached HEAD at `commit`.
+    pub fn worktree_add_detached(&self, path: &Path, commit: &str) -> Result<(), Error> {
+        let path = path.to_string_lossy();
+        self.run(&["worktree", "add", "--detach", &path, commit])
+    }
+
+    /// Remove the worktree at `path`, discarding any changes in it.
+    pub fn worktree_remove(&self, path: &Path) -> Result<(), Error> {
+        let path = path.to_string_lossy();
+        self.run(&["worktree", "remove", "--force", &path])
+    }
+
+    /// Forget worktrees whose directories no longer exist.
+    pub fn worktree_prune(&self) -> Result<(), Error> {
+        self.run(&["worktree", "prune"])
+    }
+
+    /// The paths of all worktrees of this repository, including the main one.
+    pub fn worktree_list(&self) -> Result<Vec<PathBuf>, Error> {
+        let output = self.run_output(&["worktree", "list", "--porcelain"])?;
+        Ok(output
+            .lines()
+            .filter_map(|line| line.strip_prefix("worktree "))
+            .map(PathBuf::from)
+            .collect())
+    }
+
+    /// Write each commit in `from..to` as a patch file in `out_dir`.
+    ///
+    /// Returns the paths of the patches, in order.
+    pub fn format_patch(
+        &self,
+        from: &str,
+        to: &str,
+        out_dir: &Path,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let range = format!("{}..{}", peeled(from), peeled(to));
+        let out_dir = out_dir.to_string_lossy();
+        let output = self.run_output(&["format-patch", "-o", &out_dir, &range])?;
+        Ok(output.lines().map(PathBuf::from).collect())
+    }
+
+    /// Each commit in `from..to`, oldest first, with its message and the
+    /// files it changed.
+    pub fn log_stat(&self, from: &str, to: &str) -> Result<String, Error> {
+        let range = format!("{}..{}", peeled(from), peeled(to));
+        self.run_output(&[
+            "log",
+            "--reverse",
+            "--stat",
+            "--format=commit %h%n%B",
+            &range,
+        ])
+    }
+
+    /// Apply a commit's changes to the working tree and index without committing.
+    ///
+    /// On a conflict, the partial result is undone before returning the error.
+    pub fn cherry_pick_no_commit(&self, commit: &str) -> Result<(), Error> {
+        let result = self.run(&["cherry-pick", "--no-commit", commit]);
+        if result.is_err() {
+            let _ = self.run(&["cherry-pick", "--abort"]);
+            let _ = self.run(&["reset", "--hard", "HEAD"]);
+        }
+        result
+    }
+
+    /// The full message of a commit.
+    pub fn commit_message(&self, commit: &str) -> Result<String, Error> {
+        self.run_output(&["log", "-1", "--format=%B", commit])
+    }
+
+    /// Apply the mbox at `patch` with `git am`, aborting it if any patch fails.
+    pub fn am(&self, patch: &Path) -> Result<(), Error> {
+        let result = self.run(&["am", &patch.to_string_lossy()]);
+        if result.is_err() {
+            let _ = self.run(&["am", "--abort"]);
+        }
+        result
+    }
+
+    /// The tree hash of `rev`.
+    pub fn tree(&self, rev: &str) -> Result<String, Error> {
+        let output = self.run_output(&["rev-parse", "--verify", &format!("{rev}^{{tree}}")])?;
+        Ok(output.trim().to_string())
+    }
+
+    /// Format the commits in `from..to` as a single mbox, as `git am` accepts.
+    pub fn format_patch_stdout(&self, from: &str, to: &str) -> Result<String, Error> {
+        let range = format!("{}..{}", peeled(from), peeled(to));
+        self.run_output(&["format-patch", "--stdout", &range])
+    }
+
+    /// Format `commits` as a single mbox in the order given, as `git am` accepts.
+    ///
+    /// Unlike [`format_patch_stdout`](Self::format_patch_stdout), this takes
+    /// the commits one by one, so a root commit works too.
+    pub fn format_commits_stdout(&self, commits: &[&str]) -> Result<String, Error> {
+        let mut mbox = String::new();
+        for commit in commits {
+            mbox.push_str(&self.run_output(&[
+                "format-patch",
+                "--stdout",
+                "-1",
+                &peeled(commit),
+            ])?);
+        }
+        Ok(mbox)
+    }
+
     /// Checkout files from a ref.
     pub fn checkout_files(&self, refname: &str, pathspec: &str) -> Result<(), Error> {
         self.run(&["checkout", refname, "--", pathspec])
     }
 
+    /// Get the diff of the staged changes against HEAD.
+    pub fn staged_diff(&self) -> Result<String, Error> {
+        self.run_output(&["diff", "--cached"])
+    }
+
+    /// Reset the current branch and index to `commit`, leaving the working tree as it is.
+    pub fn reset_mixed(&self, commit: &str) -> Result<(), Error> {
+        self.run(&["reset", "--mixed", "-q", commit])
+    }
+
     /// Stage all changes.
     pub fn add_all(&self) -> Result<(), Error> {
         self.run(&["add", "-A"])
     }
 
+    /// Discard uncommitted changes to tracked files.
+    pub fn discard_changes(&self) -> Result<(), Error> {
+        self.run(&["reset", "--hard", "HEAD"])
+    }
+
+    /// Remove untracked files and directories.
+    pub fn clean_untracked(&self) -> Result<(), Error> {
+        self.run(&["clean", "-fd"])
+    }
+
     /// Create a commit with the given message, returning the short hash.
     pub fn commit(&self, message: &str) -> Result<String, Error> {
         self.add_all()?;
-        self.run(&["commit", "-m", message])?;
+        self.run_commit(&["-m", message])?;
+        self.head_short()
+    }
+
+    /// Commit the index as it stands, leaving the rest of the working tree out.
+    ///
+    /// Returns the new short hash.
+    pub fn commit_index(&self, message: &str) -> Result<String, Error> {
+        self.run_commit(&["-m", message])?;
+        self.head_short()
+    }
+
+    /// Fold all working tree changes into HEAD, keeping its message.
+    ///
+    /// Returns the new short hash.
+    pub fn amend_all(&self) -> Result<String, Error> {
+        self.add_all()?;
+        self.run_commit(&["--amend", "--no-edit"])?;
+        self.head_short()
+    }
+
+    /// Whether the working tree or index differ from HEAD, untracked files included.
+    pub fn has_changes(&self) -> Result<bool, Error> {
+        let output = self.run_output(&["status", "--porcelain"])?;
+        Ok(!output.trim().is_empty())
+    }
+
+    /// Commit everything in the working tree, even if nothing changed.
+    ///
+    /// Used for intermediate snapshots that never end up on a branch, so
+    /// commit hooks are skipped.
+    pub fn commit_snapshot(&self, message: &str) -> Result<String, Error> {
+        self.add_all()?;
+        self.run(&["commit", "--allow-empty", "--no-verify", "-m", message])?;
         self.head_short()
     }
 
+    /// Make the index and working tree match `commit`'s tree exactly.
+    ///
+    /// Unlike [`checkout_files`](Self::checkout_files), this also removes
+    /// tracked files that don't exist in `commit`. HEAD does not move.
+    pub fn restore_tree(&self, commit: &str) -> Result<(), Error> {
+        self.run(&["read-tree", "--reset", "-u", commit])
+    }
+
     /// Create a fixup commit targeting the given commit hash.
     pub fn commit_fixup(&self, target: &str) -> Result<String, Error> {
         self.add_all()?;
-        self.run(&["commit", "--fixup", target])?;
+        self.run_commit(&["--fixup", target])?;
         self.head_short()
     }
 
+    /// Rewrite the commits since `base` as the rebase todo list `todo` says
+    /// (`pick <hash>`, `fixup <hash>`, ...), as `git rebase -i` would.
+    ///
+    /// Commits that end up empty are kept, so every `pick` still makes a
+    /// commit. If the rebase stops (on a conflict, say) it is aborted,
+    /// leaving the branch as it was.
+    pub fn rebase_with_todo(&self, base: &str, todo: &str) -> Result<(), Error> {
+        let path = self.git_path("retcon-rebase-todo")?;
+        std::fs::write(&path, todo)
+            .map_err(|e| Error::Exec(format!("write {}: {e}", path.display())))?;
+        // Git hands the editor the todo file to edit; copy ours over it
+        let editor = format!("cp {}", shell_quote(&path.to_string_lossy()));
+        let env = [
+            ("GIT_SEQUENCE_EDITOR", editor.as_str()),
+            ("GIT_EDITOR", "true"),
+        ];
+        let result = self.run_output_with_env(&["rebase", "-i", "--empty=keep", base], &env);
+        let _ = std::fs::remove_file(&path);
+        if result.is_err() {
+            let _ = self.run(&["rebase", "--abort"]);
+        }
+        result.map(drop)
+    }
+
+    /// Committer time of HEAD, in seconds since the Unix epoch.
+    pub fn head_commit_time(&self) -> Result<u64, Error> {
+        let output = self.run_output(&["log", "-1", "--format=%ct", "HEAD"])?;
+        output
+            .trim()
+            .parse()
+            .map_err(|_| Error::Failed(format!("unexpected commit time '{}'", output.trim())))
+    }
+
     /// Get the short hash of HEAD.
     pub fn head_short(&self) -> Result<String, Error> {
         let hash = self.run_output(&["rev-parse", "HEAD"])?;
@@ -111,35 +833,148 @@ impl Git {
     // Internal helpers
     // -------------------------------------------------------------------------
 
-    /// Run a git command that produces no output we care about.
-    fn run(&self, args: &[&str]) -> Result<(), Error> {
-        let status = Command::new("git")
-            .args(args)
+    /// Run `git commit` with the given arguments, honoring `no_verify`.
+    fn run_commit(&self, args: &[&str]) -> Result<(), Error> {
+        let mut full = vec!["commit"];
+        if self.no_verify {
+            full.push("--no-verify");
+        }
+        full.extend_from_slice(args);
+        self.run(&full)
+    }
+
+    /// A git command in the repository root, with this handle's program and environment.
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command
             .current_dir(&self.root)
-            .status()
-            .map_err(|e| Error::Exec(format!("git {}: {e}", args.first().unwrap_or(&""))))?;
+            .envs(self.env.iter().map(|(k, v)| (k, v)));
+        command
+    }
 
-        if status.success() {
-            Ok(())
-        } else {
-            Err(Error::Failed(format!("git {}", args.join(" "))))
-        }
+    /// Run `git apply` with `args` on `patch`, passed through a scratch file.
+    fn run_apply(&self, args: &[&str], patch: &str) -> Result<(), Error> {
+        self.run_apply_with_env(args, patch, &[])
+    }
+
+    /// Like [`run_apply`](Self::run_apply), with extra environment variables.
+    fn run_apply_with_env(
+        &self,
+        args: &[&str],
+        patch: &str,
+        env: &[(&str, &str)],
+    ) -> Result<(), Error> {
+        let path = self.git_path("retcon-apply.patch")?;
+        std::fs::write(&path, patch)
+            .map_err(|e| Error::Exec(format!("write {}: {e}", path.display())))?;
+        let path_str = path.to_string_lossy();
+        let mut full = vec!["apply"];
+        full.extend_from_slice(args);
+        full.push(&path_str);
+        let result = self.run_output_with_env(&full, env).map(drop);
+        let _ = std::fs::remove_file(&path);
+        result
+    }
+
+    /// Run a git command that produces no output we care about.
+    fn run(&self, args: &[&str]) -> Result<(), Error> {
+        self.run_output(args).map(drop)
     }
 
     /// Run a git command and capture its stdout.
     fn run_output(&self, args: &[&str]) -> Result<String, Error> {
-        let output = Command::new("git")
+        self.run_output_with_env(args, &[])
+    }
+
+    /// Run a git command with extra environment variables and capture its stdout.
+    fn run_output_with_env(&self, args: &[&str], env: &[(&str, &str)]) -> Result<String, Error> {
+        let output = self
+            .command()
             .args(args)
-            .current_dir(&self.root)
+            .envs(env.iter().copied())
+            .stdin(std::process::Stdio::null())
             .output()
             .map_err(|e| Error::Exec(format!("git {}: {e}", args.first().unwrap_or(&""))))?;
 
         if output.status.success() {
             Ok(String::from_utf8_lossy(&output.stdout).to_string())
         } else {
-            Err(Error::Failed(format!("git {}", args.join(" "))))
+            Err(failure(args, &output))
+        }
+    }
+}
+
+/// `refname` peeled to the commit it names, so an annotated tag works
+/// anywhere a branch would.
+fn peeled(refname: &str) -> String {
+    format!("{refname}^{{commit}}")
+}
+
+/// Identity and dates for commits retcon makes up, so they come out the same every time.
+const FIXED_IDENT: [(&str, &str); 6] = [
+    ("GIT_AUTHOR_NAME", "retcon"),
+    ("GIT_AUTHOR_EMAIL", "retcon@localhost"),
+    ("GIT_AUTHOR_DATE", "2000-01-01T00:00:00+0000"),
+    ("GIT_COMMITTER_NAME", "retcon"),
+    ("GIT_COMMITTER_EMAIL", "retcon@localhost"),
+    ("GIT_COMMITTER_DATE", "2000-01-01T00:00:00+0000"),
+];
+
+/// Longest stderr excerpt kept in a [`Error::Failed`] message.
+const MAX_STDERR_BYTES: usize = 2000;
+
+/// Build the error for a failed git command, including what it printed to stderr.
+fn failure(args: &[&str], output: &std::process::Output) -> Error {
+    let command = format!("git {}", args.join(" "));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut stderr = stderr.trim().to_string();
+    if stderr.is_empty() {
+        return Error::Failed(command);
+    }
+    if stderr.len() > MAX_STDERR_BYTES {
+        let mut cut = MAX_STDERR_BYTES;
+        while !stderr.is_char_boundary(cut) {
+            cut -= 1;
         }
+        stderr.truncate(cut);
+        stderr.push_str("\n[... truncated]");
     }
+    Error::Failed(format!("{command}: {stderr}"))
+}
+
+/// `s` quoted for a POSIX shell, as one word.
+pub(crate) fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Set or clear the executable bits of a file on disk.
+#[cfg(unix)]
+fn set_file_executable(path: &Path, executable: bool) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    let error = |e: std::io::Error| Error::Exec(format!("chmod {}: {e}", path.display()));
+    let mut perms = std::fs::metadata(path).map_err(error)?.permissions();
+    let mode = perms.mode();
+    perms.set_mode(if executable {
+        mode | 0o111
+    } else {
+        mode & !0o111
+    });
+    std::fs::set_permissions(path, perms).map_err(error)
+}
+
+/// Without unix permissions, only the index records the executable bit.
+#[cfg(not(unix))]
+fn set_file_executable(_path: &Path, _executable: bool) -> Result<(), Error> {
+    Ok(())
+}
+
+/// A commit as listed by [`Git::log_between`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    /// Short hash of the commit
+    pub hash: String,
+    /// First line of the commit message
+    pub subject: String,
 }
 
 /// Errors from git operations.
@@ -154,3 +989,140 @@ pub enum Error {
     #[error("{0}")]
     Failed(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A repository in a scratch directory, removed again on drop.
+    struct ScratchRepo(PathBuf);
+
+    impl ScratchRepo {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("retcon-git-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            let git = Git::at(&dir);
+            git.run(&["init", "-q"]).unwrap();
+            git.run(&["config", "user.name", "Test"]).unwrap();
+            git.run(&["config", "user.email", "test@example.com"])
+                .unwrap();
+            Self(dir)
+        }
+
+        fn git(&self) -> Git {
+            Git::at(&self.0)
+        }
+    }
+
+    impl Drop for ScratchRepo {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn grep_refuses_option_shaped_rev() {
+        let repo = ScratchRepo::new("grep");
+        let git = repo.git();
+        std::fs::write(repo.0.join("a.txt"), "needle\n").unwrap();
+        git.commit("add a").unwrap();
+
+        let marker = repo.0.join("PWNED");
+        let rev = format!("--open-files-in-pager=touch {}", marker.display());
+        assert!(git.grep("needle", Some(&rev), None).is_err());
+        assert!(!marker.exists());
+
+        let matches = git.grep("needle", Some("HEAD"), None).unwrap();
+        assert_eq!(matches, ["HEAD:a.txt:1:needle"]);
+    }
+
+    #[test]
+    fn apply_3way_reports_conflicts() {
+        let repo = ScratchRepo::new("apply-3way");
+        let git = repo.git();
+        let file = repo.0.join("a.txt");
+        std::fs::write(&file, "one\ntwo\n").unwrap();
+        let base = git.commit("base").unwrap();
+        std::fs::write(&file, "one, theirs\ntwo\n").unwrap();
+        git.commit("theirs").unwrap();
+        let patch = git.diff(&base, "HEAD").unwrap();
+
+        git.run(&["reset", "-q", "--hard", &base]).unwrap();
+        std::fs::write(&file, "one, ours\ntwo\n").unwrap();
+        assert_eq!(git.apply_3way(&patch).unwrap(), ["a.txt"]);
+        assert!(std::fs::read_to_string(&file).unwrap().contains("<<<<<<<"));
+        assert_eq!(git.unmerged_paths().unwrap(), ["a.txt"]);
+    }
+
+    #[test]
+    fn snapshot_tree_leaves_the_index_alone() {
+        let repo = ScratchRepo::new("snapshot");
+        let git = repo.git();
+        std::fs::write(repo.0.join("a.txt"), "a\n").unwrap();
+        git.commit("add a").unwrap();
+        std::fs::write(repo.0.join("a.txt"), "a, edited\n").unwrap();
+        std::fs::write(repo.0.join("new.txt"), "new\n").unwrap();
+
+        let tree = git.snapshot_tree().unwrap();
+        assert_eq!(git.staged_diff().unwrap(), "");
+        let listed = git.run_output(&["ls-tree", "--name-only", &tree]).unwrap();
+        assert_eq!(listed, "a.txt\nnew.txt\n");
+    }
+
+    #[test]
+    fn shell_quote_survives_the_shell() {
+        let path = "it's a $file; `x`.txt";
+        let output = Command::new("sh")
+            .args(["-c", &format!("printf %s {}", shell_quote(path))])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), path);
+    }
+
+    #[test]
+    fn commit_patch_builds_on_the_parent() {
+        let repo = ScratchRepo::new("commit-patch");
+        let git = repo.git();
+        std::fs::write(repo.0.join("a.txt"), "a\n").unwrap();
+        let base = git.commit("add a").unwrap();
+        std::fs::write(repo.0.join("a.txt"), "a, edited\n").unwrap();
+        git.commit("edit a").unwrap();
+        let patch = git.tree_patch(&git.tree(&base).unwrap(), &git.tree("HEAD").unwrap());
+        git.run(&["reset", "-q", "--hard", &base]).unwrap();
+
+        let commit = git
+            .commit_patch(&base, &patch.unwrap(), "edit a again")
+            .unwrap();
+        assert_eq!(git.tree(&commit).unwrap(), git.tree("HEAD@{1}").unwrap());
+        assert_eq!(git.commit_message(&commit).unwrap(), "edit a again\n\n");
+        assert!(git.rev_parse("HEAD").unwrap().starts_with(&base));
+    }
+
+    #[test]
+    fn whitespace_only_changes_only_lists_text_edits() {
+        let repo = ScratchRepo::new("whitespace");
+        let git = repo.git();
+        let write =
+            |path: &str, content: &[u8]| std::fs::write(repo.0.join(path), content).unwrap();
+        write("spaces.txt", b"a b\n");
+        write("eol.txt", b"x\n");
+        write("real.txt", b"x\n");
+        write("bin", b"\0one");
+        write("gone.txt", b"\n");
+        write("mode.sh", b"x\n");
+        let before = git.commit("before").unwrap();
+        write("spaces.txt", b"a  b\n");
+        write("eol.txt", b"x");
+        write("real.txt", b"y\n");
+        write("bin", b"\0two");
+        write("empty.txt", b"");
+        std::fs::remove_file(repo.0.join("gone.txt")).unwrap();
+        set_file_executable(&repo.0.join("mode.sh"), true).unwrap();
+        git.commit("after").unwrap();
+
+        let ignored = git.whitespace_only_changes(&before, "HEAD").unwrap();
+        assert_eq!(ignored, ["eol.txt", "spaces.txt"]);
+    }
+}
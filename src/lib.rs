@@ -9,17 +9,34 @@
 //! - **Spec**: Parse and manipulate history specifications
 //! - **Execute**: Run the reconstruction loop with LLM assistance
 //! - **Prompt**: Generate guidance for creating specifications
+//! - **Detect**: Infer default build/test commands from the repository
 
+mod detect;
 mod execute;
 mod git;
+mod manifest;
 mod prompt;
+mod record;
+mod script;
 mod spec;
+mod throttle;
+mod tools;
 pub mod tui;
+mod util;
 
+pub use detect::{DefaultCommands, default_commands};
 pub use execute::{
-    CommitStatus, ExecuteConfig, ExecuteHooks, NoOpHooks, PrintHooks, execute,
-    execute_with_connection, execute_with_hooks,
+    Approval, CommitStatus, DEFAULT_CATCHALL_MESSAGE, Direction, Error as ExecuteError,
+    ExecuteConfig, ExecuteHooks, ExtractionPlan, NoOpHooks, PlannedChange, PlannedCommit,
+    PrintHooks, cleaned_history, execute, execute_with_connection, execute_with_hooks,
+    plan_extraction, remaining_diff, remaining_is_empty, write_spec,
 };
-pub use git::Git;
+pub use git::{Git, LogEntry};
+pub use manifest::{MANIFEST_FILE, Manifest, ManifestCommit};
 pub use prompt::prompt;
-pub use spec::{CommitSpec, HistoryEntry, HistorySpec};
+pub use record::Recording;
+pub use spec::{
+    Base, CherryPick, CommitSpec, HintFeedback, Hints, HistoryEntry, HistorySpec, StructuredHints,
+    StuckKind, StuckReason, ValidationError, move_commit,
+};
+pub use throttle::RateLimit;
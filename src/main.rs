@@ -1,6 +1,7 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use anyhow::Context;
 use clap::{Parser, Subcommand};
 use serde::Deserialize;
 
@@ -12,6 +13,8 @@ struct Cli {
     command: Command,
 }
 
+// Parsed once at startup, so the size of the Execute variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Command {
     /// Emit LLM guidance for creating a history specification
@@ -22,21 +25,240 @@ enum Command {
         /// Path to the history specification TOML file
         plan: PathBuf,
 
+        /// Record execution history in PATH instead of the plan, and resume from it if it exists
+        #[arg(long, value_name = "PATH")]
+        output_spec: Option<PathBuf>,
+
         /// Agent command to use for LLM work (e.g. "npx -y @zed-industries/claude-code-acp@latest")
         #[arg(long)]
         agent: Option<String>,
 
-        /// Build command to run after each commit (default: cargo check --all --workspace)
+        /// Build command to run after each commit (default: detected from Cargo.toml)
         #[arg(long)]
         build_command: Option<String>,
 
-        /// Test command to run after build passes (default: cargo test --all --workspace)
+        /// Build and test with CARGO_TARGET_DIR set to PATH (default: retcon-target in the git directory)
+        #[arg(long, value_name = "PATH", require_equals = true)]
+        build_target_dir: Option<Option<PathBuf>>,
+
+        /// Test command to run after build passes (default: detected from Cargo.toml)
         #[arg(long)]
         test_command: Option<String>,
 
+        /// Command to run after each commit (e.g. "cargo fmt"); its changes are amended into the commit
+        #[arg(long, value_name = "CMD")]
+        post_commit_command: Option<String>,
+
+        /// Command to run after each commit completes or gets stuck (sees RETCON_COMMIT_INDEX, RETCON_STATUS, RETCON_HASH)
+        #[arg(long, value_name = "CMD")]
+        on_commit_command: Option<String>,
+
         /// Skip build or test step (can be specified multiple times)
         #[arg(long = "skip", value_name = "STEP")]
         skip: Vec<SkipStep>,
+
+        /// Keep going with later commits when one gets stuck
+        #[arg(long)]
+        continue_on_stuck: bool,
+
+        /// With --continue-on-stuck, give up after N consecutive stuck commits
+        #[arg(long, value_name = "N", requires = "continue_on_stuck")]
+        max_consecutive_stuck: Option<usize>,
+
+        /// With --continue-on-stuck, write the stuck commits and their reasons to PATH (.json for JSON)
+        #[arg(long, value_name = "PATH", requires = "continue_on_stuck")]
+        stuck_report: Option<PathBuf>,
+
+        /// Only print how the LLM would split the changes among the commits, without applying them
+        #[arg(long)]
+        dry_extract: bool,
+
+        /// Once all commits are reconstructed, only print where the LLM would put the leftover changes
+        #[arg(long, conflicts_with = "confirm_finalize")]
+        dry_finalize: bool,
+
+        /// Show where the LLM would put the leftover changes and ask before committing them
+        #[arg(long)]
+        confirm_finalize: bool,
+
+        /// Check that the agent accepts retcon's tools before starting
+        #[arg(long)]
+        preflight: bool,
+
+        /// Fetch the spec's remote before computing the merge-base
+        #[arg(long)]
+        fetch: bool,
+
+        /// Show the LLM the source branch's original commits when extracting each commit
+        #[arg(long)]
+        show_original_commits: bool,
+
+        /// Reconstruct newest commit first, peeling changes off the source
+        #[arg(long)]
+        reverse: bool,
+
+        /// Mark a commit stuck if its first extraction fails, rather than adding fix commits
+        #[arg(long)]
+        strict_no_wip: bool,
+
+        /// Only reconstruct pending commits whose message matches this regex
+        #[arg(long, value_name = "REGEX", conflicts_with = "reverse")]
+        filter_message: Option<regex::Regex>,
+
+        /// Only reconstruct the changes to this file, leaving the rest of the tree as in the base (needs --output-spec)
+        #[arg(long, value_name = "PATH", requires = "output_spec")]
+        file: Option<String>,
+
+        /// Discard previous progress and rebuild the cleaned branch from the base
+        #[arg(long)]
+        fresh: bool,
+
+        /// Extra attempts at sorting leftover changes into fixup commits before the catchall commit
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        catchall_retries: usize,
+
+        /// Message for the catchall commit (`{last}` is the last commit's subject, e.g. "squash! {last}")
+        #[arg(long, value_name = "MESSAGE")]
+        catchall_message: Option<String>,
+
+        /// After the spec's commits, put all remaining changes in one commit with this message
+        #[arg(
+            long,
+            value_name = "MESSAGE",
+            conflicts_with_all = ["dry_finalize", "confirm_finalize", "catchall_message"]
+        )]
+        remainder_commit: Option<String>,
+
+        /// Undo a resolved stuck commit's earlier commits before retrying it
+        #[arg(long)]
+        retry_clean: bool,
+
+        /// Skip pre-commit and commit-msg hooks for the commits retcon creates
+        #[arg(long)]
+        no_verify: bool,
+
+        /// Run this git executable instead of the `git` on PATH
+        #[arg(long, value_name = "PATH")]
+        git_binary: Option<PathBuf>,
+
+        /// Wait between commits so that no two share a timestamp
+        #[arg(long)]
+        distinct_commit_times: bool,
+
+        /// Tag the cleaned branch's base commit as `<cleaned>-base`
+        #[arg(long)]
+        tag_base: bool,
+
+        /// When done, fold the fixup commits into the commits they fix
+        #[arg(long)]
+        autosquash: bool,
+
+        /// Also write each completed commit as a .patch file into this directory
+        #[arg(long, value_name = "DIR")]
+        patch_dir: Option<PathBuf>,
+
+        /// When done, write `git range-diff` of source against the cleaned branch to PATH (`-` for stdout)
+        #[arg(long, value_name = "PATH")]
+        range_diff: Option<PathBuf>,
+
+        /// Show only the last N lines of build and test output (the LLM still sees its own excerpt)
+        #[arg(long, value_name = "N")]
+        output_lines: Option<usize>,
+
+        /// Wait at least this many seconds between requests to the agent
+        #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+        commit_delay: Option<std::time::Duration>,
+
+        /// Send at most N requests to the agent in any one minute
+        #[arg(long, value_name = "N")]
+        max_requests_per_minute: Option<usize>,
+
+        /// Truncate files read through the agent's read_file tool to this many bytes
+        #[arg(long, value_name = "BYTES")]
+        max_read_file_bytes: Option<usize>,
+
+        /// Split extraction into several prompts when the remaining diff is over this many tokens
+        #[arg(long, value_name = "TOKENS")]
+        max_prompt_tokens: Option<usize>,
+
+        /// Write a shell script (plus patches) that creates the cleaned branch, instead of creating it
+        #[arg(long, value_name = "PATH")]
+        emit_script: Option<PathBuf>,
+
+        /// Mark a commit stuck if it adds files the target doesn't have, instead of warning
+        #[arg(long)]
+        stuck_on_stray_files: bool,
+
+        /// Treat the branch as matching the source if the only differences left are whitespace
+        #[arg(long)]
+        ignore_whitespace: bool,
+
+        /// With --emit-script, delete anything in the way of the scratch worktree
+        #[arg(long, requires = "emit_script")]
+        worktree_force: bool,
+
+        /// Show only retcon's own progress, not the agent's prompts, replies, and tool calls
+        #[arg(long)]
+        quiet_agent: bool,
+
+        /// Save every agent request's result and working tree changes to PATH
+        #[arg(long, value_name = "PATH", conflicts_with = "replay")]
+        record: Option<PathBuf>,
+
+        /// Take agent results and changes from a file written by --record, instead of the agent
+        #[arg(long, value_name = "PATH")]
+        replay: Option<PathBuf>,
+    },
+
+    /// Check that a history specification is valid, without touching any repository
+    Check {
+        /// Path to the history specification TOML file
+        plan: PathBuf,
+
+        /// Also fail if the spec has more than N commits
+        #[arg(long, value_name = "N")]
+        max_commits_allowed: Option<usize>,
+    },
+
+    /// List every commit in a history specification with its status and hints
+    List {
+        /// Path to the history specification TOML file
+        plan: PathBuf,
+    },
+
+    /// Compare the outcomes recorded in two runs of a history specification
+    Compare {
+        /// The spec from the earlier run
+        old: PathBuf,
+
+        /// The spec from the later run
+        new: PathBuf,
+    },
+
+    /// Recreate a branch from the patches and manifest written by `execute --patch-dir`
+    ApplyManifest {
+        /// The patch directory, or the manifest.json inside it
+        manifest: PathBuf,
+    },
+
+    /// Move a commit to a different position in a history specification
+    Reorder {
+        /// Path to the history specification TOML file
+        plan: PathBuf,
+
+        /// Position of the commit to move (1-indexed, as shown by `retcon list`)
+        #[arg(long = "move", value_name = "N")]
+        from: usize,
+
+        /// Position to move it to (1-indexed)
+        #[arg(long, value_name = "M")]
+        to: usize,
+    },
+
+    /// List the commits in a history specification that are stuck
+    Stuck {
+        /// Path to the history specification TOML file
+        plan: PathBuf,
     },
 }
 
@@ -51,6 +273,49 @@ enum SkipStep {
 struct Config {
     #[serde(default)]
     agent: Option<String>,
+    /// Prompt text for passing a resolution note to the LLM (`{note}` is substituted)
+    #[serde(default)]
+    resolution_template: Option<String>,
+    /// Most commits `retcon check` accepts in a spec
+    #[serde(default)]
+    max_commits_allowed: Option<usize>,
+}
+
+/// Parse a (possibly fractional) number of seconds.
+fn parse_seconds(s: &str) -> Result<std::time::Duration, String> {
+    let secs: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    std::time::Duration::try_from_secs_f64(secs).map_err(|e| format!("{e}"))
+}
+
+/// A one-word summary of where a commit's reconstruction stands.
+fn commit_status(commit: &retcon::CommitSpec) -> &'static str {
+    if commit.is_complete() {
+        "complete"
+    } else if commit.is_stuck() {
+        "stuck"
+    } else if commit.is_resolved() {
+        "resolved"
+    } else if commit.history.is_empty() {
+        "pending"
+    } else {
+        "in progress"
+    }
+}
+
+/// The files changed by the commits recorded for `commit`, if they're in the repository.
+fn commit_files(git: &retcon::Git, commit: &retcon::CommitSpec) -> Option<Vec<String>> {
+    let first = commit.first_commit()?;
+    let last = commit.last_commit()?;
+    git.changed_paths(&format!("{first}^"), last, "ACDMRT").ok()
+}
+
+/// Read and parse a history specification from disk.
+fn load_spec(plan: &Path) -> anyhow::Result<retcon::HistorySpec> {
+    let content = std::fs::read_to_string(plan)
+        .with_context(|| format!("failed to read spec file '{}'", plan.display()))?;
+    let spec = retcon::HistorySpec::from_toml(&content)
+        .with_context(|| format!("failed to parse spec file '{}'", plan.display()))?;
+    Ok(spec)
 }
 
 fn load_config() -> Config {
@@ -75,30 +340,309 @@ async fn main() -> anyhow::Result<()> {
         }
         Command::Execute {
             plan,
+            output_spec,
             agent,
             build_command,
+            build_target_dir,
             test_command,
             skip,
+            continue_on_stuck,
+            max_consecutive_stuck,
+            stuck_report,
+            dry_extract,
+            dry_finalize,
+            confirm_finalize,
+            preflight,
+            fetch,
+            show_original_commits,
+            reverse,
+            strict_no_wip,
+            filter_message,
+            file,
+            fresh,
+            catchall_retries,
+            catchall_message,
+            remainder_commit,
+            retry_clean,
+            no_verify,
+            git_binary,
+            distinct_commit_times,
+            tag_base,
+            autosquash,
+            patch_dir,
+            range_diff,
+            output_lines,
+            post_commit_command,
+            on_commit_command,
+            commit_delay,
+            max_requests_per_minute,
+            max_read_file_bytes,
+            max_prompt_tokens,
+            emit_script,
+            stuck_on_stray_files,
+            ignore_whitespace,
+            worktree_force,
+            quiet_agent,
+            record,
+            replay,
         } => {
+            let git_program = git_binary.as_deref().unwrap_or(Path::new("git"));
+            let defaults = retcon::Git::discover_with_program(&plan, git_program)
+                .map(|git| retcon::default_commands(git.root()))
+                .unwrap_or_default();
+            let build_command = build_command.or(defaults.build);
+            let build_target_dir = match build_target_dir {
+                Some(Some(dir)) => Some(
+                    std::path::absolute(&dir)
+                        .with_context(|| format!("failed to resolve '{}'", dir.display()))?,
+                ),
+                Some(None) => Some(
+                    retcon::Git::discover_with_program(&plan, git_program)
+                        .and_then(|git| git.git_path("retcon-target"))
+                        .context("failed to find the git directory for --build-target-dir")?,
+                ),
+                None => None,
+            };
+            let skip_build = skip.contains(&SkipStep::Build);
+            let recording = match (record, replay) {
+                (Some(path), _) => retcon::Recording::record(&path)
+                    .with_context(|| format!("failed to create '{}'", path.display()))?,
+                (None, Some(path)) => retcon::Recording::replay(&path)
+                    .with_context(|| format!("failed to read recording '{}'", path.display()))?,
+                (None, None) => retcon::Recording::default(),
+            };
             let config = retcon::ExecuteConfig {
-                build_command: if skip.contains(&SkipStep::Build) {
-                    None
-                } else {
-                    Some(
-                        build_command
-                            .unwrap_or_else(|| "cargo check --all --workspace".to_string()),
-                    )
-                },
+                build_command: build_command.clone().filter(|_| !skip_build),
+                skipped_build_command: build_command.filter(|_| skip_build),
                 test_command: if skip.contains(&SkipStep::Test) {
                     None
                 } else {
-                    Some(test_command.unwrap_or_else(|| "cargo test --all --workspace".to_string()))
+                    test_command.or(defaults.test)
                 },
+                build_target_dir,
+                post_commit_command,
+                on_commit_command,
                 agent: agent.or(config_file.agent),
+                continue_on_stuck,
+                max_consecutive_stuck,
+                stuck_report,
+                strict_no_wip,
+                filter_message,
+                file,
+                dry_extract,
+                dry_finalize,
+                confirm_finalize,
+                fetch,
+                show_original_commits,
+                preflight,
+                direction: if reverse {
+                    retcon::Direction::Reverse
+                } else {
+                    retcon::Direction::Forward
+                },
+                fresh,
+                catchall_retries,
+                catchall_message,
+                remainder_commit,
+                retry_clean,
+                no_verify,
+                git_binary,
+                distinct_commit_times,
+                tag_base,
+                autosquash,
+                output_spec: output_spec.clone(),
+                patch_dir,
+                range_diff,
+                resolution_template: config_file.resolution_template,
+                output_lines,
+                rate_limit: retcon::RateLimit::new(commit_delay, max_requests_per_minute),
+                recording,
+                max_read_file_bytes,
+                max_prompt_tokens,
+                emit_script,
+                stuck_on_stray_files,
+                ignore_whitespace,
+                worktree_force,
             };
 
-            let (observer, hooks) = retcon::tui::new();
-            retcon::execute_with_hooks(&plan, &config, &hooks, Some(Arc::new(observer))).await?;
+            let (mut observer, hooks) = retcon::tui::new();
+            if quiet_agent {
+                observer = observer.quiet();
+            }
+            if let Err((spec, e)) =
+                retcon::execute_with_hooks(&plan, &config, &hooks, Some(Arc::new(observer))).await
+            {
+                if let Some(spec) = spec {
+                    let complete = spec.commits.iter().filter(|c| c.is_complete()).count();
+                    eprintln!(
+                        "{complete}/{} commits complete; progress is saved in {}",
+                        spec.commits.len(),
+                        output_spec.as_deref().unwrap_or(&plan).display()
+                    );
+                }
+                return Err(e.into());
+            }
+        }
+        Command::Check {
+            plan,
+            max_commits_allowed,
+        } => {
+            let spec = load_spec(&plan)?;
+            spec.validate()
+                .with_context(|| format!("invalid spec file '{}'", plan.display()))?;
+            if let Some(max) = max_commits_allowed.or(config_file.max_commits_allowed) {
+                spec.check_commit_count(max)
+                    .with_context(|| format!("invalid spec file '{}'", plan.display()))?;
+            }
+            println!(
+                "{}: {} commit(s), valid",
+                plan.display(),
+                spec.commits.len()
+            );
+        }
+        Command::List { plan } => {
+            let spec = load_spec(&plan)?;
+            let total = spec.commits.len();
+            for (idx, commit) in spec.commits.iter().enumerate() {
+                let status = commit_status(commit);
+                println!("{:>3}/{total} [{status}] {}", idx + 1, commit.subject());
+                if let Some(hints) = &commit.hints {
+                    for line in hints.render().lines() {
+                        println!("        {line}");
+                    }
+                }
+            }
+        }
+        Command::Compare { old, new } => {
+            let old_spec = load_spec(&old)?;
+            let new_spec = load_spec(&new)?;
+            // Without the repository, only statuses and hashes are compared
+            let git = retcon::Git::discover(&new).ok();
+            let total = new_spec.commits.len();
+            let mut changed = 0;
+            for (idx, commit) in new_spec.commits.iter().enumerate() {
+                let Some(before) = old_spec
+                    .commits
+                    .iter()
+                    .find(|c| c.label() == commit.label())
+                else {
+                    changed += 1;
+                    println!("Commit {}/{total}: {}", idx + 1, commit.subject());
+                    println!("  only in {}", new.display());
+                    continue;
+                };
+
+                let mut lines = Vec::new();
+                let (was, now) = (commit_status(before), commit_status(commit));
+                if was != now {
+                    lines.push(format!("status: {was} -> {now}"));
+                }
+                let (was, now) = (before.last_commit(), commit.last_commit());
+                if was != now {
+                    lines.push(format!(
+                        "commit: {} -> {}",
+                        was.unwrap_or("none"),
+                        now.unwrap_or("none")
+                    ));
+                    if let Some(git) = &git
+                        && let (Some(was), Some(now)) =
+                            (commit_files(git, before), commit_files(git, commit))
+                    {
+                        let added: Vec<_> = now.iter().filter(|f| !was.contains(f)).collect();
+                        let dropped: Vec<_> = was.iter().filter(|f| !now.contains(f)).collect();
+                        for file in added {
+                            lines.push(format!("+ {file}"));
+                        }
+                        for file in dropped {
+                            lines.push(format!("- {file}"));
+                        }
+                    }
+                }
+                if !lines.is_empty() {
+                    changed += 1;
+                    println!("Commit {}/{total}: {}", idx + 1, commit.subject());
+                    for line in lines {
+                        println!("  {line}");
+                    }
+                }
+            }
+            let old_total = old_spec.commits.len();
+            for (idx, commit) in old_spec.commits.iter().enumerate() {
+                if !new_spec.commits.iter().any(|c| c.label() == commit.label()) {
+                    changed += 1;
+                    println!("Commit {}/{old_total}: {}", idx + 1, commit.subject());
+                    println!("  only in {}", old.display());
+                }
+            }
+            if changed == 0 {
+                println!("No differences in outcome.");
+            }
+        }
+        Command::ApplyManifest { manifest } => {
+            let path = if manifest.is_dir() {
+                manifest.join(retcon::MANIFEST_FILE)
+            } else {
+                manifest
+            };
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read manifest '{}'", path.display()))?;
+            let parsed: retcon::Manifest = serde_json::from_str(&content)
+                .with_context(|| format!("failed to parse manifest '{}'", path.display()))?;
+            let git = retcon::Git::discover(Path::new("."))?;
+            let dir = path.parent().unwrap_or(Path::new("."));
+            parsed.apply(&git, dir)?;
+            println!(
+                "Created branch '{}' with the tree of the reconstruction",
+                parsed.branch
+            );
+        }
+        Command::Reorder { plan, from, to } => {
+            let spec = load_spec(&plan)?;
+            let total = spec.commits.len();
+            for n in [from, to] {
+                anyhow::ensure!(
+                    (1..=total).contains(&n),
+                    "position {n} is out of range; the spec has {total} commit(s)"
+                );
+            }
+            let content = std::fs::read_to_string(&plan)
+                .with_context(|| format!("failed to read spec file '{}'", plan.display()))?;
+            let updated = retcon::move_commit(&content, from - 1, to - 1)
+                .context("failed to edit the spec's commits")?;
+            let reordered = retcon::HistorySpec::from_toml(&updated)
+                .context("failed to parse the reordered spec")?;
+            reordered
+                .validate()
+                .context("the new order is not valid; nothing was changed")?;
+
+            let moved = &spec.commits[from - 1];
+            let (lo, hi) = (from.min(to) - 1, from.max(to) - 1);
+            if spec.commits[lo..=hi].iter().any(|c| !c.history.is_empty()) {
+                eprintln!(
+                    "Note: commits that were already reconstructed changed position; \
+                     rerun `retcon execute` with --fresh to rebuild the cleaned branch"
+                );
+            }
+            retcon::write_spec(&plan, &updated)?;
+            println!("Moved commit {from} to position {to}: {}", moved.subject());
+        }
+        Command::Stuck { plan } => {
+            let spec = load_spec(&plan)?;
+            let total = spec.commits.len();
+            let mut any_stuck = false;
+            for (idx, commit) in spec.commits.iter().enumerate() {
+                if let Some(reason) = commit.stuck_reason() {
+                    any_stuck = true;
+                    println!("Commit {}/{}: {}", idx + 1, total, commit.subject());
+                    match commit.stuck_kind() {
+                        Some(kind) => println!("  ✗ Stuck ({}): {reason}", kind.as_str()),
+                        None => println!("  ✗ Stuck: {reason}"),
+                    }
+                }
+            }
+            if !any_stuck {
+                println!("No stuck commits.");
+            }
         }
     }
 
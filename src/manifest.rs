@@ -0,0 +1,83 @@
+//! A reconstruction exported as plain patches, replayable without the LLM.
+//!
+//! With `--patch-dir`, every logical commit is written as soon as it
+//! completes. Once the whole run matches the target, the patches are
+//! rewritten from the branch and a `manifest.json` next to them records
+//! their order, the base they apply to, and the tree they must produce. [`Manifest::apply`] (`retcon apply-manifest`)
+//! recreates the branch from them with nothing but `git am`.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::git::{self, Git};
+
+/// File name of the manifest inside the patch directory.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// The order and expected outcome of a directory of exported patches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Branch the patches recreate
+    pub branch: String,
+    /// Commit the first patch applies to, or `None` for a branch with no
+    /// history in common with anything else
+    pub base: Option<String>,
+    /// The branch the reconstruction was made from
+    pub source: String,
+    /// Tree the branch must end up with, to check the replay against
+    pub tree: String,
+    /// The logical commits, in order
+    pub commits: Vec<ManifestCommit>,
+    /// Fixup and catchall commits made after the last logical commit, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<String>,
+}
+
+/// One logical commit in a [`Manifest`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestCommit {
+    /// The commit message from the spec
+    pub message: String,
+    /// Patch file for this commit, relative to the manifest; `None` if it
+    /// ended up with no changes of its own
+    pub patch: Option<String>,
+}
+
+impl Manifest {
+    /// Create the manifest's branch in `git` and apply its patches from `dir`.
+    ///
+    /// Fails without touching anything if the branch already exists. If a
+    /// patch doesn't apply, `git am` is aborted and the branch is left at
+    /// the last patch that did.
+    pub fn apply(&self, git: &Git, dir: &Path) -> Result<(), git::Error> {
+        if git.ref_exists(&format!("refs/heads/{}", self.branch)) {
+            return Err(git::Error::Failed(format!(
+                "branch '{}' already exists; delete it or rename it first",
+                self.branch
+            )));
+        }
+        match &self.base {
+            Some(base) => git.checkout_new_branch(&self.branch, base)?,
+            None => git.checkout_orphan(&self.branch)?,
+        }
+
+        let patches = self
+            .commits
+            .iter()
+            .filter_map(|c| c.patch.as_deref())
+            .chain(self.remaining.as_deref());
+        for patch in patches {
+            git.am(&dir.join(patch))?;
+        }
+
+        let tree = git.tree("HEAD")?;
+        if tree != self.tree {
+            return Err(git::Error::Failed(format!(
+                "branch '{}' has tree {tree} after applying the patches, but the manifest expects {}",
+                self.branch, self.tree
+            )));
+        }
+        Ok(())
+    }
+}
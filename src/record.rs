@@ -0,0 +1,135 @@
+//! Record what the agent does during a run, and replay it in a later one.
+//!
+//! Each `d.think()` call changes the working tree through the agent's own
+//! tools, may commit through retcon's tools, and returns a structured
+//! result. In record mode all three are saved, one JSON line per request.
+//! In replay mode they are read back in order, the saved commits are made
+//! again and the saved changes applied, and the agent is never asked, so a
+//! run can be repeated exactly while chasing a bug on retcon's side.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+/// Where agent results come from, and whether they are saved.
+///
+/// Clones share the same file and position, so a copied
+/// [`ExecuteConfig`](crate::ExecuteConfig) still records or replays a
+/// single sequence.
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    mode: Mode,
+}
+
+#[derive(Debug, Clone, Default)]
+enum Mode {
+    /// Talk to the agent and keep nothing
+    #[default]
+    Off,
+    /// Talk to the agent and append each request to a file
+    Record {
+        path: PathBuf,
+        file: Arc<Mutex<File>>,
+    },
+    /// Take each request's outcome from a file instead of the agent
+    Replay {
+        path: PathBuf,
+        entries: Arc<Mutex<VecDeque<Entry>>>,
+    },
+}
+
+/// One agent request as it was recorded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    /// The kind of result the request asked for
+    pub(crate) kind: String,
+    /// The structured result the agent returned
+    pub(crate) result: serde_json::Value,
+    /// The changes the agent made to the working tree, as a binary git diff
+    pub(crate) patch: String,
+    /// The commits made while the request ran, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) commits: Vec<Commit>,
+}
+
+/// A commit made while an agent request ran.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Commit {
+    /// The commit's full message
+    pub(crate) message: String,
+    /// The changes from its parent's tree, as a binary git diff
+    pub(crate) patch: String,
+}
+
+impl Recording {
+    /// Record every agent request to `path`, replacing what was there.
+    pub fn record(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let file = File::create(&path)?;
+        Ok(Self {
+            mode: Mode::Record {
+                path,
+                file: Arc::new(Mutex::new(file)),
+            },
+        })
+    }
+
+    /// Replay the requests recorded in `path` instead of asking the agent.
+    pub fn replay(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let content = std::fs::read_to_string(&path)?;
+        let entries = content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<VecDeque<Entry>, _>>()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok(Self {
+            mode: Mode::Replay {
+                path,
+                entries: Arc::new(Mutex::new(entries)),
+            },
+        })
+    }
+
+    /// The file being recorded to or replayed from.
+    pub(crate) fn path(&self) -> Option<&Path> {
+        match &self.mode {
+            Mode::Off => None,
+            Mode::Record { path, .. } | Mode::Replay { path, .. } => Some(path),
+        }
+    }
+
+    /// Whether requests are being saved.
+    pub(crate) fn is_recording(&self) -> bool {
+        matches!(self.mode, Mode::Record { .. })
+    }
+
+    /// Whether requests are answered from a recording.
+    pub(crate) fn is_replaying(&self) -> bool {
+        matches!(self.mode, Mode::Replay { .. })
+    }
+
+    /// Append `entry` to the recording, if recording.
+    pub(crate) fn save(&self, entry: &Entry) -> std::io::Result<()> {
+        let Mode::Record { file, .. } = &self.mode else {
+            return Ok(());
+        };
+        let line = serde_json::to_string(entry)?;
+        let mut file = file.lock().unwrap();
+        writeln!(file, "{line}")?;
+        file.flush()
+    }
+
+    /// Take the next recorded request, if replaying and any are left.
+    pub(crate) fn next(&self) -> Option<Entry> {
+        let Mode::Replay { entries, .. } = &self.mode else {
+            return None;
+        };
+        entries.lock().unwrap().pop_front()
+    }
+}
@@ -0,0 +1,231 @@
+//! Emit a reviewable shell script instead of mutating the user's branches.
+//!
+//! In script mode the reconstruction runs in a scratch worktree on a detached
+//! HEAD, so no branch in the repository is created or moved. When the run
+//! completes, the reconstructed commits are exported as patches and a shell
+//! script is written that recreates the cleaned branch from them.
+
+use std::path::{Path, PathBuf};
+
+use crate::git::{self, Git};
+
+/// A scratch worktree used to reconstruct history for `--emit-script`.
+///
+/// The worktree lives next to the script (`<script>.work`) and survives
+/// across runs, so a stuck reconstruction can be resumed like a normal one.
+/// It must be outside the repository's own working tree.
+pub(crate) struct ScriptWorkspace {
+    script_path: PathBuf,
+    worktree: Git,
+}
+
+impl ScriptWorkspace {
+    /// Open the scratch worktree for `script_path`, creating it at `base` if needed.
+    ///
+    /// Anything else already at the worktree path is an error unless `force`
+    /// is set, in which case it is deleted. With `force`, an existing worktree
+    /// is reused but its uncommitted changes are discarded.
+    ///
+    /// Returns the workspace and whether it was freshly created.
+    pub(crate) fn open_or_create(
+        git: &Git,
+        script_path: &Path,
+        base: &str,
+        force: bool,
+    ) -> Result<(Self, bool), git::Error> {
+        // git runs from the repository root, so relative paths must be resolved first
+        let script_path = std::path::absolute(script_path)
+            .map_err(|e| git::Error::Exec(format!("resolve {}: {e}", script_path.display())))?;
+        let worktree_path = worktree_path(&script_path);
+        reject_inside_repo(git, &worktree_path)?;
+
+        // A worktree whose directory was deleted (say, after a crash) stays
+        // registered until pruned, and would block creating a new one there
+        git.worktree_prune()?;
+        let registered = is_registered_worktree(git, &worktree_path)?;
+        let created = !registered;
+        if created {
+            if !is_empty_dir(&worktree_path) {
+                if !force {
+                    return Err(git::Error::Failed(format!(
+                        "{} already exists and is not a retcon worktree; \
+                         remove it or pass --worktree-force",
+                        worktree_path.display()
+                    )));
+                }
+                remove_path(&worktree_path)?;
+            }
+            git.worktree_add_detached(&worktree_path, base)?;
+        }
+        let root = worktree_path
+            .canonicalize()
+            .map_err(|e| git::Error::Exec(format!("resolve {}: {e}", worktree_path.display())))?;
+        let worktree = git.for_worktree(root);
+        if registered && force {
+            worktree.discard_changes()?;
+            worktree.clean_untracked()?;
+        }
+        Ok((
+            Self {
+                script_path,
+                worktree,
+            },
+            created,
+        ))
+    }
+
+    /// Remove the scratch worktree for `script_path`, if there is one.
+    pub(crate) fn discard(git: &Git, script_path: &Path) -> Result<(), git::Error> {
+        let script_path = std::path::absolute(script_path)
+            .map_err(|e| git::Error::Exec(format!("resolve {}: {e}", script_path.display())))?;
+        let worktree_path = worktree_path(&script_path);
+        if worktree_path.exists() {
+            git.worktree_remove(&worktree_path)?;
+        }
+        Ok(())
+    }
+
+    /// The git handle for the scratch worktree, where all work happens.
+    pub(crate) fn git(&self) -> &Git {
+        &self.worktree
+    }
+
+    /// Export the reconstructed commits and write the script.
+    ///
+    /// The patches go in `<script stem>-patches/` next to the script. Once
+    /// the script is written the scratch worktree is removed.
+    pub(crate) fn finish(self, git: &Git, base: &str, cleaned: &str) -> Result<(), git::Error> {
+        let dir = self
+            .script_path
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let stem = self
+            .script_path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "retcon".to_string());
+        let patch_dir_name = format!("{stem}-patches");
+        let patch_dir = dir.join(&patch_dir_name);
+
+        // Start from an empty patch directory so stale patches aren't replayed
+        let _ = std::fs::remove_dir_all(&patch_dir);
+        std::fs::create_dir_all(&patch_dir)
+            .map_err(|e| git::Error::Exec(format!("create {}: {e}", patch_dir.display())))?;
+        let patch_dir = patch_dir
+            .canonicalize()
+            .map_err(|e| git::Error::Exec(format!("resolve {}: {e}", patch_dir.display())))?;
+        let patches = self.worktree.format_patch(base, "HEAD", &patch_dir)?;
+
+        let mut script = String::new();
+        script.push_str("#!/bin/sh\n");
+        script.push_str("# Generated by `retcon execute --emit-script`.\n");
+        script.push_str(&format!(
+            "# Recreates branch '{cleaned}' from {base} using the patches in {patch_dir_name}/.\n"
+        ));
+        script.push_str("set -e\n");
+        script.push_str("here=\"$(cd \"$(dirname \"$0\")\" && pwd)\"\n");
+        script.push_str(&format!(
+            "git checkout -b {} {}\n",
+            shell_quote(cleaned),
+            shell_quote(base)
+        ));
+        for patch in &patches {
+            let name = patch
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+            script.push_str(&format!(
+                "git am \"$here\"/{}\n",
+                shell_quote(&format!("{patch_dir_name}/{name}"))
+            ));
+        }
+
+        std::fs::write(&self.script_path, script)
+            .map_err(|e| git::Error::Exec(format!("write {}: {e}", self.script_path.display())))?;
+        make_executable(&self.script_path);
+
+        git.worktree_remove(self.worktree.root())?;
+        Ok(())
+    }
+}
+
+/// Location of the scratch worktree for a script.
+fn worktree_path(script_path: &Path) -> PathBuf {
+    let mut path = script_path.as_os_str().to_owned();
+    path.push(".work");
+    PathBuf::from(path)
+}
+
+/// Refuse a worktree path inside the repository's working tree.
+///
+/// A nested worktree would show up as untracked content in the main
+/// checkout, where `git add -A` or a workspace-wide build would pick it up.
+fn reject_inside_repo(git: &Git, worktree_path: &Path) -> Result<(), git::Error> {
+    let resolve = |path: &Path| {
+        path.canonicalize()
+            .map_err(|e| git::Error::Exec(format!("resolve {}: {e}", path.display())))
+    };
+    let root = resolve(git.root())?;
+    let parent = worktree_path.parent().unwrap_or(Path::new("/"));
+    let resolved = match worktree_path.file_name() {
+        Some(name) => resolve(parent)?.join(name),
+        None => resolve(worktree_path)?,
+    };
+    if resolved.starts_with(&root) {
+        return Err(git::Error::Failed(format!(
+            "scratch worktree {} would be inside the repository at {}; \
+             put the script outside it",
+            resolved.display(),
+            root.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `path` is one of the repository's registered worktrees.
+fn is_registered_worktree(git: &Git, path: &Path) -> Result<bool, git::Error> {
+    let Ok(path) = path.canonicalize() else {
+        return Ok(false);
+    };
+    Ok(git
+        .worktree_list()?
+        .iter()
+        .any(|w| w.canonicalize().is_ok_and(|w| w == path)))
+}
+
+/// Whether `path` is missing or an empty directory.
+fn is_empty_dir(path: &Path) -> bool {
+    match std::fs::read_dir(path) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(_) => !path.exists(),
+    }
+}
+
+/// Delete whatever is at `path`, file or directory.
+fn remove_path(path: &Path) -> Result<(), git::Error> {
+    let result = if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    };
+    result.map_err(|e| git::Error::Exec(format!("remove {}: {e}", path.display())))
+}
+
+/// Quote a string for safe use as a single POSIX shell word.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(meta) = std::fs::metadata(path) {
+        let mut perms = meta.permissions();
+        perms.set_mode(perms.mode() | 0o111);
+        let _ = std::fs::set_permissions(path, perms);
+    }
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) {}
@@ -3,11 +3,19 @@
 //! The spec is a TOML file that serves as both the plan AND execution state.
 //! As retcon works, it appends to the `history` field of each commit.
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use toml_edit::{Array, DocumentMut, Item, Table, Value};
 
 /// The complete history specification.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HistorySpec {
+    /// Free-form human summary of what this spec reconstructs
+    ///
+    /// Unlike a TOML comment, this is visible to programs reading the spec.
+    #[serde(default)]
+    pub description: Option<String>,
+
     /// Branch containing all changes (the messy history)
     pub source: String,
 
@@ -17,26 +25,198 @@ pub struct HistorySpec {
     /// New branch to create with reconstructed history
     pub cleaned: String,
 
+    /// Ref the cleaned branch must end up matching (defaults to `source`)
+    ///
+    /// Set this to reconstruct a subset of `source`, e.g. a branch with
+    /// WIP or revert commits dropped.
+    #[serde(default)]
+    pub target: Option<String>,
+
+    /// Ref to match instead of `target`, for the current run only
+    ///
+    /// Retcon sets this for `--file`; it is never read from or saved to
+    /// the spec file.
+    #[serde(skip)]
+    pub(crate) target_override: Option<String>,
+
+    /// What the cleaned branch starts from
+    #[serde(default, skip_serializing_if = "Base::is_merge_base")]
+    pub base: Base,
+
+    /// Commit the cleaned branch was started from (recorded by retcon)
+    ///
+    /// Stays put even if `remote` moves later, so it is always a valid
+    /// `git rebase -i` target for the cleaned branch.
+    #[serde(default)]
+    pub base_resolved: Option<String>,
+
+    /// Oldest retcon version that can execute this spec (e.g. `"1.2.0"`)
+    #[serde(default)]
+    pub min_tool_version: Option<String>,
+
     /// Commits to create, in order
     #[serde(rename = "commit")]
     pub commits: Vec<CommitSpec>,
 }
 
+/// Where the cleaned branch starts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Base {
+    /// The merge-base of `source` and `remote`, so only the branch's own
+    /// changes are reconstructed
+    #[default]
+    MergeBase,
+
+    /// Of the merge-bases of `source` and `remote`, the one with the smallest
+    /// diff to the target; for branches with criss-cross merges, where there
+    /// is more than one
+    SmallestDiff,
+
+    /// An empty root commit, so the cleaned branch shares no history with
+    /// `remote` and everything in the target is reconstructed
+    Orphan,
+
+    /// The tip of `remote`, so the cleaned branch comes out rebased onto it;
+    /// the target's changes are merged onto `remote` to find what to match
+    Remote,
+}
+
+impl Base {
+    fn is_merge_base(&self) -> bool {
+        *self == Base::MergeBase
+    }
+}
+
 /// A single logical commit to reconstruct.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommitSpec {
-    /// The commit message (first line)
+    /// Stable identifier that other commits can reference in `depends_on`
+    #[serde(default)]
+    pub id: Option<String>,
+
+    /// The commit message (first line); left out when a cherry-pick
+    /// inherits the original commit's message
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub message: String,
 
+    /// Ids of commits this one builds on; they must appear earlier in the spec
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+
     /// Guidance for the LLM on what changes belong in this commit
     #[serde(default)]
-    pub hints: Option<String>,
+    pub hints: Option<Hints>,
+
+    /// Framing for this commit, put at the top of its extraction prompt
+    /// (e.g. "This is a pure mechanical rename; do not change behavior.")
+    #[serde(default)]
+    pub style: Option<String>,
+
+    /// Take this commit's changes from an existing commit instead of having
+    /// the LLM extract them
+    #[serde(default)]
+    pub cherry_pick: Option<CherryPick>,
+
+    /// Run the build for this commit even if it is skipped for the run
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub force_build: bool,
+
+    /// Files this commit is expected to change; once it is reconstructed,
+    /// retcon warns if it changed a different set
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expected_files: Vec<String>,
+
+    /// Files whose current contents are always included in this commit's
+    /// extraction prompt, for interfaces the changes have to fit
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub context_files: Vec<String>,
+
+    /// Review feedback on an earlier reconstruction of this commit, shown
+    /// to the LLM when the commit is rebuilt
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub review_notes: Vec<String>,
 
     /// Execution history - herodotus appends entries as it works
     #[serde(default)]
     pub history: Vec<HistoryEntry>,
 }
 
+/// An existing commit to reuse as-is for a logical commit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CherryPick {
+    /// The commit to cherry-pick (any revision git accepts)
+    pub commit: String,
+
+    /// Use the picked commit's message instead of the spec's `message`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub inherit_message: bool,
+}
+
+/// Guidance for the LLM on what changes belong in a commit.
+///
+/// Either free text or a table spelling out what to include and exclude;
+/// the spec may use whichever form reads better.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Hints {
+    /// Free-form prose
+    Text(String),
+
+    /// Guidance broken down by category
+    Structured(StructuredHints),
+}
+
+/// Hints given as separate lists of what to include and exclude.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StructuredHints {
+    /// Changes that belong in this commit
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+
+    /// Changes that must be left for other commits
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+
+    /// Anything else worth knowing, as free text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+}
+
+impl Hints {
+    /// Render the hints as text for a prompt.
+    #[must_use]
+    pub fn render(&self) -> String {
+        let hints = match self {
+            Hints::Text(text) => return text.trim().to_string(),
+            Hints::Structured(hints) => hints,
+        };
+        let mut sections = Vec::new();
+        if !hints.include.is_empty() {
+            sections.push(format!("Include:\n{}", bullet_list(&hints.include)));
+        }
+        if !hints.exclude.is_empty() {
+            sections.push(format!(
+                "Exclude (leave these for other commits):\n{}",
+                bullet_list(&hints.exclude)
+            ));
+        }
+        if let Some(notes) = &hints.notes {
+            sections.push(notes.trim().to_string());
+        }
+        sections.join("\n")
+    }
+}
+
+fn bullet_list(items: &[String]) -> String {
+    items
+        .iter()
+        .map(|item| format!("- {}", item.trim()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 /// An entry in a commit's execution history.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -44,11 +224,11 @@ pub enum HistoryEntry {
     /// Execution started on this commit (may have partial changes in working dir)
     Started,
 
-    /// A commit was created (main or WIP fix)
+    /// A commit was created (main or fixup commit)
     CommitCreated(String),
 
     /// LLM assessed it cannot proceed - needs human intervention
-    Stuck(String),
+    Stuck(StuckReason),
 
     /// Human resolved a stuck state - describes what changed
     Resolved(String),
@@ -56,10 +236,138 @@ pub enum HistoryEntry {
     /// Pre-configured response to send to the LLM on stuck - auto-resolves
     Response(String),
 
+    /// Commits created so far were undone by resetting the branch to this
+    /// commit. A retry keeps the working tree; `continue_on_stuck` discards it.
+    RolledBack(String),
+
+    /// Which hints the LLM says it acted on when extracting the commit
+    HintFeedback(HintFeedback),
+
+    /// No commit was made, because nothing was left to extract or the hooks deferred it
+    Skipped(String),
+
+    /// With `--reverse`, this commit's changes were peeled off, leaving this
+    /// snapshot of the tree as it was just before the commit
+    Peeled(String),
+
     /// This logical commit is done
     Complete,
 }
 
+/// The LLM's account of which hints mattered for a commit.
+///
+/// Tallied over several runs, this shows which hints are never used and
+/// may be unclear or belong to another commit.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HintFeedback {
+    /// Hints the LLM acted on
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub applied: Vec<String>,
+
+    /// Hints the LLM found irrelevant to this commit
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignored: Vec<String>,
+}
+
+/// Why a commit got stuck: a free-text explanation, optionally categorized.
+///
+/// In TOML this is either a plain string or `{ kind = "...", message = "..." }`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "StuckRepr", into = "StuckRepr")]
+pub struct StuckReason {
+    /// The category, if retcon or the LLM could tell
+    pub kind: Option<StuckKind>,
+
+    /// Details, for the human resolving it
+    pub message: String,
+}
+
+/// Broad categories of stuck commits, for tallying across runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StuckKind {
+    /// The commit needs changes from a later commit, and vice versa
+    CircularDependency,
+    /// The hints or the diff don't say enough to tell what belongs here
+    MissingContext,
+    /// The build or tests fail and fixing them is out of reach
+    BuildUnfixable,
+    /// Too many attempts were made without success
+    MaxIterations,
+    /// The run was interrupted or errored out partway through
+    Interrupted,
+    /// Anything else
+    Other,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum StuckRepr {
+    Text(String),
+    Categorized { kind: StuckKind, message: String },
+}
+
+impl StuckKind {
+    /// The name used for this kind in the spec, e.g. `"missing_context"`.
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StuckKind::CircularDependency => "circular_dependency",
+            StuckKind::MissingContext => "missing_context",
+            StuckKind::BuildUnfixable => "build_unfixable",
+            StuckKind::MaxIterations => "max_iterations",
+            StuckKind::Interrupted => "interrupted",
+            StuckKind::Other => "other",
+        }
+    }
+}
+
+impl StuckReason {
+    /// A reason in the given category.
+    pub fn new(kind: StuckKind, message: impl Into<String>) -> Self {
+        Self {
+            kind: Some(kind),
+            message: message.into(),
+        }
+    }
+}
+
+impl From<String> for StuckReason {
+    fn from(message: String) -> Self {
+        Self {
+            kind: None,
+            message,
+        }
+    }
+}
+
+impl From<StuckRepr> for StuckReason {
+    fn from(repr: StuckRepr) -> Self {
+        match repr {
+            StuckRepr::Text(message) => message.into(),
+            StuckRepr::Categorized { kind, message } => Self::new(kind, message),
+        }
+    }
+}
+
+impl From<StuckReason> for StuckRepr {
+    fn from(reason: StuckReason) -> Self {
+        match reason.kind {
+            None => StuckRepr::Text(reason.message),
+            Some(kind) => StuckRepr::Categorized {
+                kind,
+                message: reason.message,
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for StuckReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
 impl HistorySpec {
     /// Parse a history spec from TOML content.
     pub fn from_toml(content: &str) -> Result<Self, toml::de::Error> {
@@ -68,7 +376,142 @@ impl HistorySpec {
 
     /// Serialize the spec back to TOML.
     pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
-        toml::to_string_pretty(self)
+        let pretty = toml::to_string_pretty(self)?;
+        let Ok(mut doc) = pretty.parse::<DocumentMut>() else {
+            return Ok(pretty);
+        };
+        // The serializer writes a history made up only of tables (say, a
+        // categorized stuck entry) as `[[commit.history]]` sections; keep it
+        // an inline array like every other history
+        if let Some(commits) = doc.get_mut("commit").and_then(Item::as_array_of_tables_mut) {
+            for commit in commits.iter_mut() {
+                inline_array_of_tables(commit, "history");
+            }
+        }
+        Ok(doc.to_string())
+    }
+
+    /// Serialize the spec as an edit of `original`, the file it was read from.
+    ///
+    /// Values that haven't changed keep their original formatting and
+    /// comments, and entries appended to a `history` array are added
+    /// without rewriting the ones already there. If `original` isn't valid
+    /// TOML, this is the same as [`to_toml`](Self::to_toml).
+    pub fn to_toml_preserving(&self, original: &str) -> Result<String, toml::ser::Error> {
+        let fresh = self.to_toml()?;
+        let (Ok(mut doc), Ok(new)) = (
+            original.parse::<DocumentMut>(),
+            fresh.parse::<DocumentMut>(),
+        ) else {
+            return Ok(fresh);
+        };
+        merge_table(doc.as_table_mut(), new.as_table());
+        Ok(doc.to_string())
+    }
+
+    /// The ref the cleaned branch must match once reconstruction is done.
+    #[must_use]
+    pub fn target(&self) -> &str {
+        self.target_override
+            .as_deref()
+            .or(self.target.as_deref())
+            .unwrap_or(&self.source)
+    }
+
+    /// Check that this spec can be executed by the running version of retcon.
+    ///
+    /// This looks only at the spec itself and makes no git calls, so it can
+    /// run without the repository present.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if let Some(required) = &self.min_tool_version {
+            let current = env!("CARGO_PKG_VERSION");
+            let required_version = parse_version(required)
+                .ok_or_else(|| ValidationError::InvalidToolVersion(required.clone()))?;
+            let current_version =
+                parse_version(current).expect("crate version is always valid");
+            if current_version < required_version {
+                return Err(ValidationError::ToolTooOld {
+                    required: required.clone(),
+                    current: current.to_string(),
+                });
+            }
+        }
+        if self.cleaned == self.source {
+            return Err(ValidationError::CleanedIsSource(self.source.clone()));
+        }
+        if let Some(idx) = self
+            .commits
+            .iter()
+            .position(|c| c.inherits_message() && !c.message.trim().is_empty())
+        {
+            return Err(ValidationError::TwoMessages(idx + 1));
+        }
+        if let Some(idx) = self
+            .commits
+            .iter()
+            .position(|c| !c.inherits_message() && c.message.trim().is_empty())
+        {
+            return Err(ValidationError::EmptyMessage(idx + 1));
+        }
+        self.validate_dependencies()
+    }
+
+    /// Check that commit ids are unique and every dependency comes earlier.
+    ///
+    /// Since dependencies may only point backwards, this also guarantees
+    /// the dependency graph is acyclic.
+    fn validate_dependencies(&self) -> Result<(), ValidationError> {
+        let mut seen: Vec<&str> = Vec::new();
+        for commit in &self.commits {
+            for dep in &commit.depends_on {
+                if !seen.contains(&dep.as_str()) {
+                    let exists = self.commits.iter().any(|c| c.id.as_deref() == Some(dep));
+                    return Err(if exists {
+                        ValidationError::DependencyOrder {
+                            commit: commit.label().to_string(),
+                            dependency: dep.clone(),
+                        }
+                    } else {
+                        ValidationError::UnknownDependency {
+                            commit: commit.label().to_string(),
+                            dependency: dep.clone(),
+                        }
+                    });
+                }
+            }
+            if let Some(id) = &commit.id {
+                if seen.contains(&id.as_str()) {
+                    return Err(ValidationError::DuplicateId(id.clone()));
+                }
+                seen.push(id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the spec can be reconstructed with `--reverse`.
+    ///
+    /// Peeling asks the LLM to take each commit's changes off the target, so
+    /// a `cherry_pick` would be silently dropped. [`validate`](Self::validate)
+    /// doesn't check this, since forward runs honor cherry-picks.
+    pub fn check_reversible(&self) -> Result<(), ValidationError> {
+        match self.commits.iter().position(|c| c.cherry_pick.is_some()) {
+            Some(idx) => Err(ValidationError::CherryPickInReverse(idx + 1)),
+            None => Ok(()),
+        }
+    }
+
+    /// Check that the spec declares at most `max` commits.
+    ///
+    /// This is a policy some teams apply to keep plans from getting too
+    /// granular. [`validate`](Self::validate) doesn't check it, since no
+    /// limit is built in.
+    pub fn check_commit_count(&self, max: usize) -> Result<(), ValidationError> {
+        let count = self.commits.len();
+        if count > max {
+            return Err(ValidationError::TooManyCommits { count, max });
+        }
+        Ok(())
     }
 
     /// Find the index of the first commit that isn't complete.
@@ -81,6 +524,30 @@ impl HistorySpec {
 }
 
 impl CommitSpec {
+    /// A short name for this commit: its id, or else the first line of its message.
+    #[must_use]
+    pub fn label(&self) -> &str {
+        self.id.as_deref().unwrap_or_else(|| self.subject())
+    }
+
+    /// The first line of the commit message, or for a cherry-pick that
+    /// inherits its message, the revision being picked.
+    #[must_use]
+    pub fn subject(&self) -> &str {
+        match &self.cherry_pick {
+            Some(pick) if self.message.is_empty() => &pick.commit,
+            _ => self.message.lines().next().unwrap_or(""),
+        }
+    }
+
+    /// Whether the commit takes its message from the commit it cherry-picks.
+    #[must_use]
+    pub fn inherits_message(&self) -> bool {
+        self.cherry_pick
+            .as_ref()
+            .is_some_and(|pick| pick.inherit_message)
+    }
+
     /// Check if this commit is complete.
     #[must_use]
     pub fn is_complete(&self) -> bool {
@@ -96,10 +563,66 @@ impl CommitSpec {
         matches!(self.history.last(), Some(HistoryEntry::Stuck(_)))
     }
 
+    /// Get the reason this commit is stuck, if it is awaiting human resolution.
+    #[must_use]
+    pub fn stuck_reason(&self) -> Option<&str> {
+        self.stuck().map(|reason| reason.message.as_str())
+    }
+
+    /// The category of the reason this commit is stuck, if it is stuck and
+    /// the reason was categorized.
+    #[must_use]
+    pub fn stuck_kind(&self) -> Option<StuckKind> {
+        self.stuck().and_then(|reason| reason.kind)
+    }
+
+    fn stuck(&self) -> Option<&StuckReason> {
+        match self.history.last() {
+            Some(HistoryEntry::Stuck(reason)) => Some(reason),
+            _ => None,
+        }
+    }
+
     /// Check if this commit was started but interrupted (e.g., Ctrl-C).
+    ///
+    /// Progress is saved mid-commit, so an interrupted commit's history may
+    /// already record the commits it made after `Started`; it is still in
+    /// progress until a `Complete` or `Stuck` entry follows.
     #[must_use]
     pub fn is_started(&self) -> bool {
-        matches!(self.history.last(), Some(HistoryEntry::Started))
+        self.entries_since_started().is_some_and(|entries| {
+            entries.iter().all(|e| {
+                matches!(
+                    e,
+                    HistoryEntry::CommitCreated(_) | HistoryEntry::HintFeedback(_)
+                )
+            })
+        })
+    }
+
+    /// The commit an interrupted attempt already made, which resuming
+    /// verifies instead of extracting the changes again.
+    #[must_use]
+    pub fn interrupted_commit(&self) -> Option<&str> {
+        if !self.is_started() {
+            return None;
+        }
+        self.entries_since_started()?
+            .iter()
+            .rev()
+            .find_map(|e| match e {
+                HistoryEntry::CommitCreated(h) => Some(h.as_str()),
+                _ => None,
+            })
+    }
+
+    /// The entries after the last `Started`, if there is one.
+    fn entries_since_started(&self) -> Option<&[HistoryEntry]> {
+        let start = self
+            .history
+            .iter()
+            .rposition(|e| matches!(e, HistoryEntry::Started))?;
+        Some(&self.history[start + 1..])
     }
 
     /// Check if this commit was stuck but has been resolved by a human.
@@ -113,6 +636,52 @@ impl CommitSpec {
         )
     }
 
+    /// The entries since the last `RolledBack`, which describe the commits
+    /// that are still on the branch; the whole history if there was no rollback.
+    #[must_use]
+    pub fn entries_since_rollback(&self) -> &[HistoryEntry] {
+        let start = self
+            .history
+            .iter()
+            .rposition(|e| matches!(e, HistoryEntry::RolledBack(_)))
+            .map_or(0, |i| i + 1);
+        &self.history[start..]
+    }
+
+    /// The first commit created for this logical commit that is still on the branch.
+    ///
+    /// Commits from before the last `RolledBack` entry no longer exist on
+    /// the branch and are ignored.
+    #[must_use]
+    pub fn first_commit(&self) -> Option<&str> {
+        self.entries_since_rollback().iter().find_map(|e| match e {
+            HistoryEntry::CommitCreated(h) => Some(h.as_str()),
+            _ => None,
+        })
+    }
+
+    /// The latest commit created for this logical commit that is still on the branch.
+    #[must_use]
+    pub fn last_commit(&self) -> Option<&str> {
+        self.entries_since_rollback()
+            .iter()
+            .rev()
+            .find_map(|e| match e {
+                HistoryEntry::CommitCreated(h) => Some(h.as_str()),
+                _ => None,
+            })
+    }
+
+    /// The snapshot this commit was peeled to with `--reverse`, unless
+    /// something has happened to the commit since.
+    #[must_use]
+    pub fn peeled_snapshot(&self) -> Option<&str> {
+        match self.history.last() {
+            Some(HistoryEntry::Peeled(snapshot)) => Some(snapshot),
+            _ => None,
+        }
+    }
+
     /// Get the resolution note if this commit was resolved.
     #[must_use]
     pub fn resolution_note(&self) -> Option<&str> {
@@ -122,3 +691,360 @@ impl CommitSpec {
         }
     }
 }
+
+/// Move the commit at index `from` of the spec in `original` to index `to`.
+///
+/// The commits in between shift over by one. This edits the TOML text
+/// directly rather than going through [`HistorySpec`], so each commit
+/// keeps its comments, formatting, and history. Returns `None` if
+/// `original` isn't valid TOML or either index is out of range.
+pub fn move_commit(original: &str, from: usize, to: usize) -> Option<String> {
+    let mut doc = original.parse::<DocumentMut>().ok()?;
+    let commits = doc.get_mut("commit")?.as_array_of_tables_mut()?;
+    if from >= commits.len() || to >= commits.len() {
+        return None;
+    }
+
+    let mut tables: Vec<Table> = std::mem::take(commits).into_iter().collect();
+    // Tables are written out in position order, so the moved commit (and
+    // any `[commit.*]` sections under it) needs new positions too
+    let mut positions = Vec::new();
+    for table in &tables {
+        collect_positions(table, &mut positions);
+    }
+    positions.sort_unstable();
+
+    let moved = tables.remove(from);
+    tables.insert(to, moved);
+    let mut positions = positions.into_iter();
+    for table in &mut tables {
+        assign_positions(table, &mut positions);
+    }
+    for table in tables {
+        commits.push(table);
+    }
+    Some(doc.to_string())
+}
+
+/// Collect the positions of `table` and every table nested under it.
+fn collect_positions(table: &Table, out: &mut Vec<usize>) {
+    out.extend(table.position());
+    for (_, item) in table.iter() {
+        match item {
+            Item::Table(sub) => collect_positions(sub, out),
+            Item::ArrayOfTables(subs) => subs.iter().for_each(|sub| collect_positions(sub, out)),
+            _ => {}
+        }
+    }
+}
+
+/// Renumber `table` and its nested tables from `positions`, keeping their relative order.
+fn assign_positions(table: &mut Table, positions: &mut impl Iterator<Item = usize>) {
+    if table.position().is_some()
+        && let Some(pos) = positions.next()
+    {
+        table.set_position(pos);
+    }
+    let mut nested: Vec<&mut Table> = Vec::new();
+    for (_, item) in table.iter_mut() {
+        match item {
+            Item::Table(sub) => nested.push(sub),
+            Item::ArrayOfTables(subs) => nested.extend(subs.iter_mut()),
+            _ => {}
+        }
+    }
+    nested.sort_by_key(|sub| sub.position());
+    for sub in nested {
+        assign_positions(sub, positions);
+    }
+}
+
+/// Update `doc` to hold the same data as `new`, touching only what changed.
+fn merge_table(doc: &mut Table, new: &Table) {
+    let removed: Vec<String> = doc
+        .iter()
+        .filter(|(key, _)| !new.contains_key(key))
+        .map(|(key, _)| key.to_string())
+        .collect();
+    for key in removed {
+        doc.remove(&key);
+    }
+
+    for (key, new_item) in new.iter() {
+        match (doc.get_mut(key), new_item) {
+            (Some(Item::Table(old)), Item::Table(new)) => merge_table(old, new),
+            (Some(Item::ArrayOfTables(old)), Item::ArrayOfTables(new))
+                if old.len() == new.len() =>
+            {
+                for (old, new) in old.iter_mut().zip(new.iter()) {
+                    merge_table(old, new);
+                }
+            }
+            (Some(Item::Value(old)), Item::Value(new)) if same_value(old, new) => {}
+            // The serializer writes nested tables out in full, but the user
+            // may have written the same table inline
+            (Some(Item::Value(old @ Value::InlineTable(_))), Item::Table(new))
+                if same_value(old, &Value::InlineTable(new.clone().into_inline_table())) => {}
+            (Some(Item::Value(Value::Array(old))), Item::Value(Value::Array(new)))
+                if extends(old, new) =>
+            {
+                append_values(old, new.iter().skip(old.len()));
+            }
+            _ => {
+                doc.insert(key, new_item.clone());
+            }
+        }
+    }
+}
+
+/// Turn the array of tables at `key` into an inline array, one element per line.
+fn inline_array_of_tables(table: &mut Table, key: &str) {
+    let Some(item) = table.get_mut(key) else {
+        return;
+    };
+    if !item.is_array_of_tables() {
+        return;
+    }
+    let Item::ArrayOfTables(tables) = std::mem::take(item) else {
+        return;
+    };
+    let mut array = tables.into_array();
+    for value in array.iter_mut() {
+        value.decor_mut().set_prefix("\n    ");
+    }
+    array.set_trailing_comma(true);
+    array.set_trailing("\n");
+    array.decor_mut().set_prefix(" ");
+    *item = Item::Value(Value::Array(array));
+    if let Some(mut key) = table.key_mut(key) {
+        key.leaf_decor_mut().set_suffix(" ");
+    }
+}
+
+/// Whether two values are equal, ignoring formatting and comments.
+///
+/// This round-trips both values through the `toml` crate, which is far
+/// from the cheapest comparison, but it is the one that treats `'a'` and
+/// `"a"`, or `1_000` and `1000`, as equal. A save compares each value of a
+/// spec once, and specs are a few dozen commits long, so the cost is lost
+/// next to the git and agent calls between saves.
+fn same_value(a: &Value, b: &Value) -> bool {
+    fn plain(v: &Value) -> Option<toml::Value> {
+        let mut v = v.clone();
+        v.decor_mut().clear();
+        let table: toml::Table = toml::from_str(&format!("v = {v}")).ok()?;
+        table.get("v").cloned()
+    }
+    plain(a).is_some() && plain(a) == plain(b)
+}
+
+/// Whether `new` is `old` with more elements added at the end.
+fn extends(old: &Array, new: &Array) -> bool {
+    new.len() > old.len() && old.iter().zip(new.iter()).all(|(a, b)| same_value(a, b))
+}
+
+/// Append `values` to `array`, laid out like the elements already there.
+fn append_values<'a>(array: &mut Array, values: impl Iterator<Item = &'a Value>) {
+    let prefix = array
+        .iter()
+        .last()
+        .and_then(|v| v.decor().prefix())
+        .and_then(|p| p.as_str())
+        .map(String::from);
+    for value in values {
+        let mut value = value.clone();
+        value.decor_mut().clear();
+        if let Some(prefix) = &prefix {
+            value.decor_mut().set_prefix(prefix.clone());
+        }
+        array.push_formatted(value);
+    }
+}
+
+/// Parse a `major.minor.patch` version, ignoring any pre-release suffix.
+///
+/// Missing components default to zero, so `"1.2"` parses as `1.2.0`.
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let version = version.trim().trim_start_matches('v');
+    let version = version.split(['-', '+']).next()?;
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next().map_or(Some(0), |p| p.parse().ok())?;
+    let patch = parts.next().map_or(Some(0), |p| p.parse().ok())?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((major, minor, patch))
+}
+
+/// Problems that make a spec unsafe to execute.
+#[derive(Debug, thiserror::Error)]
+pub enum ValidationError {
+    #[error("invalid min_tool_version '{0}' (expected e.g. \"1.2.0\")")]
+    InvalidToolVersion(String),
+
+    #[error("spec requires retcon {required} or newer, but this is retcon {current}")]
+    ToolTooOld { required: String, current: String },
+
+    #[error("`cleaned` and `source` are both '{0}'; retcon would rewrite the source branch")]
+    CleanedIsSource(String),
+
+    #[error("commit {0} has an empty message")]
+    EmptyMessage(usize),
+
+    #[error("commit {0} has a message but also inherits its cherry-picked commit's message")]
+    TwoMessages(usize),
+
+    #[error("duplicate commit id '{0}'")]
+    DuplicateId(String),
+
+    #[error("commit '{commit}' depends on unknown commit id '{dependency}'")]
+    UnknownDependency { commit: String, dependency: String },
+
+    #[error("commit '{commit}' depends on '{dependency}', which does not come before it")]
+    DependencyOrder { commit: String, dependency: String },
+
+    #[error("spec has {count} commits, more than the {max} allowed")]
+    TooManyCommits { count: usize, max: usize },
+
+    #[error("commit {0} is a cherry-pick, which --reverse can't honor; build forward instead")]
+    CherryPickInReverse(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse `original`, change it with `edit`, and save it back over `original`.
+    fn resave(original: &str, edit: impl FnOnce(&mut HistorySpec)) -> String {
+        let mut spec = HistorySpec::from_toml(original).unwrap();
+        edit(&mut spec);
+        let saved = spec.to_toml_preserving(original).unwrap();
+        HistorySpec::from_toml(&saved).expect("saved spec parses");
+        saved
+    }
+
+    const SPEC: &str = r#"# Reconstruct the parser work
+source = "feature"   # the messy branch
+remote = "origin/main"
+cleaned = "feature-clean"
+target = "feature~1"
+
+# First the refactor
+[[commit]]
+message = "refactor: split the lexer out"
+cherry_pick = { commit = "abc123" }
+history = [
+    "started",
+    { commit_created = "1111111" },
+]
+"#;
+
+    #[test]
+    fn unchanged_spec_is_left_byte_for_byte() {
+        assert_eq!(resave(SPEC, |_| {}), SPEC);
+    }
+
+    #[test]
+    fn comments_are_kept() {
+        let saved = resave(SPEC, |spec| spec.cleaned = "feature-tidy".to_string());
+        assert!(saved.starts_with("# Reconstruct the parser work\n"));
+        assert!(saved.contains(r#"source = "feature"   # the messy branch"#));
+        assert!(saved.contains("# First the refactor\n[[commit]]"));
+        assert!(saved.contains(r#"cleaned = "feature-tidy""#));
+    }
+
+    #[test]
+    fn history_is_appended_to() {
+        let saved = resave(SPEC, |spec| {
+            spec.commits[0].history.push(HistoryEntry::Complete);
+        });
+        assert!(saved.contains(
+            "history = [\n    \"started\",\n    { commit_created = \"1111111\" },\n    \"complete\",\n]"
+        ));
+    }
+
+    #[test]
+    fn inline_table_is_left_inline() {
+        let saved = resave(SPEC, |spec| {
+            spec.commits[0].history.push(HistoryEntry::Complete);
+        });
+        assert!(saved.contains(r#"cherry_pick = { commit = "abc123" }"#));
+        assert!(!saved.contains("[commit.cherry_pick]"));
+    }
+
+    #[test]
+    fn removed_key_is_dropped() {
+        let saved = resave(SPEC, |spec| spec.target = None);
+        assert!(!saved.contains("target"));
+        assert!(saved.contains(r#"cleaned = "feature-clean""#));
+    }
+
+    #[test]
+    fn categorized_stuck_entry_stays_inline() {
+        let original = "source = \"feature\"\nremote = \"main\"\ncleaned = \"clean\"\n\n\
+                        [[commit]]\nmessage = \"add parser\"\n";
+        let saved = resave(original, |spec| {
+            spec.commits[0].history = vec![HistoryEntry::Stuck(StuckReason::new(
+                StuckKind::MissingContext,
+                "needs the lexer",
+            ))];
+        });
+        assert!(!saved.contains("[[commit.history]]"), "{saved}");
+        assert!(saved.contains("history = ["), "{saved}");
+
+        let spec = HistorySpec::from_toml(&saved).unwrap();
+        let [HistoryEntry::Stuck(reason)] = spec.commits[0].history.as_slice() else {
+            panic!("unexpected history: {:?}", spec.commits[0].history);
+        };
+        assert_eq!(reason.kind, Some(StuckKind::MissingContext));
+    }
+
+    #[test]
+    fn move_commit_carries_sub_tables() {
+        let original = r#"source = "feature"
+remote = "main"
+cleaned = "clean"
+
+[[commit]]
+message = "first"
+
+[[commit]]
+message = "second"
+history = ["started"]
+
+[commit.cherry_pick]
+commit = "abc123"
+
+[[commit]]
+message = "third"
+"#;
+        let moved = move_commit(original, 1, 0).unwrap();
+        let spec = HistorySpec::from_toml(&moved).unwrap();
+        let messages: Vec<&str> = spec.commits.iter().map(|c| c.message.as_str()).collect();
+        assert_eq!(messages, ["second", "first", "third"]);
+        assert_eq!(
+            spec.commits[0].cherry_pick.as_ref().unwrap().commit,
+            "abc123"
+        );
+        assert!(spec.commits[1].cherry_pick.is_none());
+        assert!(matches!(
+            spec.commits[0].history.as_slice(),
+            [HistoryEntry::Started]
+        ));
+
+        // Moving it back restores the file exactly
+        let back = move_commit(&moved, 0, 1).unwrap();
+        assert_eq!(back, original);
+        assert!(move_commit(original, 0, 3).is_none());
+    }
+
+    #[test]
+    fn check_reversible_refuses_cherry_picks() {
+        let spec = HistorySpec::from_toml(SPEC).unwrap();
+        assert!(matches!(
+            spec.check_reversible(),
+            Err(ValidationError::CherryPickInReverse(1))
+        ));
+    }
+}
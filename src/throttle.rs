@@ -0,0 +1,123 @@
+//! Pace requests to the agent so long runs stay under provider rate limits.
+//!
+//! Every `d.think()` call is one request to the agent. A [`RateLimit`] is
+//! consulted before each of them and sleeps until the request is allowed.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Limits on how often retcon sends requests to the agent.
+///
+/// Clones share their record of past requests, so a copied
+/// [`ExecuteConfig`](crate::ExecuteConfig) still enforces a single limit.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimit {
+    min_delay: Option<Duration>,
+    max_per_minute: Option<usize>,
+    sent: Arc<Mutex<VecDeque<Instant>>>,
+}
+
+impl RateLimit {
+    /// Wait at least `min_delay` between requests, and send at most
+    /// `max_per_minute` requests in any one-minute window.
+    pub fn new(min_delay: Option<Duration>, max_per_minute: Option<usize>) -> Self {
+        Self {
+            min_delay,
+            max_per_minute: max_per_minute.filter(|&n| n > 0),
+            sent: Arc::default(),
+        }
+    }
+
+    /// Whether no limit is set.
+    fn is_unlimited(&self) -> bool {
+        self.min_delay.is_none() && self.max_per_minute.is_none()
+    }
+
+    /// Sleep until another request is allowed, then record it as sent.
+    pub(crate) async fn wait(&self) {
+        if self.is_unlimited() {
+            return;
+        }
+        loop {
+            let ready_at = self.ready_at();
+            let now = Instant::now();
+            if ready_at <= now {
+                self.sent.lock().unwrap().push_back(now);
+                return;
+            }
+            tokio::time::sleep_until(ready_at).await;
+        }
+    }
+
+    /// The earliest time the next request may be sent.
+    fn ready_at(&self) -> Instant {
+        const WINDOW: Duration = Duration::from_secs(60);
+
+        let now = Instant::now();
+        let mut sent = self.sent.lock().unwrap();
+        // Keep the latest request even once it ages out, for `min_delay`
+        while sent.len() > 1
+            && sent
+                .front()
+                .is_some_and(|&t| now.duration_since(t) >= WINDOW)
+        {
+            sent.pop_front();
+        }
+
+        let mut ready_at = now;
+        if let (Some(delay), Some(&last)) = (self.min_delay, sent.back()) {
+            ready_at = ready_at.max(last + delay);
+        }
+        if let Some(max) = self.max_per_minute
+            && sent.len() >= max
+        {
+            // The oldest request in the window has to age out first
+            ready_at = ready_at.max(sent[sent.len() - max] + WINDOW);
+        }
+        ready_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send `count` requests through `limit`, returning the seconds each was
+    /// sent at, counted from the first call.
+    async fn send_times(limit: &RateLimit, count: usize) -> Vec<u64> {
+        let start = Instant::now();
+        let mut times = Vec::new();
+        for _ in 0..count {
+            limit.wait().await;
+            times.push(start.elapsed().as_secs());
+        }
+        times
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn min_delay_spaces_requests() {
+        let limit = RateLimit::new(Some(Duration::from_secs(2)), None);
+        assert_eq!(send_times(&limit, 3).await, [0, 2, 4]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn max_per_minute_waits_for_the_window() {
+        let limit = RateLimit::new(None, Some(2));
+        assert_eq!(send_times(&limit, 5).await, [0, 0, 60, 60, 120]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn both_limits_apply() {
+        let limit = RateLimit::new(Some(Duration::from_secs(10)), Some(3));
+        assert_eq!(send_times(&limit, 5).await, [0, 10, 20, 60, 70]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn min_delay_counts_from_a_request_outside_the_window() {
+        let limit = RateLimit::new(Some(Duration::from_secs(90)), Some(5));
+        assert_eq!(send_times(&limit, 2).await, [0, 90]);
+    }
+}
@@ -0,0 +1,308 @@
+//! Tools that retcon offers the agent during reconstruction.
+//!
+//! The agent brings its own file and shell tools; these complement them with
+//! operations whose output retcon wants to keep under control, like reading
+//! files with a size cap so a single huge file can't blow the context.
+
+use std::path::{Path, PathBuf};
+
+use determinishtic::ThinkBuilder;
+use sacp::role::{HasPeer, Role};
+use sacp::{Agent, RunWithConnectionTo};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::git::Git;
+
+/// Register the file tools on a think block.
+///
+/// Paths are resolved relative to the root of `git`'s repository and may not
+/// escape it. Returned content is truncated to `max_bytes` when set.
+pub(crate) fn with_file_tools<'bound, Output, R, Run>(
+    builder: ThinkBuilder<'bound, Output, R, Run>,
+    git: &Git,
+    max_bytes: Option<usize>,
+) -> ThinkBuilder<'bound, Output, R, impl RunWithConnectionTo<R>>
+where
+    R: Role + HasPeer<Agent>,
+    Output: Send + JsonSchema + DeserializeOwned + 'static,
+    Run: RunWithConnectionTo<R>,
+{
+    builder
+        .define_tool(
+            "read_file",
+            "Read a file from the working tree (large files are truncated)",
+            {
+                let repo = git.root().to_path_buf();
+                async move |input: ReadFileInput, _cx| {
+                    Ok(match read_repo_file(&repo, &input.path) {
+                        Ok(content) => ReadFileOutput::content(truncate(content, max_bytes, None)),
+                        Err(e) => ReadFileOutput::error(e),
+                    })
+                }
+            },
+            sacp::tool_fn_mut!(),
+        )
+        .define_tool(
+            "read_file_range",
+            "Read lines start_line..=end_line (1-indexed) of a file from the working tree",
+            {
+                let repo = git.root().to_path_buf();
+                async move |input: ReadFileRangeInput, _cx| {
+                    let content = match read_repo_file(&repo, &input.path) {
+                        Ok(content) => content,
+                        Err(e) => return Ok(ReadFileOutput::error(e)),
+                    };
+                    let start = input.start_line.max(1);
+                    let end = input.end_line.max(start);
+                    let total_lines = content.lines().count();
+                    let selected: String = content
+                        .lines()
+                        .skip(start - 1)
+                        .take(end - start + 1)
+                        .map(|line| format!("{line}\n"))
+                        .collect();
+                    Ok(ReadFileOutput::content(truncate(
+                        selected,
+                        max_bytes,
+                        Some(total_lines),
+                    )))
+                }
+            },
+            sacp::tool_fn_mut!(),
+        )
+        .define_tool(
+            "set_executable",
+            "Set or clear a file's executable bit, for changes that only alter a file's mode",
+            {
+                let git = git.clone();
+                async move |input: SetExecutableInput, _cx| {
+                    let result = resolve_in_repo(git.root(), &input.path).and_then(|_| {
+                        git.set_executable(&input.path, input.executable)
+                            .map_err(|e| e.to_string())
+                    });
+                    Ok(SetExecutableOutput {
+                        error: result.err(),
+                    })
+                }
+            },
+            sacp::tool_fn_mut!(),
+        )
+        .define_tool(
+            "search",
+            "Find lines matching a regular expression (git grep -E), in the working tree \
+             or at a revision such as the source branch",
+            {
+                let git = git.clone();
+                async move |input: SearchInput, _cx| {
+                    let result =
+                        git.grep(&input.pattern, input.rev.as_deref(), input.path.as_deref());
+                    Ok(match result {
+                        Ok(matches) => SearchOutput::matches(matches),
+                        Err(e) => SearchOutput {
+                            matches: Vec::new(),
+                            truncated: false,
+                            error: Some(e.to_string()),
+                        },
+                    })
+                }
+            },
+            sacp::tool_fn_mut!(),
+        )
+        .define_tool(
+            "apply_patch",
+            "Apply a unified diff to the working tree with `git apply --3way`, staging all \
+             current changes first; hunks that can't be merged are left as conflict markers, \
+             which must be edited away before the commit can be made",
+            {
+                let git = git.clone();
+                async move |input: ApplyPatchInput, _cx| {
+                    Ok(match git.apply_3way(&input.patch) {
+                        Ok(conflicts) => ApplyPatchOutput {
+                            conflicts,
+                            error: None,
+                        },
+                        Err(e) => ApplyPatchOutput {
+                            conflicts: Vec::new(),
+                            error: Some(e.to_string()),
+                        },
+                    })
+                }
+            },
+            sacp::tool_fn_mut!(),
+        )
+}
+
+/// Read a file for inclusion in a prompt, truncated like `read_file`'s output.
+pub(crate) fn read_for_prompt(
+    repo_root: &Path,
+    path: &str,
+    max_bytes: Option<usize>,
+) -> Result<String, String> {
+    read_repo_file(repo_root, path).map(|content| truncate(content, max_bytes, None))
+}
+
+/// Read a file, refusing paths that resolve outside the repository.
+fn read_repo_file(repo_root: &Path, path: &str) -> Result<String, String> {
+    let full = resolve_in_repo(repo_root, path)?;
+    std::fs::read_to_string(&full).map_err(|e| format!("failed to read '{path}': {e}"))
+}
+
+/// Resolve `path` relative to the repository root, refusing to escape it.
+pub(crate) fn resolve_in_repo(repo_root: &Path, path: &str) -> Result<PathBuf, String> {
+    let root = repo_root
+        .canonicalize()
+        .map_err(|e| format!("failed to resolve repository root: {e}"))?;
+    let full = root
+        .join(path)
+        .canonicalize()
+        .map_err(|e| format!("failed to resolve '{path}': {e}"))?;
+    if !full.starts_with(&root) {
+        return Err(format!("'{path}' is outside the repository"));
+    }
+    Ok(full)
+}
+
+/// Cut `content` down to at most `max_bytes`, appending a marker if anything was dropped.
+fn truncate(mut content: String, max_bytes: Option<usize>, total_lines: Option<usize>) -> String {
+    let Some(max) = max_bytes else {
+        return content;
+    };
+    if content.len() <= max {
+        return content;
+    }
+
+    let original_len = content.len();
+    let mut cut = max;
+    while !content.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    content.truncate(cut);
+    let shown_lines = content.lines().count();
+    content.push_str(&format!(
+        "\n[... truncated: showing {cut} of {original_len} bytes ({shown_lines} lines"
+    ));
+    if let Some(total) = total_lines {
+        content.push_str(&format!(" of a {total}-line file"));
+    }
+    content.push_str("); use read_file_range to read the rest ...]\n");
+    content
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct ReadFileInput {
+    /// Path relative to the repository root
+    path: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct ReadFileRangeInput {
+    /// Path relative to the repository root
+    path: String,
+    /// First line to read (1-indexed)
+    start_line: usize,
+    /// Last line to read (inclusive)
+    end_line: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct SetExecutableInput {
+    /// Path relative to the repository root
+    path: String,
+    /// Whether the file should be executable
+    executable: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct SetExecutableOutput {
+    /// Error message if the mode could not be changed
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct SearchInput {
+    /// Extended regular expression to search for
+    pattern: String,
+    /// Revision to search instead of the working tree (e.g. the source branch)
+    #[serde(default)]
+    rev: Option<String>,
+    /// Only search under this path, relative to the repository root
+    #[serde(default)]
+    path: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct SearchOutput {
+    /// Matching lines as `path:line:text`
+    matches: Vec<String>,
+    /// Whether more matches were found than are shown; narrow the search to see them
+    truncated: bool,
+    /// Error message if the search failed
+    error: Option<String>,
+}
+
+impl SearchOutput {
+    /// Most matches returned from one search.
+    const MAX_MATCHES: usize = 100;
+    /// Longest matching line returned, in bytes.
+    const MAX_LINE_BYTES: usize = 300;
+
+    fn matches(mut matches: Vec<String>) -> Self {
+        let truncated = matches.len() > Self::MAX_MATCHES;
+        matches.truncate(Self::MAX_MATCHES);
+        for line in &mut matches {
+            if line.len() > Self::MAX_LINE_BYTES {
+                let mut cut = Self::MAX_LINE_BYTES;
+                while !line.is_char_boundary(cut) {
+                    cut -= 1;
+                }
+                line.truncate(cut);
+                line.push_str(" [...]");
+            }
+        }
+        Self {
+            matches,
+            truncated,
+            error: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct ApplyPatchInput {
+    /// A unified diff, as printed by `git diff`, with paths relative to the repository root
+    patch: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct ApplyPatchOutput {
+    /// Files left with conflict markers, to be resolved by editing them
+    conflicts: Vec<String>,
+    /// Error message if the patch could not be applied at all
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct ReadFileOutput {
+    /// The file contents, possibly truncated
+    content: Option<String>,
+    /// Error message if the file could not be read
+    error: Option<String>,
+}
+
+impl ReadFileOutput {
+    fn content(content: String) -> Self {
+        Self {
+            content: Some(content),
+            error: None,
+        }
+    }
+
+    fn error(error: String) -> Self {
+        Self {
+            content: None,
+            error: Some(error),
+        }
+    }
+}
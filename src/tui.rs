@@ -12,7 +12,7 @@ use crossterm::style::{Attribute, Color, ResetColor, SetAttribute, SetForeground
 use crossterm::terminal::{Clear, ClearType};
 use sacp::schema::{ContentBlock, SessionNotification, SessionUpdate};
 
-use crate::execute::{CommitStatus, ExecuteHooks};
+use crate::execute::{CommitStatus, ExecuteHooks, ExtractionPlan};
 
 // =============================================================================
 // Shared State
@@ -80,15 +80,18 @@ impl StatusState {
                 format!("[{}/{}] STUCK: {}", idx + 1, total, entry.message),
                 (Color::Red, true),
             )
-        } else if self
-            .plan
-            .iter()
-            .all(|e| e.status == CommitStatus::Completed)
-        {
-            (
-                format!("[{}/{}] All commits complete", total, total),
-                (Color::Green, false),
-            )
+        } else if self.plan.iter().all(|e| e.status.is_finished()) {
+            let review = self
+                .plan
+                .iter()
+                .filter(|e| e.status == CommitStatus::NeedsReview)
+                .count();
+            let label = if review > 0 {
+                format!("[{total}/{total}] All commits complete ({review} need review)")
+            } else {
+                format!("[{total}/{total}] All commits complete")
+            };
+            (label, (Color::Green, false))
         } else {
             // Find first pending
             let idx = self
@@ -168,10 +171,26 @@ impl StatusState {
 /// Observer that prints agent activity to stdout with ANSI colors.
 pub struct TerminalObserver {
     state: Arc<Mutex<StatusState>>,
+    /// Drop the agent's own output, keeping only permission requests.
+    quiet: bool,
+}
+
+impl TerminalObserver {
+    /// Stop printing prompts, agent text, and tool calls.
+    ///
+    /// Retcon's own progress still comes through [`TerminalHooks`], and
+    /// permission requests are still shown since they may need attention.
+    pub fn quiet(mut self) -> Self {
+        self.quiet = true;
+        self
+    }
 }
 
 impl determinishtic::ThinkObserver for TerminalObserver {
     fn on_prompt(&self, prompt: &str) {
+        if self.quiet {
+            return;
+        }
         let mut state = self.state.lock().unwrap();
         state.println_styled("--- Prompt ---", Color::DarkGrey, false);
         for line in prompt.lines() {
@@ -181,6 +200,9 @@ impl determinishtic::ThinkObserver for TerminalObserver {
     }
 
     fn on_notification(&self, notification: &SessionNotification) {
+        if self.quiet {
+            return;
+        }
         let mut state = self.state.lock().unwrap();
         match &notification.update {
             SessionUpdate::AgentMessageChunk(chunk) => {
@@ -212,6 +234,9 @@ impl determinishtic::ThinkObserver for TerminalObserver {
     }
 
     fn on_stop(&self, reason: &sacp::schema::StopReason) {
+        if self.quiet {
+            return;
+        }
         let mut state = self.state.lock().unwrap();
         state.println_styled(&format!("Session stopped: {reason:?}"), Color::Green, false);
     }
@@ -253,6 +278,23 @@ impl ExecuteHooks for TerminalHooks {
         state.draw_status_line();
     }
 
+    fn approve_finalize(&self, _plan: &ExtractionPlan) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.clear_status_line();
+        drop(state);
+
+        // The plan itself has already been reported
+        println!();
+        print!("Create fixup commits following this plan? [y/N] ");
+        let _ = io::stdout().flush();
+
+        let mut input = String::new();
+        match io::stdin().read_line(&mut input) {
+            Ok(0) | Err(_) => false,
+            Ok(_) => matches!(input.trim(), "y" | "Y" | "yes"),
+        }
+    }
+
     fn on_stuck(&self, reason: &str) -> Option<String> {
         let mut state = self.state.lock().unwrap();
         state.clear_status_line();
@@ -291,6 +333,7 @@ pub fn new() -> (TerminalObserver, TerminalHooks) {
     let state = Arc::new(Mutex::new(StatusState::new()));
     let observer = TerminalObserver {
         state: state.clone(),
+        quiet: false,
     };
     let hooks = TerminalHooks { state };
     (observer, hooks)
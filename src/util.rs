@@ -0,0 +1,43 @@
+//! Small filesystem helpers shared across modules.
+
+use std::path::{Path, PathBuf};
+
+/// Replace the file at `path` with `content` in one step.
+///
+/// The content goes to a temporary file next to it, which is then renamed
+/// over the original, so an interrupted write leaves either the old file or
+/// the new one, never a truncated mix.
+pub(crate) fn write_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    // Write through a symlink rather than replacing it
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let tmp = sibling_temp_path(&path, "tmp");
+
+    let result = (|| {
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        if let Ok(meta) = std::fs::metadata(&path) {
+            std::fs::set_permissions(&tmp, meta.permissions())?;
+        }
+        std::fs::rename(&tmp, &path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// A hidden scratch file in the same directory as `path`, ending in `.{suffix}`.
+pub(crate) fn sibling_temp_path(path: &Path, suffix: &str) -> PathBuf {
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    dir.join(format!(".{name}.{}.{suffix}", std::process::id()))
+}
@@ -0,0 +1,778 @@
+//! End-to-end runs of the reconstruction loop against a fixture repository.
+//!
+//! The agent's side of each run is replayed from a recording written by the
+//! test, so these exercise everything retcon does around the agent (branch
+//! setup, committing, history bookkeeping, the final tree check) without an
+//! LLM. The agent the runs connect to only answers `initialize`, since a
+//! replayed run never sends it anything else.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use determinishtic::Determinishtic;
+use retcon::{
+    Approval, Base, Direction, ExecuteConfig, ExecuteError, ExecuteHooks, Git, HistoryEntry,
+    HistorySpec, MANIFEST_FILE, Manifest, NoOpHooks, Recording, StuckKind, StuckReason,
+    cleaned_history, execute_with_connection, remaining_diff, remaining_is_empty,
+};
+use sacp::schema::{AgentCapabilities, InitializeRequest, InitializeResponse};
+use sacp::{Agent, Client, ConnectionTo, Dispatch};
+use serde_json::json;
+
+/// A scratch directory holding a repository with a messy `feature` branch.
+///
+/// `main` has a single commit. `feature` adds `a.txt` and `b.txt` across
+/// two commits that don't line up with the clean history the specs ask for.
+struct Fixture {
+    dir: PathBuf,
+    repo: PathBuf,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("retcon-test-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = dir.join("repo");
+        std::fs::create_dir_all(&repo).unwrap();
+        let fixture = Self { dir, repo };
+
+        fixture.git(&["init", "-q", "-b", "main"]);
+        fixture.git(&["config", "user.name", "Test"]);
+        fixture.git(&["config", "user.email", "test@example.com"]);
+        fixture.commit_file("README", "fixture\n", "initial");
+        fixture.git(&["checkout", "-q", "-b", "feature"]);
+        fixture.write("a.txt", "a\n");
+        fixture.commit_file("b.txt", "b, first try\n", "wip");
+        fixture.commit_file("b.txt", "b\n", "fix b");
+        fixture.git(&["checkout", "-q", "main"]);
+        fixture
+    }
+
+    fn write(&self, path: &str, content: &str) {
+        std::fs::write(self.repo.join(path), content).unwrap();
+    }
+
+    fn commit_file(&self, path: &str, content: &str, message: &str) {
+        self.write(path, content);
+        self.git(&["add", "-A"]);
+        self.git(&["commit", "-q", "-m", message]);
+    }
+
+    fn git(&self, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(&self.repo)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "git {args:?} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /// The changes `feature` makes to `paths`, as the agent would have made them.
+    fn feature_patch(&self, paths: &[&str]) -> String {
+        let mut args = vec!["diff", "--binary", "--no-renames", "main", "feature", "--"];
+        args.extend(paths);
+        self.git(&args)
+    }
+
+    /// Write a recording in which the agent answers each extraction in turn.
+    fn record_extractions(&self, extractions: &[(bool, String)]) -> Recording {
+        let entries = extractions
+            .iter()
+            .map(|(applied, patch)| extraction(*applied, patch))
+            .collect::<Vec<_>>();
+        self.record(&entries)
+    }
+
+    /// Write a recording of the given entries, as built by [`extraction`].
+    fn record(&self, entries: &[serde_json::Value]) -> Recording {
+        let path = self.dir.join("recording.jsonl");
+        let lines: String = entries.iter().map(|entry| format!("{entry}\n")).collect();
+        std::fs::write(&path, lines).unwrap();
+        Recording::replay(&path).unwrap()
+    }
+
+    fn head(&self, rev: &str) -> String {
+        self.git(&["rev-parse", rev]).trim().to_string()
+    }
+
+    fn spec(&self, messages: &[&str]) -> HistorySpec {
+        let commits: String = messages
+            .iter()
+            .map(|m| format!("\n[[commit]]\nmessage = \"{m}\"\n"))
+            .collect();
+        HistorySpec::from_toml(&format!(
+            "source = \"feature\"\nremote = \"main\"\ncleaned = \"feature-clean\"\n{commits}"
+        ))
+        .unwrap()
+    }
+
+    fn tree(&self, rev: &str) -> String {
+        self.git(&["rev-parse", &format!("{rev}^{{tree}}")])
+    }
+}
+
+/// A recorded agent answer of type `kind`, which changed the tree by `patch`.
+fn recorded(kind: &str, result: serde_json::Value, patch: &str) -> serde_json::Value {
+    json!({ "kind": kind, "result": result, "patch": patch })
+}
+
+/// A recorded extraction that changed the tree by `patch`.
+fn extraction(applied: bool, patch: &str) -> serde_json::Value {
+    recorded(
+        "ExtractResult",
+        json!({ "applied_changes": applied, "hints_applied": [], "hints_ignored": [] }),
+        patch,
+    )
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Connect to an agent that answers `initialize` and rejects everything else.
+async fn replay_only_agent() -> Determinishtic {
+    let agent = Agent
+        .builder()
+        .name("replay-only")
+        .on_receive_request(
+            async move |initialize: InitializeRequest, responder, _cx| {
+                responder.respond(
+                    InitializeResponse::new(initialize.protocol_version)
+                        .agent_capabilities(AgentCapabilities::new()),
+                )
+            },
+            sacp::on_receive_request!(),
+        )
+        .on_receive_dispatch(
+            async move |message: Dispatch, cx: ConnectionTo<Client>| {
+                message.respond_with_error(sacp::util::internal_error("not in the recording"), cx)
+            },
+            sacp::on_receive_dispatch!(),
+        );
+    Determinishtic::new(agent).await.unwrap()
+}
+
+async fn run(
+    fixture: &Fixture,
+    spec: HistorySpec,
+    recording: Recording,
+    hooks: &impl ExecuteHooks,
+) -> HistorySpec {
+    let config = ExecuteConfig {
+        recording,
+        ..Default::default()
+    };
+    run_with(fixture, spec, &config, hooks).await
+}
+
+async fn run_with(
+    fixture: &Fixture,
+    spec: HistorySpec,
+    config: &ExecuteConfig,
+    hooks: &impl ExecuteHooks,
+) -> HistorySpec {
+    try_run_with(fixture, spec, config, hooks)
+        .await
+        .map_err(|(_, e)| e)
+        .unwrap()
+}
+
+async fn try_run_with(
+    fixture: &Fixture,
+    spec: HistorySpec,
+    config: &ExecuteConfig,
+    hooks: &impl ExecuteHooks,
+) -> Result<HistorySpec, (HistorySpec, ExecuteError)> {
+    let d = replay_only_agent().await;
+    let git = Git::at(&fixture.repo);
+    execute_with_connection(&d, spec, &git, config, hooks).await
+}
+
+fn assert_committed(history: &[HistoryEntry]) {
+    assert!(
+        matches!(
+            history,
+            [
+                HistoryEntry::Started,
+                HistoryEntry::CommitCreated(_),
+                HistoryEntry::Complete
+            ]
+        ),
+        "unexpected history: {history:?}"
+    );
+}
+
+#[tokio::test]
+async fn reconstructs_branch_matching_source() {
+    let fixture = Fixture::new("reconstruct");
+    let spec = fixture.spec(&["Add a", "Add b"]);
+    let recording = fixture.record_extractions(&[
+        (true, fixture.feature_patch(&["a.txt"])),
+        (true, fixture.feature_patch(&["b.txt"])),
+    ]);
+
+    let spec = run(&fixture, spec, recording, &NoOpHooks).await;
+
+    assert_eq!(fixture.tree("feature-clean"), fixture.tree("feature"));
+    for commit in &spec.commits {
+        assert_committed(&commit.history);
+    }
+
+    let git = Git::at(&fixture.repo);
+    let subjects: Vec<String> = cleaned_history(&git, &spec)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.subject)
+        .collect();
+    assert_eq!(subjects, ["Add a", "Add b"]);
+    assert!(remaining_is_empty(&git, &spec).unwrap());
+    assert_eq!(remaining_diff(&git, &spec).unwrap(), "");
+
+    // Each history entry names the commit that was actually made
+    for (commit, rev) in spec.commits.iter().zip(["feature-clean^", "feature-clean"]) {
+        let created = commit.last_commit().unwrap();
+        assert!(fixture.git(&["rev-parse", rev]).starts_with(created));
+    }
+}
+
+#[tokio::test]
+async fn failed_extraction_marks_commit_stuck() {
+    let fixture = Fixture::new("stuck");
+    let spec = fixture.spec(&["Add a", "Add b"]);
+    let recording = fixture.record_extractions(&[(false, String::new())]);
+
+    let spec = run(&fixture, spec, recording, &NoOpHooks).await;
+
+    let history = &spec.commits[0].history;
+    assert!(
+        matches!(
+            history.as_slice(),
+            [HistoryEntry::Started, HistoryEntry::Stuck(reason)]
+                if reason.kind == Some(StuckKind::MissingContext)
+        ),
+        "unexpected history: {history:?}"
+    );
+    assert!(spec.commits[1].history.is_empty());
+    assert_eq!(fixture.tree("feature-clean"), fixture.tree("main"));
+
+    let git = Git::at(&fixture.repo);
+    assert!(!remaining_is_empty(&git, &spec).unwrap());
+    let remaining = remaining_diff(&git, &spec).unwrap();
+    assert!(remaining.contains("+++ b/a.txt") && remaining.contains("+++ b/b.txt"));
+}
+
+/// Defers the first commit and approves the rest.
+struct DeferFirst;
+
+impl ExecuteHooks for DeferFirst {
+    fn approve_commit(&self, commit_idx: usize, _staged_diff: &str) -> Approval {
+        if commit_idx == 0 {
+            Approval::Defer
+        } else {
+            Approval::Approve
+        }
+    }
+}
+
+#[tokio::test]
+async fn deferred_commit_is_recorded_as_skipped() {
+    let fixture = Fixture::new("defer");
+    let spec = fixture.spec(&["Add a", "Add a and b"]);
+    let recording = fixture.record_extractions(&[
+        (true, fixture.feature_patch(&["a.txt"])),
+        (true, fixture.feature_patch(&[])),
+    ]);
+
+    let spec = run(&fixture, spec, recording, &DeferFirst).await;
+
+    let history = &spec.commits[0].history;
+    assert!(
+        matches!(
+            history.as_slice(),
+            [
+                HistoryEntry::Started,
+                HistoryEntry::Skipped(_),
+                HistoryEntry::Complete
+            ]
+        ),
+        "unexpected history: {history:?}"
+    );
+    assert_committed(&spec.commits[1].history);
+    assert_eq!(fixture.tree("feature-clean"), fixture.tree("feature"));
+    assert_eq!(fixture.tree("feature-clean^"), fixture.tree("main"));
+}
+
+#[tokio::test]
+async fn retry_clean_undoes_stuck_attempt() {
+    let fixture = Fixture::new("retry-clean");
+    let main = fixture.head("main");
+
+    // A first attempt that committed `a.txt` and then got stuck in its fix loop
+    fixture.git(&["checkout", "-q", "-b", "feature-clean"]);
+    fixture.commit_file("a.txt", "a\n", "Add a");
+    let attempt = fixture.head("HEAD");
+    let mut spec = fixture.spec(&["Add a", "Add b"]);
+    spec.commits[0].history = vec![
+        HistoryEntry::Started,
+        HistoryEntry::CommitCreated(attempt),
+        HistoryEntry::Stuck(StuckReason::new(StuckKind::BuildUnfixable, "build fails")),
+        HistoryEntry::Resolved("the build needs nothing else".to_string()),
+    ];
+
+    // The retry finds `a.txt` still in the working tree and commits it as is
+    let recording = fixture.record_extractions(&[
+        (true, String::new()),
+        (true, fixture.feature_patch(&["b.txt"])),
+    ]);
+    let config = ExecuteConfig {
+        recording,
+        retry_clean: true,
+        ..Default::default()
+    };
+    let spec = run_with(&fixture, spec, &config, &NoOpHooks).await;
+
+    let history = &spec.commits[0].history;
+    assert!(
+        matches!(
+            &history[4..],
+            [HistoryEntry::RolledBack(to), HistoryEntry::Started, HistoryEntry::CommitCreated(_), HistoryEntry::Complete]
+                if *to == main
+        ),
+        "unexpected history: {history:?}"
+    );
+    assert_eq!(fixture.head("feature-clean~2"), main);
+    assert_eq!(fixture.tree("feature-clean"), fixture.tree("feature"));
+}
+
+#[tokio::test]
+async fn resumes_after_interruption_past_the_commit() {
+    let fixture = Fixture::new("resume");
+
+    // The run was interrupted after committing `Add a`, before marking it complete
+    fixture.git(&["checkout", "-q", "-b", "feature-clean"]);
+    fixture.commit_file("a.txt", "a\n", "Add a");
+    let created = fixture.head("HEAD");
+    let mut spec = fixture.spec(&["Add a", "Add b"]);
+    spec.commits[0].history = vec![
+        HistoryEntry::Started,
+        HistoryEntry::CommitCreated(created.clone()),
+    ];
+    assert!(spec.commits[0].is_started());
+
+    // Only the second commit asks the agent anything
+    let recording = fixture.record_extractions(&[(true, fixture.feature_patch(&["b.txt"]))]);
+    let spec = run(&fixture, spec, recording, &NoOpHooks).await;
+
+    let history = &spec.commits[0].history;
+    assert!(
+        matches!(
+            history.as_slice(),
+            [HistoryEntry::Started, HistoryEntry::CommitCreated(h), HistoryEntry::Complete]
+                if *h == created
+        ),
+        "unexpected history: {history:?}"
+    );
+    assert_committed(&spec.commits[1].history);
+    assert_eq!(fixture.head("feature-clean^"), created);
+    assert_eq!(fixture.tree("feature-clean"), fixture.tree("feature"));
+}
+
+#[tokio::test]
+async fn error_after_commit_keeps_its_history() {
+    let fixture = Fixture::new("error");
+    let spec = fixture.spec(&["Add a", "Add b"]);
+
+    // The build fails, and the recording ends before the agent's fix
+    let recording = fixture.record_extractions(&[(true, fixture.feature_patch(&["a.txt"]))]);
+    let config = ExecuteConfig {
+        recording,
+        build_command: Some("false".to_string()),
+        ..Default::default()
+    };
+    let (spec, _) = try_run_with(&fixture, spec, &config, &NoOpHooks)
+        .await
+        .unwrap_err();
+
+    let history = &spec.commits[0].history;
+    assert!(
+        matches!(
+            history.as_slice(),
+            [HistoryEntry::Started, HistoryEntry::CommitCreated(_), HistoryEntry::Stuck(reason)]
+                if reason.kind == Some(StuckKind::Interrupted)
+        ),
+        "unexpected history: {history:?}"
+    );
+}
+
+#[tokio::test]
+async fn continue_on_stuck_undoes_the_stuck_commit() {
+    let fixture = Fixture::new("continue");
+    let main = fixture.head("main");
+    let spec = fixture.spec(&["Add a", "Add a and b"]);
+
+    // The first commit is made, then its build fails and leaves an artifact
+    // behind; the second has everything it needs and builds
+    let build = fixture.dir.join("build.sh");
+    std::fs::write(&build, "test -f b.txt || { touch artifact; false; }\n").unwrap();
+    let recording = fixture.record_extractions(&[
+        (true, fixture.feature_patch(&["a.txt"])),
+        (true, fixture.feature_patch(&[])),
+    ]);
+    let config = ExecuteConfig {
+        recording,
+        build_command: Some(format!("sh {}", build.display())),
+        strict_no_wip: true,
+        continue_on_stuck: true,
+        ..Default::default()
+    };
+    let spec = run_with(&fixture, spec, &config, &NoOpHooks).await;
+
+    let history = &spec.commits[0].history;
+    assert!(
+        matches!(
+            history.as_slice(),
+            [
+                HistoryEntry::Started,
+                HistoryEntry::CommitCreated(_),
+                HistoryEntry::RolledBack(to),
+                HistoryEntry::Stuck(_)
+            ] if *to == main
+        ),
+        "unexpected history: {history:?}"
+    );
+    assert!(spec.commits[0].is_stuck());
+    assert_committed(&spec.commits[1].history);
+
+    // The second commit is built on the base, without the artifact
+    assert_eq!(fixture.head("feature-clean^"), main);
+    assert_eq!(fixture.tree("feature-clean"), fixture.tree("feature"));
+}
+
+#[tokio::test]
+async fn finalize_replays_the_fixup_commits() {
+    let fixture = Fixture::new("finalize");
+    let spec = fixture.spec(&["Add a", "Add b"]);
+
+    // The second extraction only gets as far as `feature`'s first try at
+    // `b.txt`, and the catchall commits the rest as a fixup
+    let first_try = fixture.git(&["diff", "--binary", "main", "feature^", "--", "b.txt"]);
+    let rest = fixture.git(&["diff", "--binary", "feature^", "feature"]);
+    let mut catchall = recorded("CatchallResult", json!({ "commits_created": 1 }), &rest);
+    catchall["commits"] = json!([{ "message": "fixup! Add b", "patch": rest }]);
+    let recording = fixture.record(&[
+        extraction(true, &fixture.feature_patch(&["a.txt"])),
+        extraction(true, &first_try),
+        catchall,
+    ]);
+    let config = ExecuteConfig {
+        recording,
+        autosquash: true,
+        ..Default::default()
+    };
+    let spec = run_with(&fixture, spec, &config, &NoOpHooks).await;
+
+    assert_eq!(fixture.tree("feature-clean"), fixture.tree("feature"));
+    let git = Git::at(&fixture.repo);
+    let subjects: Vec<String> = cleaned_history(&git, &spec)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.subject)
+        .collect();
+    assert_eq!(subjects, ["Add a", "Add b"]);
+    assert_eq!(fixture.git(&["status", "--porcelain"]), "");
+}
+
+#[tokio::test]
+async fn autosquash_renames_the_rewritten_commits() {
+    let fixture = Fixture::new("autosquash");
+    fixture.git(&["checkout", "-q", "feature"]);
+    fixture.commit_file("c.txt", "c\n", "add c");
+    fixture.commit_file("d.txt", "d\n", "add d");
+    fixture.git(&["checkout", "-q", "main"]);
+    let spec = fixture.spec(&["Add a", "Add b"]);
+
+    // The catchall folds `c.txt` into the first commit, which rewrites the
+    // second, and leaves a `squash!` for the user
+    let c = fixture.feature_patch(&["c.txt"]);
+    let d = fixture.feature_patch(&["d.txt"]);
+    let both = fixture.feature_patch(&["c.txt", "d.txt"]);
+    let mut catchall = recorded("CatchallResult", json!({ "commits_created": 2 }), &both);
+    catchall["commits"] = json!([
+        { "message": "fixup! Add a", "patch": c },
+        { "message": "squash! Add b", "patch": d },
+    ]);
+    let recording = fixture.record(&[
+        extraction(true, &fixture.feature_patch(&["a.txt"])),
+        extraction(true, &fixture.feature_patch(&["b.txt"])),
+        catchall,
+    ]);
+    let config = ExecuteConfig {
+        recording,
+        autosquash: true,
+        ..Default::default()
+    };
+    let spec = run_with(&fixture, spec, &config, &NoOpHooks).await;
+
+    assert_eq!(fixture.tree("feature-clean"), fixture.tree("feature"));
+    let git = Git::at(&fixture.repo);
+    let log = cleaned_history(&git, &spec).unwrap();
+    let subjects: Vec<&str> = log.iter().map(|entry| entry.subject.as_str()).collect();
+    assert_eq!(subjects, ["Add a", "Add b", "squash! Add b"]);
+    assert_eq!(
+        fixture.git(&["show", "--format=", "--name-only", "feature-clean~2"]),
+        "a.txt\nc.txt\n"
+    );
+
+    // Every commit the history names is one on the rewritten branch
+    let on_branch: Vec<&str> = log.iter().map(|entry| entry.hash.as_str()).collect();
+    for commit in &spec.commits {
+        for entry in &commit.history {
+            if let HistoryEntry::CommitCreated(hash) = entry {
+                assert!(on_branch.contains(&hash.as_str()), "{hash} is gone");
+            }
+        }
+    }
+    assert_eq!(spec.commits[0].first_commit(), Some(on_branch[0]));
+    assert_eq!(spec.commits[1].first_commit(), Some(on_branch[1]));
+}
+
+#[tokio::test]
+async fn remainder_commit_takes_the_rest_but_not_untracked_files() {
+    let fixture = Fixture::new("remainder");
+    let spec = fixture.spec(&["Add a"]);
+    let recording = fixture.record_extractions(&[(true, fixture.feature_patch(&["a.txt"]))]);
+
+    // The build leaves a log behind, which isn't part of the rest
+    let build = fixture.dir.join("build.sh");
+    std::fs::write(&build, "echo built > build.log\n").unwrap();
+    let config = ExecuteConfig {
+        recording,
+        build_command: Some(format!("sh {}", build.display())),
+        remainder_commit: Some("Add the rest".to_string()),
+        ..Default::default()
+    };
+    let spec = run_with(&fixture, spec, &config, &NoOpHooks).await;
+
+    assert_eq!(fixture.tree("feature-clean"), fixture.tree("feature"));
+    let git = Git::at(&fixture.repo);
+    let subjects: Vec<String> = cleaned_history(&git, &spec)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.subject)
+        .collect();
+    assert_eq!(subjects, ["Add a", "Add the rest"]);
+    assert_eq!(fixture.git(&["status", "--porcelain"]), "?? build.log\n");
+}
+
+#[tokio::test]
+async fn swapped_source_and_remote_are_refused() {
+    let fixture = Fixture::new("swapped");
+    let mut spec = fixture.spec(&["Add a"]);
+    std::mem::swap(&mut spec.source, &mut spec.remote);
+    let recording = fixture.record_extractions(&[]);
+    let config = ExecuteConfig {
+        recording,
+        ..Default::default()
+    };
+    let (_, e) = try_run_with(&fixture, spec, &config, &NoOpHooks)
+        .await
+        .unwrap_err();
+    assert!(matches!(e, ExecuteError::SourceInRemote { .. }), "{e}");
+    assert!(
+        !fixture
+            .git(&["branch", "--list", "feature-clean"])
+            .contains("feature-clean")
+    );
+}
+
+#[tokio::test]
+async fn remote_base_stays_put_when_the_remote_moves() {
+    let fixture = Fixture::new("remote-moves");
+    let main = fixture.head("main");
+    let mut spec = fixture.spec(&["Add a", "Add b"]);
+    spec.base = Base::Remote;
+
+    // The first run stops after `Add a`
+    spec.commits[1].history = vec![HistoryEntry::Stuck(StuckReason::new(
+        StuckKind::Other,
+        "not yet",
+    ))];
+    let recording = fixture.record_extractions(&[(true, fixture.feature_patch(&["a.txt"]))]);
+    let mut spec = run(&fixture, spec, recording, &NoOpHooks).await;
+    assert_eq!(spec.base_resolved.as_deref(), Some(main.as_str()));
+
+    // `main` moves on before the second run
+    fixture.git(&["checkout", "-q", "main"]);
+    fixture.commit_file("c.txt", "c\n", "Add c upstream");
+    spec.commits[1]
+        .history
+        .push(HistoryEntry::Resolved("go ahead".to_string()));
+    let recording = fixture.record_extractions(&[(true, fixture.feature_patch(&["b.txt"]))]);
+    let spec = run(&fixture, spec, recording, &NoOpHooks).await;
+
+    assert_eq!(spec.base_resolved.as_deref(), Some(main.as_str()));
+    assert_committed(&spec.commits[0].history);
+    assert_eq!(fixture.head("feature-clean~2"), main);
+    assert_eq!(fixture.tree("feature-clean"), fixture.tree("feature"));
+}
+
+/// A recorded peel that changed the tree by `patch`, stuck with `stuck_reason` if given.
+fn peel(stuck_reason: Option<&str>, patch: &str) -> serde_json::Value {
+    recorded(
+        "PeelResult",
+        json!({ "stuck_reason": stuck_reason, "stuck_kind": null }),
+        patch,
+    )
+}
+
+#[tokio::test]
+async fn reverse_resumes_after_the_peeled_commits() {
+    let fixture = Fixture::new("reverse");
+    fixture.git(&["checkout", "-q", "feature"]);
+    fixture.commit_file("c.txt", "c\n", "add c");
+    fixture.git(&["checkout", "-q", "main"]);
+    let spec = fixture.spec(&["Add a", "Add b", "Add c"]);
+    let remove = |path: &str| fixture.git(&["diff", "--binary", "feature", "main", "--", path]);
+
+    // `Add c` peels off; `Add b` gets stuck, leaving a stray file behind
+    let stray = "diff --git a/junk b/junk\nnew file mode 100644\n--- /dev/null\n+++ b/junk\n@@ -0,0 +1 @@\n+junk\n";
+    let recording = fixture.record(&[
+        peel(None, &remove("c.txt")),
+        peel(Some("b and a are tangled"), stray),
+    ]);
+    let config = ExecuteConfig {
+        recording,
+        direction: Direction::Reverse,
+        ..Default::default()
+    };
+    let mut spec = run_with(&fixture, spec, &config, &NoOpHooks).await;
+
+    assert!(spec.commits[1].is_stuck());
+    assert!(spec.commits[2].peeled_snapshot().is_some());
+    assert_eq!(
+        fixture.git(&["symbolic-ref", "--short", "HEAD"]),
+        "feature-clean\n"
+    );
+    assert_eq!(fixture.git(&["status", "--porcelain"]), "");
+
+    // Only `Add b` is peeled again
+    spec.commits[1]
+        .history
+        .push(HistoryEntry::Resolved("a.txt is separate".to_string()));
+    let recording = fixture.record(&[peel(None, &remove("b.txt"))]);
+    let config = ExecuteConfig {
+        recording,
+        direction: Direction::Reverse,
+        ..Default::default()
+    };
+    let spec = run_with(&fixture, spec, &config, &NoOpHooks).await;
+
+    assert!(spec.commits.iter().all(|c| c.is_complete()));
+    assert_eq!(fixture.tree("feature-clean"), fixture.tree("feature"));
+    assert_eq!(fixture.head("feature-clean~3"), fixture.head("main"));
+    let first = fixture.git(&["ls-tree", "-r", "--name-only", "feature-clean~2"]);
+    assert_eq!(first, "README\na.txt\n");
+}
+
+#[tokio::test]
+async fn reverse_error_restores_the_branch() {
+    let fixture = Fixture::new("reverse-error");
+    let spec = fixture.spec(&["Add a", "Add b", "Nothing"]);
+
+    // The first peel edits the tree, and the recording ends before the second
+    let recording = fixture.record(&[peel(
+        None,
+        &fixture.git(&["diff", "--binary", "feature", "main", "--", "b.txt"]),
+    )]);
+    let config = ExecuteConfig {
+        recording,
+        direction: Direction::Reverse,
+        ..Default::default()
+    };
+    try_run_with(&fixture, spec, &config, &NoOpHooks)
+        .await
+        .unwrap_err();
+
+    assert_eq!(
+        fixture.git(&["symbolic-ref", "--short", "HEAD"]),
+        "feature-clean\n"
+    );
+    assert_eq!(fixture.git(&["status", "--porcelain"]), "");
+}
+
+#[tokio::test]
+async fn manifest_replays_the_branch() {
+    let fixture = Fixture::new("manifest");
+    let spec = fixture.spec(&["Add a", "Add b"]);
+
+    // As in `finalize_replays_the_fixup_commits`, leaving a fixup for the remaining patch
+    let first_try = fixture.git(&["diff", "--binary", "main", "feature^", "--", "b.txt"]);
+    let rest = fixture.git(&["diff", "--binary", "feature^", "feature"]);
+    let mut catchall = recorded("CatchallResult", json!({ "commits_created": 1 }), &rest);
+    catchall["commits"] = json!([{ "message": "fixup! Add b", "patch": rest }]);
+    let recording = fixture.record(&[
+        extraction(true, &fixture.feature_patch(&["a.txt"])),
+        extraction(true, &first_try),
+        catchall,
+    ]);
+    let patch_dir = fixture.dir.join("patches");
+    let config = ExecuteConfig {
+        recording,
+        patch_dir: Some(patch_dir.clone()),
+        ..Default::default()
+    };
+    run_with(&fixture, spec, &config, &NoOpHooks).await;
+
+    let manifest: Manifest =
+        serde_json::from_str(&std::fs::read_to_string(patch_dir.join(MANIFEST_FILE)).unwrap())
+            .unwrap();
+    assert!(manifest.commits.iter().all(|c| c.patch.is_some()));
+    assert!(manifest.remaining.is_some());
+
+    // Recreate the branch from the patches alone
+    fixture.git(&["checkout", "-q", "main"]);
+    fixture.git(&["branch", "-q", "-m", "feature-clean", "feature-clean-old"]);
+    manifest.apply(&Git::at(&fixture.repo), &patch_dir).unwrap();
+    assert_eq!(fixture.tree("feature-clean"), fixture.tree("feature"));
+    assert_eq!(
+        fixture.git(&["rev-list", "--count", "main..feature-clean"]),
+        "3\n"
+    );
+}
+
+#[tokio::test]
+async fn manifest_refuses_commits_no_patch_covers() {
+    let fixture = Fixture::new("manifest-uncovered");
+
+    // Someone committed on the cleaned branch by hand, before `Add a`
+    fixture.git(&["checkout", "-q", "-b", "feature-clean"]);
+    fixture.git(&["commit", "-q", "--allow-empty", "-m", "by hand"]);
+    fixture.commit_file("a.txt", "a\n", "Add a");
+    let mut spec = fixture.spec(&["Add a", "Add b"]);
+    spec.commits[0].history = vec![
+        HistoryEntry::Started,
+        HistoryEntry::CommitCreated(fixture.head("HEAD")[..8].to_string()),
+        HistoryEntry::Complete,
+    ];
+
+    let recording = fixture.record_extractions(&[(true, fixture.feature_patch(&["b.txt"]))]);
+    let config = ExecuteConfig {
+        recording,
+        patch_dir: Some(fixture.dir.join("patches")),
+        ..Default::default()
+    };
+    let (_, error) = try_run_with(&fixture, spec, &config, &NoOpHooks)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(error, ExecuteError::UncoveredCommits { .. }),
+        "unexpected error: {error}"
+    );
+}